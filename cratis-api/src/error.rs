@@ -0,0 +1,42 @@
+use std::io::ErrorKind;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use cratis_core::error::CratisError;
+use cratis_core::protocol::ErrorResponse;
+
+/// An error returned by a request handler, rendered as a JSON `ErrorResponse`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+}
+
+impl From<CratisError> for ApiError {
+    /// Maps a core error to the closest HTTP status.
+    ///
+    /// Missing objects become `404 Not Found`, malformed digests and paths `400 Bad Request` and
+    /// authentication problems `401 Unauthorized`. Everything else is a server-side failure.
+    fn from(error: CratisError) -> Self {
+        let status = match &error {
+            CratisError::InvalidInput(_) | CratisError::InvalidPath(_) => StatusCode::BAD_REQUEST,
+            CratisError::AuthFailure(_) => StatusCode::UNAUTHORIZED,
+            CratisError::IoError(e) if e.kind() == ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        Self::new(status, error.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(ErrorResponse { error: self.message })).into_response()
+    }
+}
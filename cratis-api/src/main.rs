@@ -1,3 +1,34 @@
-fn main() {
-    println!("Hello, world!");
+mod error;
+mod server;
+
+use std::env;
+use cratis_core::config::{config_flag, find_config, load_config, CratisConfig};
+use cratis_core::error::{display_error, CratisResult};
+use cratis_core::repository::Repository;
+use tokio::net::TcpListener;
+
+/// Entry point for the Cratis API server.
+///
+/// Loads the configuration (from `--config`, `CRATIS_CONFIG` or the standard locations, see
+/// `find_config`), opens the repository at `server.storage_path` and serves the API on
+/// `server.listen`, or the host and port of `server.address` if it is not set.
+#[tokio::main]
+async fn main() {
+    let config = match find_config(config_flag(env::args().skip(1)).as_deref()).and_then(load_config) {
+        Ok(config) => config,
+        Err(e) => display_error(&e, false),
+    };
+
+    if let Err(e) = run(&config).await {
+        display_error(&e, false);
+    }
+}
+
+async fn run(config: &CratisConfig) -> CratisResult<()> {
+    let repo = Repository::open(config.server.storage_path()?)?;
+    let listener = TcpListener::bind(config.server.listen_address()).await?;
+    println!("Serving {} on {}", repo.root().display(), listener.local_addr()?);
+
+    axum::serve(listener, server::router(repo, &config.server.auth_token)).await?;
+    Ok(())
 }
@@ -0,0 +1,702 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, FromRequestParts, Path as UrlPath, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use cratis_core::config::is_valid_name;
+use cratis_core::error::{CratisError, CratisResult};
+use cratis_core::index::SnapshotRecord;
+use cratis_core::protocol::{CommitRequest, CommitResponse, MissingRequest, MissingResponse, SnapshotsQuery, API_PREFIX, CLIENT_HEADER, ENCRYPTED_HEADER};
+use cratis_core::repository::Repository;
+use cratis_core::store::{validate_hash, ObjectStore};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::error::ApiError;
+
+/// Largest accepted request body. File recipes of very large files are the biggest uploads.
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Number of chunks buffered ahead of the client while streaming a file.
+const STREAM_BUFFER: usize = 4;
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Directory below the repository root holding the objects of encrypted clients, one
+/// subdirectory per client.
+const CLIENTS_DIR: &str = "clients";
+
+#[derive(Clone)]
+struct AppState {
+    repo: Arc<Repository>,
+    auth_token: Arc<str>,
+}
+
+/// Builds the router serving the API described in `cratis_core::protocol`.
+///
+/// Every endpoint requires `Authorization: Bearer <auth_token>`. Objects of unencrypted clients
+/// are checked against their digest on upload and shared between clients. Objects of encrypted
+/// clients cannot be checked, so every such client gets its own store below
+/// `<root>/clients/<client>` and never reads what another client uploaded. Commits and snapshot
+/// listings use the index namespace of the client named in the `CLIENT_HEADER` and the
+/// requested backup set. Filesystem and database work runs on the blocking thread pool, so
+/// slow disks do not stall other requests.
+///
+/// # Arguments
+/// * `repo` - The repository uploads are stored in and restores are served from
+/// * `auth_token` - The token clients have to present
+///
+/// # Examples
+/// ```ignore
+/// let listener = TcpListener::bind("127.0.0.1:8080").await?;
+/// axum::serve(listener, router(Repository::open("/var/lib/cratis")?, "secret")).await?;
+/// ```
+pub fn router(repo: Repository, auth_token: &str) -> Router {
+    let state = AppState { repo: Arc::new(repo), auth_token: Arc::from(auth_token) };
+
+    let api = Router::new()
+        .route("/objects/missing", post(missing_objects))
+        .route("/objects/{hash}", get(get_object).put(put_object))
+        .route("/files/missing", post(missing_files))
+        .route("/files/{hash}", get(get_file).put(put_file))
+        .route("/files/{hash}/content", get(file_content))
+        .route("/commit", post(commit))
+        .route("/snapshots", get(list_snapshots))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(state);
+
+    Router::new().nest(API_PREFIX, api)
+}
+
+/// Rejects requests that do not carry the configured bearer token.
+async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), state.auth_token.as_bytes()) => Ok(next.run(request).await),
+        _ => Err(CratisError::AuthFailure("Missing or invalid bearer token").into()),
+    }
+}
+
+/// The client sending a request, as named in the `CLIENT_HEADER` and `ENCRYPTED_HEADER`.
+struct Client {
+    id: String,
+    encrypted: bool,
+}
+
+impl Client {
+    /// Returns the store holding the objects of this client.
+    ///
+    /// Unencrypted clients share the repository's store, as every upload to it is verified.
+    /// Encrypted clients get a store of their own, so a client can neither read nor replace
+    /// objects another client uploaded under the same digest.
+    fn store(&self, repo: &Repository) -> CratisResult<ObjectStore> {
+        if self.encrypted {
+            ObjectStore::open(repo.root().join(CLIENTS_DIR).join(&self.id))
+        } else {
+            Ok(repo.store.clone())
+        }
+    }
+
+    /// Returns the index namespace holding the history of backup set `set` of this client.
+    ///
+    /// Every backup set of every client gets its own namespace, `<client>/<set>`, so two
+    /// machines or two sets backing up the same paths never overwrite each other's revisions.
+    fn namespace(&self, set: &str) -> Result<String, ApiError> {
+        if !is_valid_name(set) {
+            return Err(CratisError::InvalidInput("Invalid backup set name").into());
+        }
+
+        Ok(format!("{}/{set}", self.id))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name| parts.headers.get(name).and_then(|value| value.to_str().ok());
+
+        let id = header(CLIENT_HEADER)
+            .filter(|id| is_valid_name(id))
+            .ok_or(CratisError::InvalidInput("Missing or invalid client id"))?
+            .to_string();
+
+        let encrypted = match header(ENCRYPTED_HEADER) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(_) => return Err(CratisError::InvalidInput("Invalid encryption header").into()),
+        };
+
+        Ok(Self { id, encrypted })
+    }
+}
+
+async fn missing_objects(State(state): State<AppState>, client: Client, Json(request): Json<MissingRequest>) -> Result<Json<MissingResponse>, ApiError> {
+    let missing = blocking(&state, move |repo| {
+        let store = client.store(repo)?;
+        filter_missing(request.hashes, |hash| store.contains(hash))
+    })
+    .await?;
+    Ok(Json(MissingResponse { missing }))
+}
+
+async fn missing_files(State(state): State<AppState>, client: Client, Json(request): Json<MissingRequest>) -> Result<Json<MissingResponse>, ApiError> {
+    let missing = blocking(&state, move |repo| {
+        let store = client.store(repo)?;
+        filter_missing(request.hashes, |hash| store.contains_file(hash))
+    })
+    .await?;
+    Ok(Json(MissingResponse { missing }))
+}
+
+async fn get_object(State(state): State<AppState>, client: Client, UrlPath(hash): UrlPath<String>) -> Result<Response, ApiError> {
+    let data = blocking(&state, move |repo| client.store(repo)?.get_encoded(&hash)).await?;
+    Ok(([(CONTENT_TYPE, OCTET_STREAM)], data).into_response())
+}
+
+/// Stores an uploaded object. Objects of unencrypted clients that do not decode to data
+/// matching their digest are rejected with `400 Bad Request`.
+async fn put_object(State(state): State<AppState>, client: Client, UrlPath(hash): UrlPath<String>, body: Bytes) -> Result<StatusCode, ApiError> {
+    let created = blocking(&state, move |repo| {
+        if client.encrypted { client.store(repo)?.put_encoded(&hash, &body) } else { repo.store.put_verified(&hash, &body) }
+    })
+    .await?;
+    Ok(if created { StatusCode::CREATED } else { StatusCode::OK })
+}
+
+async fn get_file(State(state): State<AppState>, client: Client, UrlPath(hash): UrlPath<String>) -> Result<Response, ApiError> {
+    let data = blocking(&state, move |repo| client.store(repo)?.get_encoded_file(&hash)).await?;
+    Ok(([(CONTENT_TYPE, OCTET_STREAM)], data).into_response())
+}
+
+/// Stores an uploaded file recipe. Recipes of unencrypted clients have to list chunks already
+/// uploaded that together match the recipe's digest, otherwise they are rejected with
+/// `400 Bad Request`.
+async fn put_file(State(state): State<AppState>, client: Client, UrlPath(hash): UrlPath<String>, body: Bytes) -> Result<StatusCode, ApiError> {
+    let created = blocking(&state, move |repo| {
+        if client.encrypted { client.store(repo)?.put_encoded_file(&hash, &body) } else { repo.store.put_verified_file(&hash, &body) }
+    })
+    .await?;
+    Ok(if created { StatusCode::CREATED } else { StatusCode::OK })
+}
+
+/// Streams the reassembled contents of a stored file, verifying every chunk on the way.
+///
+/// The server holds no repository keys, so this only works for files uploaded from an
+/// unencrypted repository. Clients of encrypted repositories fetch the encoded recipe and
+/// chunks instead and decode them locally.
+///
+/// A chunk that is missing or fails verification aborts the response body mid-stream, so the
+/// client sees a truncated transfer rather than corrupt data.
+async fn file_content(State(state): State<AppState>, UrlPath(hash): UrlPath<String>) -> Result<Response, ApiError> {
+    let recipe = blocking(&state, move |repo| repo.store.file_recipe(&hash)).await?;
+    let size = recipe.size;
+
+    let (tx, rx) = mpsc::channel::<Result<Bytes, io::Error>>(STREAM_BUFFER);
+    let repo = state.repo.clone();
+    tokio::task::spawn_blocking(move || {
+        for chunk in recipe.chunks {
+            let data = repo.store.get(&chunk.hash).and_then(|data| {
+                if repo.store.hash_bytes(&data) != chunk.hash {
+                    return Err(CratisError::BackupFailure("Stored chunk does not match its hash"));
+                }
+                Ok(data)
+            });
+
+            let failed = data.is_err();
+            let item = data.map(Bytes::from).map_err(|e| io::Error::other(e.to_string()));
+            if tx.blocking_send(item).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Ok((
+        [(CONTENT_TYPE, OCTET_STREAM.to_string()), (CONTENT_LENGTH, size.to_string())],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+/// Records uploaded file revisions and snapshots in the version index of the sending client's
+/// backup set.
+///
+/// All referenced objects have to be uploaded before the commit, otherwise it is rejected with
+/// `409 Conflict` and nothing is recorded. Revisions and snapshots that are already recorded
+/// are skipped, so a client can safely retry a commit.
+async fn commit(State(state): State<AppState>, client: Client, Json(request): Json<CommitRequest>) -> Result<Json<CommitResponse>, ApiError> {
+    let namespace = client.namespace(&request.set)?;
+    let missing = {
+        let request = request.clone();
+        blocking(&state, move |repo| {
+            let store = client.store(repo)?;
+            let mut missing: Vec<String> = request
+                .versions
+                .iter()
+                .filter_map(|entry| entry.version.hash.clone())
+                .filter(|hash| !store.contains_file(hash))
+                .collect();
+
+            if let Some(snapshot) = &request.snapshot
+                && !store.contains(&snapshot.id)
+            {
+                missing.push(snapshot.id.clone());
+            }
+
+            Ok(missing)
+        })
+        .await?
+    };
+
+    if !missing.is_empty() {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Referenced objects are missing: {}", missing.join(", "))));
+    }
+
+    let response = blocking(&state, move |repo| {
+        let index = repo.index.namespace(&namespace)?;
+        let mut recorded = 0;
+        for entry in &request.versions {
+            let path = Path::new(&entry.path);
+            if index.at(path, entry.version.timestamp)?.as_ref() != Some(&entry.version) {
+                index.record(path, &entry.version)?;
+                recorded += 1;
+            }
+        }
+
+        let snapshot = match request.snapshot {
+            Some(snapshot) => {
+                if !index.snapshots()?.iter().any(|record| record.id == snapshot.id) {
+                    index.record_snapshot(&snapshot)?;
+                }
+                Some(snapshot.id)
+            }
+            None => None,
+        };
+
+        index.flush()?;
+        Ok(CommitResponse { versions: recorded, snapshot })
+    })
+    .await?;
+
+    Ok(Json(response))
+}
+
+async fn list_snapshots(State(state): State<AppState>, client: Client, Query(query): Query<SnapshotsQuery>) -> Result<Json<Vec<SnapshotRecord>>, ApiError> {
+    let namespace = client.namespace(&query.set)?;
+    Ok(Json(blocking(&state, move |repo| repo.index.namespace(&namespace)?.snapshots()).await?))
+}
+
+/// Runs repository work on the blocking thread pool.
+async fn blocking<T, F>(state: &AppState, f: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&Repository) -> CratisResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let repo = state.repo.clone();
+    tokio::task::spawn_blocking(move || f(&repo))
+        .await
+        .map_err(|_| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Request handler panicked"))?
+        .map_err(ApiError::from)
+}
+
+/// Returns the digests `is_stored` reports as absent, rejecting malformed digests.
+fn filter_missing<F>(hashes: Vec<String>, is_stored: F) -> CratisResult<Vec<String>>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut missing = Vec::new();
+    for hash in hashes {
+        validate_hash(&hash)?;
+        if !is_stored(&hash) {
+            missing.push(hash);
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use cratis_core::client::{remote_path, UploadClient};
+    use cratis_core::config::{BackupMode, ClientConfig, ServerConfig};
+    use cratis_core::index::FileVersion;
+    use cratis_core::protocol::VersionEntry;
+    use cratis_core::snapshot::{store_snapshot, Snapshot};
+    use cratis_core::utils::EventAction;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use ureq::http::Request as HttpRequest;
+
+    const TOKEN: &str = "secret";
+
+    /// A running server on an ephemeral port, and a repository standing in for a client.
+    struct Fixture {
+        _dir: TempDir,
+        address: String,
+        base_url: String,
+        repo: Repository,
+        source: Repository,
+    }
+
+    impl Fixture {
+        fn client(&self, token: &str) -> UploadClient {
+            let client = ClientConfig { id: "laptop".to_string(), name: "Laptop".to_string() };
+            let server = ServerConfig { address: self.address.clone(), auth_token: token.to_string(), listen: None, enabled: Some(true), storage_path: None };
+            UploadClient::new(&client, &server)
+        }
+
+        /// Stores `contents` in the client repository and returns it as a revision of `path`.
+        fn revision(&self, path: &str, contents: &[u8], timestamp: u64) -> (PathBuf, FileVersion) {
+            let stored = self.source.store.put_reader(contents).unwrap();
+            let version = FileVersion {
+                timestamp,
+                hash: Some(stored.hash),
+                size: stored.size,
+                mtime: timestamp,
+                action: EventAction::Modify,
+                moved_to: None,
+            };
+            (PathBuf::from(path), version)
+        }
+    }
+
+    async fn serve() -> Fixture {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::open(dir.path().join("server")).unwrap();
+        let source = Repository::open(dir.path().join("client")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, router(repo.clone(), TOKEN)).into_future());
+
+        Fixture { _dir: dir, base_url: format!("{address}{API_PREFIX}"), address, repo, source }
+    }
+
+    /// Sends a request as client `laptop` and returns the status and body.
+    fn send(method: &str, url: &str, token: &str, body: Vec<u8>) -> (u16, Vec<u8>) {
+        send_as(method, url, token, "laptop", body)
+    }
+
+    /// Sends a request as `client`. Every `POST` endpoint takes JSON, every `PUT` raw bytes.
+    fn send_as(method: &str, url: &str, token: &str, client: &str, body: Vec<u8>) -> (u16, Vec<u8>) {
+        send_with(method, url, token, client, false, body)
+    }
+
+    /// Sends a request as `client`, marking its objects as encrypted if `encrypted` is set.
+    fn send_with(method: &str, url: &str, token: &str, client: &str, encrypted: bool, body: Vec<u8>) -> (u16, Vec<u8>) {
+        let content_type = if method == "POST" { "application/json" } else { OCTET_STREAM };
+        let agent: ureq::Agent = ureq::Agent::config_builder().http_status_as_error(false).build().into();
+        let request = HttpRequest::builder()
+            .method(method)
+            .uri(url)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .header(CLIENT_HEADER, client)
+            .header(ENCRYPTED_HEADER, encrypted.to_string())
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap();
+
+        let response = agent.run(request).unwrap();
+        let status = response.status().as_u16();
+        (status, response.into_body().read_to_vec().unwrap())
+    }
+
+    fn post_json<T: Serialize, R: DeserializeOwned>(url: &str, client: &str, body: &T) -> R {
+        let (status, body) = send_as("POST", url, TOKEN, client, serde_json::to_vec(body).unwrap());
+        assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Uploads a stored file and its chunks from the client repository.
+    fn upload_file(fixture: &Fixture, hash: &str) {
+        for chunk in fixture.source.store.file_recipe(hash).unwrap().chunks {
+            let data = fixture.source.store.get_encoded(&chunk.hash).unwrap();
+            assert_eq!(send("PUT", &format!("{}/objects/{}", fixture.base_url, chunk.hash), TOKEN, data).0, 201);
+        }
+
+        let recipe = fixture.source.store.get_encoded_file(hash).unwrap();
+        assert_eq!(send("PUT", &format!("{}/files/{hash}", fixture.base_url), TOKEN, recipe).0, 201);
+    }
+
+    fn version(hash: &str, size: u64) -> VersionEntry {
+        VersionEntry {
+            path: "/data/notes.txt".to_string(),
+            version: FileVersion { timestamp: 100, hash: Some(hash.to_string()), size, mtime: 100, action: EventAction::Create, moved_to: None },
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_without_the_token_are_rejected() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let url = format!("{}/snapshots", fixture.base_url);
+            assert_eq!(send("GET", &url, "wrong", Vec::new()).0, 401);
+
+            let agent: ureq::Agent = ureq::Agent::config_builder().http_status_as_error(false).build().into();
+            assert_eq!(agent.get(&url).call().unwrap().status().as_u16(), 401);
+
+            assert_eq!(send("GET", &url, TOKEN, Vec::new()).0, 200);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn objects_are_reported_missing_until_uploaded() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let hash = fixture.source.store.put_bytes(b"chunk").unwrap();
+            let data = fixture.source.store.get_encoded(&hash).unwrap();
+            let missing_url = format!("{}/objects/missing", fixture.base_url);
+            let object_url = format!("{}/objects/{hash}", fixture.base_url);
+
+            let response: MissingResponse = post_json(&missing_url, "laptop", &MissingRequest { hashes: vec![hash.clone()] });
+            assert_eq!(response.missing, vec![hash.clone()]);
+            assert_eq!(send("GET", &object_url, TOKEN, Vec::new()).0, 404);
+
+            assert_eq!(send("PUT", &object_url, TOKEN, data.clone()).0, 201);
+            assert_eq!(send("PUT", &object_url, TOKEN, data.clone()).0, 200);
+
+            let response: MissingResponse = post_json(&missing_url, "laptop", &MissingRequest { hashes: vec![hash.clone()] });
+            assert!(response.missing.is_empty());
+            assert_eq!(send("GET", &object_url, TOKEN, Vec::new()), (200, data));
+
+            let (status, _) = send("POST", &missing_url, TOKEN, br#"{"hashes":["../escape"]}"#.to_vec());
+            assert_eq!(status, 400);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_not_matching_their_digest_are_rejected() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let genuine = fixture.source.store.put_bytes(b"genuine").unwrap();
+            let forged = fixture.source.store.put_bytes(b"forged").unwrap();
+            let data = fixture.source.store.get_encoded(&forged).unwrap();
+            let url = format!("{}/objects/{genuine}", fixture.base_url);
+            assert_eq!(send("PUT", &url, TOKEN, data).0, 400);
+            assert_eq!(send("PUT", &url, TOKEN, b"not an object".to_vec()).0, 400);
+            assert!(!fixture.repo.store.contains(&genuine));
+
+            let first = fixture.source.store.put_reader(&b"first file"[..]).unwrap();
+            let second = fixture.source.store.put_reader(&b"second file"[..]).unwrap();
+            let recipe = fixture.source.store.get_encoded_file(&first.hash).unwrap();
+            let url = format!("{}/files/{}", fixture.base_url, first.hash);
+            assert_eq!(send("PUT", &url, TOKEN, recipe.clone()).0, 400, "recipe uploaded before its chunks");
+
+            upload_file(&fixture, &first.hash);
+            let url = format!("{}/files/{}", fixture.base_url, second.hash);
+            assert_eq!(send("PUT", &url, TOKEN, recipe).0, 400);
+            assert!(!fixture.repo.store.contains_file(&second.hash));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_uploads_are_kept_per_client() {
+        let mut fixture = serve().await;
+        fixture.source = fixture.source.clone().init_encryption("passphrase").unwrap();
+
+        tokio::task::spawn_blocking(move || {
+            let (path, version) = fixture.revision("/data/a.txt", b"contents", 100);
+            let hash = version.hash.clone().unwrap();
+            fixture.client(TOKEN).push_versions(&fixture.source, &[(path, version)]).unwrap();
+
+            let own = ObjectStore::open(fixture.repo.root().join(CLIENTS_DIR).join("laptop")).unwrap();
+            assert!(own.contains_file(&hash));
+            assert!(!fixture.repo.store.contains_file(&hash));
+
+            let url = format!("{}/files/{hash}", fixture.base_url);
+            assert_eq!(send_with("GET", &url, TOKEN, "laptop", true, Vec::new()).0, 200);
+            assert_eq!(send_with("GET", &url, TOKEN, "desktop", true, Vec::new()).0, 404);
+            assert_eq!(send("GET", &url, TOKEN, Vec::new()).0, 404);
+
+            let recipe = fixture.source.store.get_encoded_file(&hash).unwrap();
+            assert_eq!(send_with("PUT", &url, TOKEN, "desktop", true, recipe).0, 201);
+            assert!(own.contains_file(&hash));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commits_with_missing_objects_are_rejected() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let stored = fixture.source.store.put_reader(&b"hello world"[..]).unwrap();
+            let request = CommitRequest { versions: vec![version(&stored.hash, stored.size)], ..CommitRequest::default() };
+            let url = format!("{}/commit", fixture.base_url);
+
+            let (status, _) = send("POST", &url, TOKEN, serde_json::to_vec(&request).unwrap());
+            assert_eq!(status, 409);
+
+            upload_file(&fixture, &stored.hash);
+            let response: CommitResponse = post_json(&url, "laptop", &request);
+            assert_eq!(response.versions, 1);
+
+            let response: CommitResponse = post_json(&url, "laptop", &request);
+            assert_eq!(response.versions, 0);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_content_is_streamed() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let content: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+            let stored = fixture.source.store.put_reader(content.as_slice()).unwrap();
+            assert!(stored.chunks > 1);
+
+            let url = format!("{}/files/{}/content", fixture.base_url, stored.hash);
+            assert_eq!(send("GET", &url, TOKEN, Vec::new()).0, 404);
+
+            upload_file(&fixture, &stored.hash);
+            assert_eq!(send("GET", &url, TOKEN, Vec::new()), (200, content));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clients_have_separate_histories() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let id = fixture.source.store.put_bytes(b"manifest").unwrap();
+            let data = fixture.source.store.get_encoded(&id).unwrap();
+            assert_eq!(send("PUT", &format!("{}/objects/{id}", fixture.base_url), TOKEN, data).0, 201);
+
+            let snapshot = SnapshotRecord { id: id.clone(), timestamp: 100, mode: BackupMode::Full, files: 0, size: 0 };
+            let request = CommitRequest { snapshot: Some(snapshot.clone()), ..CommitRequest::default() };
+            let _: CommitResponse = post_json(&format!("{}/commit", fixture.base_url), "laptop", &request);
+
+            let url = format!("{}/snapshots", fixture.base_url);
+            let (_, body) = send_as("GET", &url, TOKEN, "laptop", Vec::new());
+            assert_eq!(serde_json::from_slice::<Vec<SnapshotRecord>>(&body).unwrap(), vec![snapshot]);
+
+            let (_, body) = send_as("GET", &url, TOKEN, "desktop", Vec::new());
+            assert!(serde_json::from_slice::<Vec<SnapshotRecord>>(&body).unwrap().is_empty());
+
+            assert_eq!(send_as("GET", &url, TOKEN, "../other", Vec::new()).0, 400);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backup_sets_have_separate_histories() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let photos = fixture.source.backup_set("photos").unwrap();
+            let (path, version) = fixture.revision("/data/a.jpg", b"pixels", 100);
+            let client = fixture.client(TOKEN);
+            client.push_versions(&photos, &[(path.clone(), version)]).unwrap();
+
+            let manifest = Snapshot { timestamp: 100, mode: BackupMode::Full, parent: None, roots: Vec::new(), entries: Vec::new(), removed: Vec::new() };
+            let snapshot = store_snapshot(&photos, &manifest, 0, 0).unwrap();
+            client.push_snapshot(&photos, &snapshot).unwrap();
+
+            assert_eq!(client.snapshots("photos").unwrap(), vec![snapshot]);
+            assert!(client.snapshots("default").unwrap().is_empty());
+            assert!(fixture.repo.index.namespace("laptop/photos").unwrap().latest(&path).unwrap().is_some());
+            assert!(fixture.repo.index.namespace("laptop/default").unwrap().latest(&path).unwrap().is_none());
+
+            let request = CommitRequest { set: "../photos".to_string(), ..CommitRequest::default() };
+            let url = format!("{}/commit", fixture.base_url);
+            assert_eq!(send("POST", &url, TOKEN, serde_json::to_vec(&request).unwrap()).0, 400);
+            assert_eq!(send("GET", &format!("{}/snapshots?set=..", fixture.base_url), TOKEN, Vec::new()).0, 400);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pushing_twice_uploads_nothing_new() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let versions = vec![
+                fixture.revision("/data/a.txt", b"first file", 100),
+                fixture.revision("/data/b.txt", b"second file", 100),
+            ];
+            let client = fixture.client(TOKEN);
+
+            let report = client.push_versions(&fixture.source, &versions).unwrap();
+            assert_eq!((report.files_uploaded, report.objects_uploaded, report.versions_recorded), (2, 2, 2));
+
+            let report = client.push_versions(&fixture.source, &versions).unwrap();
+            assert_eq!((report.files_uploaded, report.objects_uploaded, report.versions_recorded), (0, 0, 0));
+            assert_eq!(report.bytes_uploaded, 0);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pushing_with_a_wrong_token_fails() {
+        let fixture = serve().await;
+
+        tokio::task::spawn_blocking(move || {
+            let versions = vec![fixture.revision("/data/a.txt", b"first file", 100)];
+            let result = fixture.client("wrong").push_versions(&fixture.source, &versions);
+            assert!(matches!(result, Err(CratisError::AuthFailure(_))), "{result:?}");
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paths_of_encrypted_repositories_are_not_sent() {
+        let mut fixture = serve().await;
+        fixture.source = fixture.source.clone().init_encryption("passphrase").unwrap();
+
+        tokio::task::spawn_blocking(move || {
+            let (path, mut version) = fixture.revision("/home/user/secret-plans.txt", b"contents", 100);
+            version.action = EventAction::Rename { from: PathBuf::from("/home/user/draft.txt"), to: path.clone() };
+            fixture.client(TOKEN).push_versions(&fixture.source, &[(path.clone(), version)]).unwrap();
+
+            let index = fixture.repo.index.namespace("laptop/default").unwrap();
+            let recorded = index.paths().unwrap();
+            assert_eq!(recorded, vec![remote_path(&fixture.source, &path)]);
+
+            let version = index.latest(&recorded[0]).unwrap().unwrap();
+            let EventAction::Rename { from, to } = version.action else { panic!("Expected a rename") };
+            assert_eq!(from, remote_path(&fixture.source, Path::new("/home/user/draft.txt")));
+            assert_eq!(to, recorded[0]);
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::BTreeMap;
+use std::path::{self, Path, PathBuf};
+use cratis_core::config::{BackupConfig, CratisConfig};
+use cratis_core::error::{CratisError, CratisResult};
+use cratis_core::exclude::ExcludeRules;
+use cratis_core::index::SnapshotRecord;
+use cratis_core::maintenance::{self, PruneOptions};
+use cratis_core::repository::Repository;
+use cratis_core::restore::{self, ConflictPolicy, RestoreOptions};
+use cratis_core::snapshot::{resolve_tree, SnapshotEntry};
+use cratis_core::utils::{timestamp_now, to_human_readable_size, EventAction};
+use crate::time::{format_time, parse_time};
+use crate::RestoreArgs;
+
+/// Creates the backup repository, optionally encrypted with the configured passphrase.
+pub fn init(config: &CratisConfig, encrypt: bool) -> CratisResult<()> {
+    let path = config.storage_path();
+    let mut repo = Repository::open(&path)?;
+
+    if encrypt && !repo.is_encrypted() {
+        let passphrase = config
+            .passphrase()?
+            .ok_or(CratisError::ConfigError("Set CRATIS_PASSPHRASE or storage.passphrase_file to encrypt the repository".to_string()))?;
+        repo = repo.init_encryption(&passphrase)?;
+    }
+
+    println!(
+        "Initialized {} repository at {}",
+        if repo.is_encrypted() { "encrypted" } else { "unencrypted" },
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Prints a summary of the backup repository and of every selected backup set.
+pub fn status(config: &CratisConfig, set: Option<&str>) -> CratisResult<()> {
+    let repo = Repository::open_configured(config)?;
+    let sets = selected_sets(config, set)?;
+
+    println!("Repository:  {}", repo.root().display());
+    println!("Encrypted:   {}", if repo.is_encrypted() { "yes" } else { "no" });
+
+    for (name, backup) in sets {
+        let repo = repo.backup_set(name)?;
+
+        let mut tracked = 0;
+        let mut live = 0;
+        let mut live_size = 0;
+        for path in repo.index.paths()? {
+            tracked += 1;
+            if let Some(version) = repo.index.latest(&path)?
+                && !version.is_deleted()
+                && !version.is_directory()
+            {
+                live += 1;
+                live_size += version.size;
+            }
+        }
+
+        let snapshots = repo.index.snapshots()?;
+
+        println!();
+        println!("Backup set:  {}", name);
+        println!("Mode:        {:?}", backup.mode);
+        println!("Files:       {} live, {} tracked ({})", live, tracked, to_human_readable_size(live_size as f64));
+        println!("Snapshots:   {}", snapshots.len());
+        if let Some(latest) = snapshots.last() {
+            println!("Last backup: {} ({})", format_time(latest.timestamp), short_id(&latest.id));
+        }
+
+        let skipped = repo.index.skipped()?;
+        if !skipped.is_empty() {
+            println!("Skipped:     {} files exceed max_file_size_mb", skipped.len());
+            for (path, file) in skipped {
+                println!(
+                    "  {} ({}, limit {}, {})",
+                    path.display(),
+                    to_human_readable_size(file.size as f64),
+                    to_human_readable_size(file.limit as f64),
+                    format_time(file.timestamp)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists every recorded version of a file, following it back across moves.
+pub fn log(config: &CratisConfig, set: Option<&str>, path: &Path) -> CratisResult<()> {
+    let path = absolute(path)?;
+    let repo = Repository::open_configured(config)?.backup_set(set_for_path(config, set, &path)?)?;
+
+    let history = repo.index.history(&path)?;
+    if history.is_empty() {
+        return Err(CratisError::InvalidPath(format!("No versions recorded for {}", path.display())));
+    }
+
+    for (recorded_at, version) in history {
+        let note = match (&version.action, &version.moved_to) {
+            (EventAction::Rename { from, .. }, _) => format!("  (moved from {})", from.display()),
+            (_, Some(to)) => format!("  (moved to {})", to.display()),
+            _ if recorded_at != path => format!("  (as {})", recorded_at.display()),
+            _ => String::new(),
+        };
+
+        println!(
+            "{}  {:<9} {:>12}  {}{}",
+            format_time(version.timestamp),
+            action_label(&version.action),
+            if version.hash.is_some() { to_human_readable_size(version.size as f64) } else { "-".to_string() },
+            version.hash.as_deref().map(short_id).unwrap_or("-"),
+            note
+        );
+    }
+
+    Ok(())
+}
+
+/// Restores a file or directory as it was at the requested point in time.
+pub fn restore(config: &CratisConfig, set: Option<&str>, args: &RestoreArgs) -> CratisResult<()> {
+    let path = absolute(&args.path)?;
+    let repo = Repository::open_configured(config)?.backup_set(set_for_path(config, set, &path)?)?;
+    let timestamp = parse_time(&args.at)?;
+
+    let conflict = if args.overwrite {
+        ConflictPolicy::Overwrite
+    } else if args.skip_existing {
+        ConflictPolicy::SkipExisting
+    } else {
+        ConflictPolicy::Alongside(args.suffix.clone())
+    };
+    let target = args.target.as_deref().map(absolute).transpose()?;
+
+    let report = restore::restore(&repo, &path, timestamp, &RestoreOptions { target, conflict })?;
+
+    for (_, restored) in &report.restored {
+        println!("Restored {}", restored.display());
+    }
+    for skipped in &report.skipped {
+        println!("Skipped  {} (already exists)", skipped.display());
+    }
+    for (path, e) in &report.failed {
+        eprintln!("Failed   {}: {}", path.display(), e);
+    }
+
+    if !report.failed.is_empty() {
+        return Err(CratisError::BackupFailure("Some files could not be restored"));
+    }
+
+    Ok(())
+}
+
+/// Lists all snapshots of every selected backup set, oldest first.
+pub fn snapshots(config: &CratisConfig, set: Option<&str>) -> CratisResult<()> {
+    let repo = Repository::open_configured(config)?;
+    let sets = selected_sets(config, set)?;
+
+    for (name, _) in &sets {
+        if sets.len() > 1 {
+            println!("{}:", name);
+        }
+
+        for record in repo.backup_set(name)?.index.snapshots()? {
+            println!(
+                "{}  {}  {:<11} {:>7} files  {:>12}",
+                short_id(&record.id),
+                format_time(record.timestamp),
+                format!("{:?}", record.mode),
+                record.files,
+                to_human_readable_size(record.size as f64)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the files that were added (`+`), removed (`-`) or modified (`M`) between two snapshots.
+pub fn diff(config: &CratisConfig, set: Option<&str>, from: &str, to: Option<&str>) -> CratisResult<()> {
+    let repo = Repository::open_configured(config)?.backup_set(single_set(config, set)?)?;
+    let records = repo.index.snapshots()?;
+
+    let from = find_snapshot(&records, from)?;
+    let to = match to {
+        Some(to) => find_snapshot(&records, to)?,
+        None => records.last().cloned().ok_or(CratisError::InvalidInput("No snapshots recorded"))?,
+    };
+
+    let old = tree_by_path(resolve_tree(&repo, &from.id)?);
+    let new = tree_by_path(resolve_tree(&repo, &to.id)?);
+
+    println!("{} ({}) -> {} ({})", short_id(&from.id), format_time(from.timestamp), short_id(&to.id), format_time(to.timestamp));
+    for (path, entry) in &new {
+        match old.get(path) {
+            None => println!("+ {path}"),
+            Some(previous) if previous.hash != entry.hash || previous.mode != entry.mode => println!("M {path}"),
+            Some(_) => {}
+        }
+    }
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        println!("- {path}");
+    }
+
+    Ok(())
+}
+
+/// Applies the retention of every selected backup set and deletes unreferenced data.
+///
+/// `keep_snapshots` and `keep_days` override the configured retention of the sets.
+pub fn prune(config: &CratisConfig, set: Option<&str>, keep_snapshots: Option<usize>, keep_days: Option<u64>, dry_run: bool) -> CratisResult<()> {
+    let repo = Repository::open_configured(config)?;
+    let now = timestamp_now()?;
+
+    for (name, backup) in selected_sets(config, set)? {
+        let retention = backup.retention.clone().unwrap_or_default();
+        let keep_days = keep_days.or(retention.keep_days);
+
+        let options = PruneOptions {
+            keep_snapshots: keep_snapshots.or(retention.keep_snapshots),
+            keep_versions_since: keep_days.map(|days| now.saturating_sub(days.saturating_mul(24 * 60 * 60))),
+            dry_run,
+        };
+        let report = maintenance::prune(&repo.backup_set(name)?, &options)?;
+
+        println!(
+            "{}: {} {} snapshots, {} versions, {} files and {} chunks ({})",
+            name,
+            if dry_run { "Would remove" } else { "Removed" },
+            report.snapshots_removed,
+            report.versions_removed,
+            report.files_removed,
+            report.objects_removed,
+            to_human_readable_size(report.bytes_freed as f64)
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks every stored file and snapshot against its hash.
+pub fn verify(config: &CratisConfig) -> CratisResult<()> {
+    let repo = Repository::open_configured(config)?;
+    let report = maintenance::verify(&repo)?;
+
+    for (id, e) in &report.errors {
+        eprintln!("{}: {}", short_id(id), e);
+    }
+    println!(
+        "Checked {} files and {} snapshots, {} errors",
+        report.files_checked,
+        report.snapshots_checked,
+        report.errors.len()
+    );
+
+    if !report.errors.is_empty() {
+        return Err(CratisError::BackupFailure("Repository verification failed"));
+    }
+
+    Ok(())
+}
+
+/// Prints for every path whether it is excluded from the backup, and by which rule.
+///
+/// A path is checked against every selected backup set watching it.
+pub fn check_ignore(config: &CratisConfig, set: Option<&str>, paths: &[PathBuf]) -> CratisResult<()> {
+    let mut sets = Vec::new();
+    for (name, backup) in selected_sets(config, set)? {
+        sets.push((name, backup, ExcludeRules::from_config(backup)?));
+    }
+
+    for path in paths {
+        let path = absolute(path)?;
+        let watching: Vec<_> = sets
+            .iter()
+            .filter(|(_, backup, _)| backup.watch_directories.iter().any(|dir| path.starts_with(dir)))
+            .collect();
+
+        if watching.is_empty() {
+            println!("{}  not in a watched directory", path.display());
+        }
+
+        for (name, _, rules) in watching {
+            let prefix = if sets.len() > 1 { format!("{name}: ") } else { String::new() };
+            match rules.explain(&path) {
+                Some(rule) => println!("{}{}  excluded by {}", prefix, path.display(), rule),
+                None => println!("{}{}  included", prefix, path.display()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints a summary of the configuration.
+///
+/// The configuration is validated while it is loaded, so reaching this point means it is valid.
+pub fn config_check(config: &CratisConfig) -> CratisResult<()> {
+    println!("Client:      {} ({})", config.client.name, config.client.id);
+    println!("Repository:  {}", config.storage_path().display());
+    for (name, backup) in config.backup_sets() {
+        println!("Backup set:  {} ({:?}), watching {}", name, backup.mode, backup.watch_directories.join(", "));
+    }
+    println!("Configuration OK");
+    Ok(())
+}
+
+/// Finds a snapshot by id prefix or by point in time, see `parse_time`.
+///
+/// A query made only of decimal digits reads as both an id prefix and a Unix timestamp. It is
+/// rejected if both readings find a snapshot, so it never silently picks the wrong one.
+///
+/// # Arguments
+/// * `records` - The recorded snapshots, oldest first
+/// * `query` - An id prefix or a point in time
+fn find_snapshot(records: &[SnapshotRecord], query: &str) -> CratisResult<SnapshotRecord> {
+    let is_hex = !query.is_empty() && query.bytes().all(|b| b.is_ascii_hexdigit());
+    let by_id: Vec<&SnapshotRecord> = if is_hex { records.iter().filter(|record| record.id.starts_with(query)).collect() } else { Vec::new() };
+    let by_time = match parse_time(query) {
+        Ok(timestamp) => records.iter().rev().find(|record| record.timestamp <= timestamp),
+        Err(e) if by_id.is_empty() => return Err(e),
+        Err(_) => None,
+    };
+
+    match (by_id.as_slice(), by_time) {
+        ([record], None) => Ok((*record).clone()),
+        ([_, _, ..], None) => Err(CratisError::InvalidInput("Ambiguous snapshot id prefix")),
+        ([], Some(record)) => Ok(record.clone()),
+        ([], None) => Err(CratisError::InvalidInput("No snapshot recorded at or before that time")),
+        (_, Some(_)) => Err(CratisError::InvalidInput("Both a snapshot id prefix and a time, use a longer prefix or a date")),
+    }
+}
+
+/// Returns the backup set selected with `--set`, or every configured set.
+fn selected_sets<'a>(config: &'a CratisConfig, set: Option<&str>) -> CratisResult<Vec<(&'a str, &'a BackupConfig)>> {
+    let Some(name) = set else {
+        return Ok(config.backup_sets());
+    };
+
+    config.backup_set(name)?;
+    Ok(config.backup_sets().into_iter().filter(|(set, _)| *set == name).collect())
+}
+
+/// Returns the backup set selected with `--set`, or the only configured set.
+fn single_set<'a>(config: &'a CratisConfig, set: Option<&'a str>) -> CratisResult<&'a str> {
+    if let Some(name) = set {
+        config.backup_set(name)?;
+        return Ok(name);
+    }
+
+    match config.backup_sets().as_slice() {
+        [(name, _)] => Ok(name),
+        _ => Err(CratisError::InvalidInput("Several backup sets are configured, select one with --set")),
+    }
+}
+
+/// Returns the backup set selected with `--set`, or the set whose watch directories contain
+/// `path`.
+///
+/// Falls back to the only configured set, so files of directories that are no longer watched
+/// can still be found.
+fn set_for_path<'a>(config: &'a CratisConfig, set: Option<&'a str>, path: &Path) -> CratisResult<&'a str> {
+    if set.is_some() {
+        return single_set(config, set);
+    }
+
+    let containing: Vec<&str> = config
+        .backup_sets()
+        .into_iter()
+        .filter(|(_, backup)| backup.watch_directories.iter().any(|dir| path.starts_with(dir)))
+        .map(|(name, _)| name)
+        .collect();
+
+    match containing.as_slice() {
+        [name] => Ok(name),
+        [] => single_set(config, None),
+        _ => Err(CratisError::ConfigError(format!(
+            "{} belongs to the backup sets {}, select one with --set",
+            path.display(),
+            containing.join(", ")
+        ))),
+    }
+}
+
+fn tree_by_path(entries: Vec<SnapshotEntry>) -> BTreeMap<String, SnapshotEntry> {
+    entries.into_iter().map(|entry| (entry.path.clone(), entry)).collect()
+}
+
+fn absolute(path: &Path) -> CratisResult<PathBuf> {
+    Ok(path::absolute(path)?)
+}
+
+fn short_id(id: &str) -> &str {
+    &id[..id.len().min(12)]
+}
+
+fn action_label(action: &EventAction) -> &'static str {
+    match action {
+        EventAction::Create => "Create",
+        EventAction::Modify => "Modify",
+        EventAction::Delete => "Delete",
+        EventAction::Rename { .. } => "Rename",
+        EventAction::MetadataChange => "Metadata",
+        EventAction::CreateDir => "CreateDir",
+        EventAction::DeleteDir => "DeleteDir",
+        EventAction::Other => "Other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cratis_core::config::BackupMode;
+
+    fn record(id: &str, timestamp: u64) -> SnapshotRecord {
+        SnapshotRecord { id: id.repeat(64 / id.len()), timestamp, mode: BackupMode::Full, files: 0, size: 0 }
+    }
+
+    fn records() -> Vec<SnapshotRecord> {
+        vec![record("ab", 1_000), record("ac", 2_000), record("17", 3_000)]
+    }
+
+    #[test]
+    fn snapshots_are_found_by_id_prefix() {
+        assert_eq!(find_snapshot(&records(), "aba").unwrap().timestamp, 1_000);
+        assert_eq!(find_snapshot(&records(), &"ac".repeat(32)).unwrap().timestamp, 2_000);
+        assert!(matches!(find_snapshot(&records(), "a"), Err(CratisError::InvalidInput(_))));
+        assert!(matches!(find_snapshot(&records(), "ff"), Err(CratisError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn snapshots_are_found_by_time() {
+        assert_eq!(find_snapshot(&records(), "2500").unwrap().timestamp, 2_000);
+        assert_eq!(find_snapshot(&records(), "now").unwrap().timestamp, 3_000);
+        assert_eq!(find_snapshot(&records(), "1970-01-01T00:50:00Z").unwrap().timestamp, 3_000);
+        assert!(matches!(find_snapshot(&records(), "999"), Err(CratisError::InvalidInput(_))));
+        assert!(matches!(find_snapshot(&records(), "yesterday"), Err(CratisError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn digits_matching_an_id_and_a_time_are_rejected() {
+        assert!(matches!(find_snapshot(&records(), "1717"), Err(CratisError::InvalidInput(_))));
+        assert_eq!(find_snapshot(&records(), "17").unwrap().timestamp, 3_000);
+        assert_eq!(find_snapshot(&records()[..2], "1717").unwrap().timestamp, 1_000);
+    }
+}
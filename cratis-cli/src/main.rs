@@ -1,3 +1,134 @@
+mod commands;
+mod time;
+
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use cratis_core::config::{find_config, load_config};
+use cratis_core::error::display_error;
+
+/// Command line interface to the Cratis backup repository.
+#[derive(Debug, Parser)]
+#[command(name = "cratis", version, about)]
+struct Cli {
+    /// Path to the configuration file, otherwise $CRATIS_CONFIG, $XDG_CONFIG_HOME/cratis/cratis.yml or /etc/cratis/cratis.yml
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Backup set to work on, by default the set containing the given path or every set
+    #[arg(short, long, global = true)]
+    set: Option<String>,
+
+    /// Print errors with full debug information
+    #[arg(long, global = true)]
+    debug: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create the backup repository
+    Init {
+        /// Encrypt the repository with the configured passphrase
+        #[arg(long)]
+        encrypt: bool,
+    },
+    /// Show a summary of the backup repository
+    Status,
+    /// List every recorded version of a file
+    Log {
+        path: PathBuf,
+    },
+    /// Restore a file or directory as it was at a point in time
+    Restore(RestoreArgs),
+    /// List all snapshots
+    Snapshots,
+    /// Show the files that differ between two snapshots
+    Diff {
+        /// Snapshot id (prefix) or time of the older snapshot
+        from: String,
+        /// Snapshot id (prefix) or time of the newer snapshot, defaults to the latest snapshot
+        to: Option<String>,
+    },
+    /// Remove old snapshots and versions and delete unreferenced data
+    Prune {
+        /// Number of most recent snapshots to keep, overrides the retention of the backup set
+        #[arg(long)]
+        keep_snapshots: Option<usize>,
+        /// Keep every file version from the last N days, overrides the retention of the backup set
+        #[arg(long)]
+        keep_days: Option<u64>,
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check every stored file and snapshot against its hash
+    Verify,
+    /// Show whether paths are excluded from the backup, and by which rule
+    CheckIgnore {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Args)]
+struct RestoreArgs {
+    /// The original path of the file or directory
+    path: PathBuf,
+    /// Point in time to restore, e.g. "2024-06-10 14:00", RFC 3339 or a Unix timestamp
+    #[arg(long)]
+    at: String,
+    /// Directory to restore into instead of the original location
+    #[arg(long)]
+    target: Option<PathBuf>,
+    /// Replace existing files
+    #[arg(long, conflicts_with_all = ["skip_existing", "suffix"])]
+    overwrite: bool,
+    /// Keep existing files and skip them
+    #[arg(long, conflicts_with = "suffix")]
+    skip_existing: bool,
+    /// Restore next to existing files, appending this suffix to the file name
+    #[arg(long, default_value = ".restored")]
+    suffix: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Check the configuration for problems
+    Check,
+}
+
+/// Entry point for the Cratis command line interface.
+///
+/// Parses the command line, loads the configuration and dispatches to the subcommand.
+/// Errors are printed to stderr and terminate the process with exit code 1.
 fn main() {
-    println!("Hello, world!");
-}
\ No newline at end of file
+    let cli = Cli::parse();
+
+    let config = match find_config(cli.config.as_deref()).and_then(load_config) {
+        Ok(config) => config,
+        Err(e) => display_error(&e, cli.debug),
+    };
+
+    let set = cli.set.as_deref();
+    let result = match cli.command {
+        Command::Init { encrypt } => commands::init(&config, encrypt),
+        Command::Status => commands::status(&config, set),
+        Command::Log { path } => commands::log(&config, set, &path),
+        Command::Restore(args) => commands::restore(&config, set, &args),
+        Command::Snapshots => commands::snapshots(&config, set),
+        Command::Diff { from, to } => commands::diff(&config, set, &from, to.as_deref()),
+        Command::Prune { keep_snapshots, keep_days, dry_run } => commands::prune(&config, set, keep_snapshots, keep_days, dry_run),
+        Command::Verify => commands::verify(&config),
+        Command::CheckIgnore { paths } => commands::check_ignore(&config, set, &paths),
+        Command::Config(ConfigCommand::Check) => commands::config_check(&config),
+    };
+
+    if let Err(e) = result {
+        display_error(&e, cli.debug);
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use cratis_core::error::{CratisError, CratisResult};
+use cratis_core::utils::timestamp_now;
+
+const DATE_TIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"];
+
+/// Parses a point in time given on the command line into a Unix timestamp.
+///
+/// Accepts:
+/// * `now`
+/// * A Unix timestamp in seconds, e.g. `1718000000`
+/// * An RFC 3339 timestamp, e.g. `2024-06-10T14:00:00+02:00`
+/// * A local date and time, e.g. `2024-06-10 14:00` or `2024-06-10 14:00:30`
+/// * A local date, e.g. `2024-06-10`, meaning the end of that day
+///
+/// # Errors
+///
+/// Returns `CratisError::InvalidInput` if the value matches none of the formats.
+pub fn parse_time(value: &str) -> CratisResult<u64> {
+    let value = value.trim();
+
+    if value == "now" {
+        return timestamp_now();
+    }
+
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp);
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return to_timestamp(time.timestamp());
+    }
+
+    for format in DATE_TIME_FORMATS {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return local_timestamp(time);
+        }
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        && let Some(end_of_day) = date.and_hms_opt(23, 59, 59)
+    {
+        return local_timestamp(end_of_day);
+    }
+
+    Err(CratisError::InvalidInput("Unrecognized time, expected e.g. '2024-06-10 14:00', RFC 3339 or a Unix timestamp"))
+}
+
+/// Formats a Unix timestamp as a local date and time.
+pub fn format_time(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|ts| Local.timestamp_opt(ts, 0).single())
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn local_timestamp(time: NaiveDateTime) -> CratisResult<u64> {
+    let local = Local
+        .from_local_datetime(&time)
+        .earliest()
+        .ok_or(CratisError::InvalidInput("Time does not exist in the local time zone"))?;
+
+    to_timestamp(local.timestamp())
+}
+
+fn to_timestamp(seconds: i64) -> CratisResult<u64> {
+    u64::try_from(seconds).map_err(|_| CratisError::InvalidInput("Time lies before the Unix epoch"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_and_rfc3339_timestamps() {
+        assert_eq!(parse_time("1718000000").unwrap(), 1_718_000_000);
+        assert_eq!(parse_time(" 0 ").unwrap(), 0);
+        assert_eq!(parse_time("2024-06-10T06:13:20Z").unwrap(), 1_718_000_000);
+        assert_eq!(parse_time("2024-06-10T08:13:20+02:00").unwrap(), 1_718_000_000);
+
+        let now = timestamp_now().unwrap();
+        assert!(parse_time("now").unwrap() >= now);
+    }
+
+    #[test]
+    fn local_dates_and_times() {
+        let minutes = parse_time("2024-06-10 14:00").unwrap();
+        assert_eq!(parse_time("2024-06-10 14:00:30").unwrap(), minutes + 30);
+        assert_eq!(parse_time("2024-06-10T14:00").unwrap(), minutes);
+        assert_eq!(parse_time("2024-06-10").unwrap(), parse_time("2024-06-10 23:59:59").unwrap());
+    }
+
+    #[test]
+    fn invalid_times_are_rejected() {
+        for value in ["", "yesterday", "2024-13-01", "2024-06-10 25:00", "-5", "1969-12-31T23:59:59Z"] {
+            assert!(matches!(parse_time(value), Err(CratisError::InvalidInput(_))), "{value}");
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use ureq::http::Response;
+use ureq::{Agent, Body};
+use crate::config::{ClientConfig, ServerConfig};
+use crate::error::{CratisError, CratisResult};
+use crate::index::{FileVersion, SnapshotRecord};
+use crate::protocol::{CommitRequest, CommitResponse, MissingRequest, MissingResponse, VersionEntry, API_PREFIX, CLIENT_HEADER, ENCRYPTED_HEADER};
+use crate::repository::Repository;
+use crate::retry::RetryPolicy;
+use crate::snapshot::load_snapshot;
+use crate::utils::EventAction;
+
+/// Maximum number of digests sent in a single "missing" query.
+const MISSING_BATCH: usize = 1000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+/// Prefix of the data hashed by `remote_path`, separating path digests from object digests.
+const PATH_DOMAIN: &[u8] = b"cratis-path\0";
+
+/// What a push transferred to the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadReport {
+    /// File recipes the server did not have yet.
+    pub files_uploaded: usize,
+    /// Chunks and snapshot manifests the server did not have yet.
+    pub objects_uploaded: usize,
+    /// Encoded bytes sent, excluding protocol overhead.
+    pub bytes_uploaded: u64,
+    /// File revisions newly recorded by the server.
+    pub versions_recorded: usize,
+}
+
+impl UploadReport {
+    fn merge(&mut self, other: UploadReport) {
+        self.files_uploaded += other.files_uploaded;
+        self.objects_uploaded += other.objects_uploaded;
+        self.bytes_uploaded += other.bytes_uploaded;
+        self.versions_recorded += other.versions_recorded;
+    }
+}
+
+/// A blocking client pushing the contents of a local repository to a cratis-api server.
+///
+/// Before anything is uploaded the client asks the server which digests it is missing, so only
+/// new data crosses the wire. Objects are sent in their encoded form (see
+/// `ObjectStore::get_encoded`), so the server never sees the plaintext of an encrypted
+/// repository. For the same reason the paths of an encrypted repository are replaced by their
+/// keyed digest before they are sent, see `remote_path`. Uploads are ordered so the server
+/// never stores a reference to something it does not have: chunks before the recipes listing
+/// them, recipes before the manifests and commits referring to them.
+///
+/// Every request carries `server.auth_token` as a bearer token, `client.id` in the
+/// `CLIENT_HEADER` and whether the repository is encrypted in the `ENCRYPTED_HEADER`, which
+/// keeps the history and encrypted objects of this client apart from others on the server.
+/// Requests failing with a transient error (unreachable server, timeout, 5xx) are retried
+/// according to the client's `RetryPolicy`. All requests are idempotent, so a retry never
+/// records anything twice.
+#[derive(Debug, Clone)]
+pub struct UploadClient {
+    agent: Agent,
+    base_url: String,
+    authorization: String,
+    client_id: String,
+    retry: RetryPolicy,
+}
+
+impl UploadClient {
+    /// Creates a client for the server described by the configuration.
+    ///
+    /// No connection is made until the first request.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let client = UploadClient::new(&config.client, &config.server);
+    /// let report = client.push_snapshot(&repo, &record)?;
+    /// println!("Uploaded {} chunks", report.objects_uploaded);
+    /// ```
+    pub fn new(client: &ClientConfig, server: &ServerConfig) -> Self {
+        let agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_connect(Some(CONNECT_TIMEOUT))
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .build()
+            .into();
+
+        Self {
+            agent,
+            base_url: format!("{}{}", server.address.trim_end_matches('/'), API_PREFIX),
+            authorization: format!("Bearer {}", server.auth_token),
+            client_id: client.id.clone(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Sets the policy for retrying requests that fail with a transient error.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uploads the given file revisions and records them on the server.
+    ///
+    /// Revisions without content (deletions) are recorded as well. Revisions the server already
+    /// recorded are skipped by the server, so pushing the same revisions twice is harmless.
+    /// Paths of an encrypted repository, including rename targets, are sent as keyed digests.
+    ///
+    /// # Arguments
+    /// * `repo` - The local repository holding the revisions
+    /// * `versions` - The revisions to push, as `(path, revision)`
+    ///
+    /// # Errors
+    /// * `CratisError::ConnectionIssue` or `CratisError::Timeout` if the server cannot be reached
+    /// * `CratisError::AuthFailure` if the server rejects the auth token
+    /// * Any error raised while reading the local repository
+    pub fn push_versions(&self, repo: &Repository, versions: &[(PathBuf, FileVersion)]) -> CratisResult<UploadReport> {
+        let hashes = versions.iter().filter_map(|(_, version)| version.hash.clone()).collect();
+        let mut report = self.upload_files(repo, hashes)?;
+
+        let request = CommitRequest {
+            set: repo.index.name().to_string(),
+            versions: versions
+                .iter()
+                .map(|(path, version)| VersionEntry {
+                    path: remote_path(repo, path).to_string_lossy().into_owned(),
+                    version: remote_version(repo, version),
+                })
+                .collect(),
+            snapshot: None,
+        };
+        report.versions_recorded = self.commit(repo, &request)?.versions;
+
+        Ok(report)
+    }
+
+    /// Uploads a snapshot, every snapshot its incremental chain depends on and all of their
+    /// files, then records the snapshot on the server.
+    ///
+    /// The chain is only followed until a manifest the server already stores, since a manifest
+    /// is never uploaded before its files and parents.
+    ///
+    /// # Errors
+    /// * `CratisError::ConnectionIssue` or `CratisError::Timeout` if the server cannot be reached
+    /// * `CratisError::AuthFailure` if the server rejects the auth token
+    /// * Any error raised while reading the local repository
+    pub fn push_snapshot(&self, repo: &Repository, record: &SnapshotRecord) -> CratisResult<UploadReport> {
+        let mut manifests = Vec::new();
+        let mut files = BTreeSet::new();
+
+        let mut next = Some(record.id.clone());
+        while let Some(id) = next {
+            if self.missing_objects(repo, std::slice::from_ref(&id))?.is_empty() {
+                break;
+            }
+
+            let snapshot = load_snapshot(repo, &id)?;
+            files.extend(snapshot.entries.into_iter().map(|entry| entry.hash));
+            next = snapshot.parent;
+            manifests.push(id);
+        }
+
+        let mut report = self.upload_files(repo, files.into_iter().collect())?;
+        // Oldest first, so a manifest is never stored before its parent.
+        manifests.reverse();
+        report.merge(self.upload_objects(repo, manifests)?);
+
+        self.commit(repo, &CommitRequest { set: repo.index.name().to_string(), versions: Vec::new(), snapshot: Some(record.clone()) })?;
+        Ok(report)
+    }
+
+    /// Uploads the recipes and chunks of the given files that the server does not have yet.
+    ///
+    /// # Errors
+    /// Returns an error if the server cannot be reached or rejects a request, or if a file or
+    /// chunk cannot be read from the local repository.
+    pub fn upload_files(&self, repo: &Repository, hashes: Vec<String>) -> CratisResult<UploadReport> {
+        let missing = self.missing_files(repo, &dedup(hashes))?;
+
+        let mut chunks = Vec::new();
+        for hash in &missing {
+            chunks.extend(repo.store.file_recipe(hash)?.chunks.into_iter().map(|chunk| chunk.hash));
+        }
+
+        let mut report = self.upload_objects(repo, dedup(chunks))?;
+        for hash in missing {
+            let data = repo.store.get_encoded_file(&hash)?;
+            self.put(repo, &format!("/files/{hash}"), &data)?;
+
+            report.files_uploaded += 1;
+            report.bytes_uploaded += data.len() as u64;
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the digests of chunks and manifests the server does not store.
+    ///
+    /// # Errors
+    /// Returns an error if the server cannot be reached or rejects the request.
+    pub fn missing_objects(&self, repo: &Repository, hashes: &[String]) -> CratisResult<Vec<String>> {
+        self.missing(repo, "/objects/missing", hashes)
+    }
+
+    /// Returns the digests of files whose recipe the server does not store.
+    ///
+    /// # Errors
+    /// Returns an error if the server cannot be reached or rejects the request.
+    pub fn missing_files(&self, repo: &Repository, hashes: &[String]) -> CratisResult<Vec<String>> {
+        self.missing(repo, "/files/missing", hashes)
+    }
+
+    /// Records file revisions and a snapshot on the server.
+    ///
+    /// # Errors
+    /// Returns `CratisError::BackupFailure` if the server is missing an object the commit refers
+    /// to, or any error raised while talking to the server.
+    pub fn commit(&self, repo: &Repository, request: &CommitRequest) -> CratisResult<CommitResponse> {
+        self.post_json(repo, "/commit", request)
+    }
+
+    /// Lists the snapshots of a backup set recorded on the server, oldest first.
+    ///
+    /// # Errors
+    /// Returns an error if the server cannot be reached or rejects the request.
+    pub fn snapshots(&self, set: &str) -> CratisResult<Vec<SnapshotRecord>> {
+        self.retry.retry(|| {
+            let response = self
+                .agent
+                .get(self.url("/snapshots"))
+                .query("set", set)
+                .header("Authorization", &self.authorization)
+                .header(CLIENT_HEADER, &self.client_id)
+                .call()
+                .map_err(transport_error)?;
+
+            check_status(response)?.body_mut().read_json().map_err(transport_error)
+        })
+    }
+
+    fn upload_objects(&self, repo: &Repository, hashes: Vec<String>) -> CratisResult<UploadReport> {
+        let mut report = UploadReport::default();
+
+        for hash in self.missing_objects(repo, &hashes)? {
+            let data = repo.store.get_encoded(&hash)?;
+            self.put(repo, &format!("/objects/{hash}"), &data)?;
+
+            report.objects_uploaded += 1;
+            report.bytes_uploaded += data.len() as u64;
+        }
+
+        Ok(report)
+    }
+
+    fn missing(&self, repo: &Repository, endpoint: &str, hashes: &[String]) -> CratisResult<Vec<String>> {
+        let mut missing = Vec::new();
+
+        for batch in hashes.chunks(MISSING_BATCH) {
+            let response: MissingResponse = self.post_json(repo, endpoint, &MissingRequest { hashes: batch.to_vec() })?;
+            missing.extend(response.missing);
+        }
+
+        Ok(missing)
+    }
+
+    fn put(&self, repo: &Repository, endpoint: &str, data: &[u8]) -> CratisResult<()> {
+        self.retry.retry(|| {
+            let response = self
+                .agent
+                .put(self.url(endpoint))
+                .header("Authorization", &self.authorization)
+                .header(CLIENT_HEADER, &self.client_id)
+                .header(ENCRYPTED_HEADER, encrypted(repo))
+                .header("Content-Type", "application/octet-stream")
+                .send(data)
+                .map_err(transport_error)?;
+
+            check_status(response)?;
+            Ok(())
+        })
+    }
+
+    fn post_json<T: Serialize, R: DeserializeOwned>(&self, repo: &Repository, endpoint: &str, body: &T) -> CratisResult<R> {
+        self.retry.retry(|| {
+            let response = self
+                .agent
+                .post(self.url(endpoint))
+                .header("Authorization", &self.authorization)
+                .header(CLIENT_HEADER, &self.client_id)
+                .header(ENCRYPTED_HEADER, encrypted(repo))
+                .send_json(body)
+                .map_err(transport_error)?;
+
+            check_status(response)?.body_mut().read_json().map_err(transport_error)
+        })
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}{}", self.base_url, endpoint)
+    }
+}
+
+/// Returns the value of the `ENCRYPTED_HEADER` for requests about the objects of `repo`.
+fn encrypted(repo: &Repository) -> &'static str {
+    if repo.store.is_encrypted() { "true" } else { "false" }
+}
+
+/// Returns the form of `path` the server records.
+///
+/// Paths of an unencrypted repository are sent as they are. Paths of an encrypted repository
+/// are replaced by a keyed digest, so the server can tell the revisions of different files
+/// apart without learning their names. The digest is domain separated from object digests, so
+/// it never equals the address of a stored object.
+pub fn remote_path(repo: &Repository, path: &Path) -> PathBuf {
+    if !repo.store.is_encrypted() {
+        return path.to_path_buf();
+    }
+
+    let data = [PATH_DOMAIN, path.as_os_str().as_encoded_bytes()].concat();
+    PathBuf::from(repo.store.hash_bytes(&data))
+}
+
+/// Returns `version` with the paths it refers to mapped by `remote_path`.
+fn remote_version(repo: &Repository, version: &FileVersion) -> FileVersion {
+    let action = match &version.action {
+        EventAction::Rename { from, to } => EventAction::Rename { from: remote_path(repo, from), to: remote_path(repo, to) },
+        action => action.clone(),
+    };
+
+    FileVersion {
+        action,
+        moved_to: version.moved_to.as_deref().map(|path| remote_path(repo, path)),
+        ..version.clone()
+    }
+}
+
+/// Maps a non-success status to the matching `CratisError`.
+fn check_status(response: Response<Body>) -> CratisResult<Response<Body>> {
+    match response.status().as_u16() {
+        200..=299 => Ok(response),
+        401 | 403 => Err(CratisError::AuthFailure("The server rejected the auth token")),
+        409 => Err(CratisError::BackupFailure("The server is missing objects referenced by the commit")),
+        408 | 429 | 500.. => Err(CratisError::ConnectionIssue("The server is unavailable or failed to handle the request")),
+        _ => Err(CratisError::BackupFailure("The server rejected the request")),
+    }
+}
+
+/// Maps a transport-level failure to the matching `CratisError`.
+fn transport_error(error: ureq::Error) -> CratisError {
+    match error {
+        ureq::Error::Timeout(_) => CratisError::Timeout,
+        ureq::Error::Json(e) => CratisError::SerializationError(e),
+        ureq::Error::BadUri(uri) => CratisError::ConfigError(format!("Invalid server address: {uri}")),
+        ureq::Error::HostNotFound | ureq::Error::ConnectionFailed => CratisError::ConnectionIssue("Cannot connect to the server"),
+        _ => CratisError::ConnectionIssue("Request to the server failed"),
+    }
+}
+
+fn dedup(hashes: Vec<String>) -> Vec<String> {
+    hashes.into_iter().collect::<BTreeSet<_>>().into_iter().collect()
+}
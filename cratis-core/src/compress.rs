@@ -0,0 +1,220 @@
+use crate::error::{CratisError, CratisResult};
+
+/// Default zstd level used when `advanced.compression_level` is not configured.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Entropy (in bits per byte) above which data is considered already compressed.
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Number of bytes sampled when estimating the entropy of a buffer.
+const ENTROPY_SAMPLE_SIZE: usize = 8 * 1024;
+
+const TAG_RAW: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+
+/// Magic byte signatures of formats that are already compressed, as `(offset, signature)`.
+const COMPRESSED_SIGNATURES: &[(usize, &[u8])] = &[
+    (0, b"\xFF\xD8\xFF"),                 // jpeg
+    (0, b"\x89PNG\r\n\x1A\n"),            // png
+    (0, b"GIF8"),                         // gif
+    (0, b"PK\x03\x04"),                   // zip, docx, xlsx, jar, apk, ...
+    (0, b"\x1F\x8B"),                     // gzip
+    (0, b"\x28\xB5\x2F\xFD"),             // zstd
+    (0, b"\xFD7zXZ\x00"),                 // xz
+    (0, b"BZh"),                          // bzip2
+    (0, b"7z\xBC\xAF\x27\x1C"),           // 7z
+    (0, b"Rar!\x1A\x07"),                 // rar
+    (0, b"\x04\x22\x4D\x18"),             // lz4
+    (0, b"OggS"),                         // ogg, opus
+    (0, b"fLaC"),                         // flac
+    (0, b"ID3"),                          // mp3
+    (0, b"\x1A\x45\xDF\xA3"),             // mkv, webm
+    (4, b"ftyp"),                         // mp4, mov, heic, m4a
+    (8, b"WEBP"),                         // webp
+];
+
+/// Compression settings applied to objects written into the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    level: Option<i32>,
+}
+
+impl Compression {
+    /// Creates compression settings from a configured zstd level.
+    ///
+    /// A level of `0` disables compression entirely.
+    pub fn new(level: i32) -> Self {
+        Self { level: (level != 0).then_some(level) }
+    }
+
+    /// Settings that store every object uncompressed.
+    pub fn disabled() -> Self {
+        Self { level: None }
+    }
+
+    /// Encodes a buffer for storage.
+    ///
+    /// The result starts with a one byte tag describing the encoding. Data is stored raw if
+    /// compression is disabled, if it looks already compressed (see `is_compressible`) or if
+    /// compressing it would not save any space.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if zstd fails to compress the data.
+    pub fn encode(&self, data: &[u8]) -> CratisResult<Vec<u8>> {
+        if let Some(level) = self.level
+            && is_compressible(data)
+        {
+            let compressed = zstd::bulk::compress(data, level)?;
+            if compressed.len() < data.len() {
+                return Ok(tagged(TAG_ZSTD, &compressed));
+            }
+        }
+
+        Ok(tagged(TAG_RAW, data))
+    }
+
+    /// Encodes a buffer for storage without attempting to compress it.
+    pub fn encode_raw(&self, data: &[u8]) -> Vec<u8> {
+        tagged(TAG_RAW, data)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new(DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+/// Decodes a buffer previously produced by `Compression::encode`.
+///
+/// # Errors
+/// * `CratisError::BackupFailure` if the buffer has an unknown or missing tag
+/// * `CratisError::IoError` if zstd fails to decompress the data
+pub fn decode(data: &[u8]) -> CratisResult<Vec<u8>> {
+    match data.split_first() {
+        Some((&TAG_RAW, rest)) => Ok(rest.to_vec()),
+        Some((&TAG_ZSTD, rest)) => Ok(zstd::stream::decode_all(rest)?),
+        _ => Err(CratisError::BackupFailure("Stored object has an unknown encoding")),
+    }
+}
+
+/// Checks whether a buffer starts with the signature of an already compressed format.
+///
+/// # Examples
+/// ```ignore
+/// assert!(has_compressed_signature(b"\xFF\xD8\xFF\xE0\x00\x10JFIF"));
+/// assert!(!has_compressed_signature(b"fn main() {}"));
+/// ```
+pub fn has_compressed_signature(data: &[u8]) -> bool {
+    COMPRESSED_SIGNATURES
+        .iter()
+        .any(|(offset, signature)| data.get(*offset..*offset + signature.len()) == Some(*signature))
+}
+
+/// Estimates whether compressing a buffer is worthwhile.
+///
+/// Returns `false` for data starting with a known compressed format signature and for data
+/// whose sampled Shannon entropy is close to 8 bits per byte, which is typical for compressed
+/// or encrypted content without a recognizable header (e.g. chunks from the middle of a video).
+pub fn is_compressible(data: &[u8]) -> bool {
+    !has_compressed_signature(data) && sample_entropy(data) < ENTROPY_THRESHOLD
+}
+
+/// Computes the Shannon entropy in bits per byte of the first `ENTROPY_SAMPLE_SIZE` bytes.
+fn sample_entropy(data: &[u8]) -> f64 {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE_SIZE)];
+    if sample.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+
+    let len = sample.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn tagged(tag: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(tag);
+    out.extend_from_slice(data);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random bytes with close to 8 bits of entropy per byte.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn text() -> Vec<u8> {
+        "fn main() { println!(\"hello world\"); }\n".repeat(200).into_bytes()
+    }
+
+    #[test]
+    fn text_is_compressed_and_round_trips() {
+        let data = text();
+        let encoded = Compression::default().encode(&data).unwrap();
+
+        assert_eq!(encoded[0], TAG_ZSTD);
+        assert!(encoded.len() < data.len());
+        assert_eq!(decode(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn high_entropy_data_is_stored_raw() {
+        let data = noise(64 * 1024);
+        assert!(!is_compressible(&data));
+
+        let encoded = Compression::default().encode(&data).unwrap();
+        assert_eq!(encoded, tagged(TAG_RAW, &data));
+        assert_eq!(decode(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn compressed_formats_are_recognized_by_signature() {
+        assert!(has_compressed_signature(b"\xFF\xD8\xFF\xE0\x00\x10JFIF"));
+        assert!(has_compressed_signature(b"\x00\x00\x00\x18ftypmp42"));
+        assert!(has_compressed_signature(b"RIFF\x00\x00\x00\x00WEBPVP8 "));
+        assert!(!has_compressed_signature(b"fn main() {}"));
+        assert!(!has_compressed_signature(b""));
+
+        let mut png = b"\x89PNG\r\n\x1A\n".to_vec();
+        png.extend(text());
+        assert!(!is_compressible(&png));
+        assert_eq!(Compression::default().encode(&png).unwrap()[0], TAG_RAW);
+    }
+
+    #[test]
+    fn disabled_compression_stores_raw() {
+        let data = text();
+        assert_eq!(Compression::new(0), Compression::disabled());
+        assert_eq!(Compression::disabled().encode(&data).unwrap(), tagged(TAG_RAW, &data));
+        assert_eq!(decode(&Compression::default().encode_raw(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_and_unknown_encodings() {
+        assert_eq!(decode(&Compression::default().encode(b"").unwrap()).unwrap(), b"");
+        assert!(matches!(decode(b""), Err(CratisError::BackupFailure(_))));
+        assert!(matches!(decode(b"\x07data"), Err(CratisError::BackupFailure(_))));
+    }
+}
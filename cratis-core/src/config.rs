@@ -1,14 +1,52 @@
 #![allow(dead_code)]
-use serde::Deserialize;
-use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use glob::Pattern;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::compress::Compression;
+use crate::error::{CratisError, CratisResult};
+use crate::exclude;
+use crate::retry::{RetryPolicy, DEFAULT_RETRY_ATTEMPTS, DEFAULT_RETRY_DELAY};
+use crate::utils::ensure_path_exists;
 
+/// Name of the backup set configured in the `backup` section.
+pub const DEFAULT_BACKUP_SET: &str = "default";
+
+/// The application configuration.
+///
+/// Backups are organized in sets, each with its own directories, schedule, retention and size
+/// limits and its own history in the index. The `backup` section is the default set, further
+/// sets are configured by name in `backup_sets`:
+///
+/// ```yaml
+/// backup_sets:
+///   code:
+///     mode: incremental
+///     watch_directories: ["/home/user/src"]
+///     exclude: ["**/target/**"]
+///   photos:
+///     mode: full
+///     watch_directories: ["/home/user/Pictures"]
+///     realtime: false
+///     interval_seconds: 86400
+///     retention: { keep_snapshots: 14 }
+///     max_file_size_mb: 4096
+/// ```
 #[derive(Debug, Deserialize)]
 pub struct CratisConfig {
     pub client: ClientConfig,
-    pub backup: BackupConfig,
+    /// The default backup set, see `backup_sets`.
+    pub backup: Option<BackupConfig>,
+    /// Additional backup sets by name, each with its own settings and history.
+    pub backup_sets: Option<BTreeMap<String, BackupConfig>>,
     pub server: ServerConfig,
     pub advanced: Option<AdvancedConfig>,
+    pub storage: Option<StorageConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -17,15 +55,63 @@ pub struct ClientConfig {
     pub name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct BackupConfig {
     pub mode: BackupMode,
     pub watch_directories: Vec<String>,
+    /// Gitignore-style patterns relative to the watch directories, see `ExcludeRules`.
     pub exclude: Option<Vec<String>>,
+    /// Honor `.gitignore` files next to `.cratisignore` files.
+    pub gitignore: Option<bool>,
+    /// Rules for temporary files, which are never backed up.
+    pub temp_files: Option<TempFileConfig>,
     pub interval_seconds: Option<u64>,
+    pub realtime: Option<bool>,
+    /// In incremental mode, the most snapshots a chain may hold before a new full snapshot is
+    /// taken, by default `snapshot::DEFAULT_MAX_CHAIN_LENGTH`.
+    pub max_chain_length: Option<usize>,
+    pub retention: Option<RetentionConfig>,
+    /// Overrides `advanced.max_file_size_mb` for this set.
+    pub max_file_size_mb: Option<u64>,
+    /// Checked before the rules of `advanced.large_files`.
+    pub large_files: Option<Vec<SizeRule>>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Which temporary files a backup set skips.
+///
+/// Temporary files are matched by named rule sets of gitignore-style patterns. The built-in
+/// sets are `editor_swap`, `office_lock`, `temporary` and `build_artifacts`, all but
+/// `build_artifacts` are used by default. Files matching an `include` pattern are backed up
+/// even if a rule set matches them:
+///
+/// ```yaml
+/// temp_files:
+///   rule_sets: [editor_swap, office_lock, build_artifacts, downloads]
+///   rules:
+///     build_artifacts: ["target/", "dist/"]
+///     downloads: ["*.crdownload", "*.part"]
+///   include: ["*.tmp.keep"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct TempFileConfig {
+    /// The rule sets in use, by default `editor_swap`, `office_lock` and `temporary`.
+    pub rule_sets: Option<Vec<String>>,
+    /// Patterns of additional rule sets by name, or replacing those of a built-in set.
+    pub rules: Option<BTreeMap<String, Vec<String>>>,
+    /// Patterns of files that are backed up even if a rule set matches them.
+    pub include: Option<Vec<String>>,
+}
+
+/// How long `cratis prune` keeps the history of a backup set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetentionConfig {
+    /// Number of most recent snapshots to keep.
+    pub keep_snapshots: Option<usize>,
+    /// Keep every file version from the last N days.
+    pub keep_days: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BackupMode {
     Full,
@@ -35,7 +121,12 @@ pub enum BackupMode {
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
     pub address: String,
-    pub auth_token: String
+    pub auth_token: String,
+    pub listen: Option<String>,
+    pub enabled: Option<bool>,
+    /// Directory of the repository the API server stores uploads in. Has to be apart from
+    /// `storage.path`, the local repository of the client.
+    pub storage_path: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,42 +134,659 @@ pub struct AdvancedConfig {
     pub max_file_size_mb: Option<u64>,
     pub retry_attempts: Option<u32>,
     pub retry_delay_seconds: Option<u64>,
-    pub enable_notifications: Option<bool>
+    pub enable_notifications: Option<bool>,
+    pub compression_level: Option<i32>,
+    pub large_files: Option<Vec<SizeRule>>,
+}
+
+/// What to do with files matching `pattern` that exceed `max_file_size_mb`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SizeRule {
+    pub pattern: String,
+    pub policy: SizePolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SizePolicy {
+    /// Skip the file without a trace.
+    Skip,
+    /// Skip the file and record it in the index, so it shows up in `cratis status`.
+    Warn,
+    /// Back the file up regardless of its size.
+    Allow,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StorageConfig {
+    pub path: Option<String>,
+    pub passphrase_file: Option<String>,
+}
+
+impl CratisConfig {
+    /// Parses and validates a configuration from YAML source.
+    ///
+    /// # Errors
+    /// * `CratisError::ConfigParseError` if the source is not a valid configuration
+    /// * `CratisError::InvalidConfig` listing every problem found by `validate`
+    pub fn from_yaml(source: &str) -> CratisResult<Self> {
+        let config: Self = serde_yaml::from_str(source)?;
+        config.validate(source)?;
+        Ok(config)
+    }
+
+    /// Checks the configuration for problems serde cannot catch.
+    ///
+    /// * `client.id` must only contain letters, digits, `-` and `_`, since the server keeps the
+    ///   history of every client in its own namespace
+    /// * At least one backup set must be configured, and set names must be usable as index
+    ///   namespaces
+    /// * Every `watch_directories` entry of every backup set must be an existing directory
+    /// * `max_chain_length` must be at least 1
+    /// * Every `exclude`, `temp_files.rules` and `temp_files.include` pattern of every backup set
+    ///   must be a valid gitignore pattern, and every `large_files` and `advanced.large_files`
+    ///   pattern a valid glob
+    /// * Every rule set in `temp_files.rule_sets` must be built in or defined in
+    ///   `temp_files.rules`
+    /// * `server.auth_token` must not be empty
+    /// * `server.address` must be an `http` or `https` URL
+    /// * `server.storage_path` must not be empty or overlap the local repository
+    ///
+    /// All problems are collected, so they can be fixed in one go, and reported in the order
+    /// they appear in the file.
+    ///
+    /// # Arguments
+    /// * `source` - The YAML source the configuration was parsed from, used to locate problems
+    ///
+    /// # Errors
+    /// Returns `CratisError::InvalidConfig` listing every problem with its line and column.
+    pub fn validate(&self, source: &str) -> CratisResult<()> {
+        let locator = SourceLocator::new(source);
+        let mut problems = Vec::new();
+        let mut report = |field: String, message: String, position: Option<(usize, usize)>| {
+            problems.push(ConfigProblem { field, message, line: position.map(|p| p.0), column: position.map(|p| p.1) });
+        };
+
+        if !is_valid_name(&self.client.id) {
+            let position = locator.locate(&["client", "id"], Some(&self.client.id));
+            report("client.id".to_string(), "Client ids may only contain letters, digits, '-' and '_'".to_string(), position);
+        }
+
+        if self.backup.is_none() && self.backup_sets.as_ref().is_none_or(BTreeMap::is_empty) {
+            report("backup".to_string(), "No backup set configured, add a `backup` or `backup_sets` section".to_string(), None);
+        }
+
+        for name in self.backup_sets.iter().flat_map(BTreeMap::keys) {
+            let position = locator.locate(&["backup_sets"], Some(name));
+            if !is_valid_name(name) {
+                report(format!("backup_sets.{name}"), "Set names may only contain letters, digits, '-' and '_'".to_string(), position);
+            } else if name == DEFAULT_BACKUP_SET && self.backup.is_some() {
+                report(format!("backup_sets.{name}"), "Conflicts with the `backup` section, which is the default set".to_string(), position);
+            }
+        }
+
+        let sets = self.backup.iter().map(|backup| (vec!["backup"], "backup".to_string(), backup)).chain(
+            self.backup_sets
+                .iter()
+                .flatten()
+                .map(|(name, backup)| (vec!["backup_sets", name.as_str()], format!("backup_sets.{name}"), backup)),
+        );
+
+        for (keys, field, backup) in sets {
+            let key = |last: &'static str| [keys.as_slice(), &[last]].concat();
+
+            for (i, dir) in backup.watch_directories.iter().enumerate() {
+                if let Err(e) = ensure_path_exists(Path::new(dir)) {
+                    let position = locator.locate(&key("watch_directories"), Some(dir));
+                    report(format!("{field}.watch_directories[{i}]"), e.to_string(), position);
+                }
+            }
+
+            if backup.max_chain_length == Some(0) {
+                let position = locator.locate(&key("max_chain_length"), None);
+                report(format!("{field}.max_chain_length"), "Must be at least 1".to_string(), position);
+            }
+
+            for (i, pattern) in backup.exclude.iter().flatten().enumerate() {
+                if let Err(e) = exclude::check_pattern(pattern) {
+                    let position = locator.locate(&key("exclude"), Some(pattern));
+                    report(format!("{field}.exclude[{i}]"), format!("Invalid exclusion pattern: {e}"), position);
+                }
+            }
+
+            if let Some(temp_files) = &backup.temp_files {
+                let temp_key = |last: &'static str| [keys.as_slice(), &["temp_files", last]].concat();
+
+                for (i, name) in temp_files.rule_sets.iter().flatten().enumerate() {
+                    if !exclude::is_builtin_rule_set(name) && !temp_files.rules.as_ref().is_some_and(|rules| rules.contains_key(name)) {
+                        let position = locator.locate(&temp_key("rule_sets"), Some(name));
+                        report(format!("{field}.temp_files.rule_sets[{i}]"), format!("Unknown rule set '{name}', define it in `temp_files.rules`"), position);
+                    }
+                }
+
+                for (name, patterns) in temp_files.rules.iter().flatten() {
+                    for (i, pattern) in patterns.iter().enumerate() {
+                        if let Err(e) = exclude::check_pattern(pattern) {
+                            let position = locator.locate(&[temp_key("rules").as_slice(), &[name.as_str()]].concat(), Some(pattern));
+                            report(format!("{field}.temp_files.rules.{name}[{i}]"), format!("Invalid pattern: {e}"), position);
+                        }
+                    }
+                }
+
+                for (i, pattern) in temp_files.include.iter().flatten().enumerate() {
+                    if let Err(e) = exclude::check_pattern(pattern) {
+                        let position = locator.locate(&temp_key("include"), Some(pattern));
+                        report(format!("{field}.temp_files.include[{i}]"), format!("Invalid pattern: {e}"), position);
+                    }
+                }
+            }
+
+            for (i, rule) in backup.large_files.iter().flatten().enumerate() {
+                if let Err(e) = Pattern::new(&rule.pattern) {
+                    let position = locator.locate(&key("large_files"), Some(&rule.pattern));
+                    report(format!("{field}.large_files[{i}].pattern"), format!("Invalid glob pattern: {e}"), position);
+                }
+            }
+        }
+
+        let large_files = self.advanced.as_ref().and_then(|advanced| advanced.large_files.as_ref());
+        for (i, rule) in large_files.into_iter().flatten().enumerate() {
+            if let Err(e) = Pattern::new(&rule.pattern) {
+                let position = locator.locate(&["advanced", "large_files"], Some(&rule.pattern));
+                report(format!("advanced.large_files[{i}].pattern"), format!("Invalid glob pattern: {e}"), position);
+            }
+        }
+
+        if self.server.auth_token.trim().is_empty() {
+            let position = locator.locate(&["server", "auth_token"], None);
+            report("server.auth_token".to_string(), "Must not be empty".to_string(), position);
+        }
+
+        let address = match url::Url::parse(&self.server.address) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https") => Err(format!("Unsupported scheme '{}', expected http or https", url.scheme())),
+            Ok(url) if url.host().is_none() => Err("Missing host".to_string()),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Not a valid URL: {e}")),
+        };
+        if let Err(message) = address {
+            let position = locator.locate(&["server", "address"], Some(&self.server.address));
+            report("server.address".to_string(), message, position);
+        }
+
+        if let Some(path) = &self.server.storage_path {
+            let client = self.storage_path();
+            let message = if path.trim().is_empty() {
+                Some("Must not be empty".to_string())
+            } else if Path::new(path).starts_with(&client) || client.starts_with(path) {
+                Some(format!("Overlaps the local repository at {}, the server needs a directory of its own", client.display()))
+            } else {
+                None
+            };
+
+            if let Some(message) = message {
+                let position = locator.locate(&["server", "storage_path"], Some(path));
+                report("server.storage_path".to_string(), message, position);
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        problems.sort_by_key(|problem| problem.line.unwrap_or(usize::MAX));
+        Err(CratisError::InvalidConfig(problems))
+    }
+
+    /// Returns every configured backup set by name, the default set first.
+    ///
+    /// The `backup` section is the set named `DEFAULT_BACKUP_SET`, the sets of `backup_sets`
+    /// follow in alphabetical order.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for (name, backup) in config.backup_sets() {
+    ///     println!("{}: {}", name, backup.watch_directories.join(", "));
+    /// }
+    /// ```
+    pub fn backup_sets(&self) -> Vec<(&str, &BackupConfig)> {
+        self.backup
+            .iter()
+            .map(|backup| (DEFAULT_BACKUP_SET, backup))
+            .chain(self.backup_sets.iter().flatten().map(|(name, backup)| (name.as_str(), backup)))
+            .collect()
+    }
+
+    /// Returns the backup set with the given name.
+    ///
+    /// # Errors
+    /// Returns `CratisError::ConfigError` listing the configured sets if there is no set with
+    /// that name.
+    pub fn backup_set(&self, name: &str) -> CratisResult<&BackupConfig> {
+        let sets = self.backup_sets();
+
+        match sets.iter().find(|(set, _)| *set == name) {
+            Some((_, backup)) => Ok(backup),
+            None => {
+                let names: Vec<_> = sets.iter().map(|(set, _)| *set).collect();
+                Err(CratisError::ConfigError(format!("Unknown backup set '{}', configured sets: {}", name, names.join(", "))))
+            }
+        }
+    }
+
+    /// Returns the directory of the local backup repository.
+    ///
+    /// Uses `storage.path` if it is set, otherwise falls back to `$XDG_DATA_HOME/cratis`
+    /// and finally `$HOME/.local/share/cratis`.
+    pub fn storage_path(&self) -> PathBuf {
+        if let Some(path) = self.storage.as_ref().and_then(|storage| storage.path.as_ref()) {
+            return PathBuf::from(path);
+        }
+
+        if let Some(data_home) = env::var_os("XDG_DATA_HOME").filter(|v| !v.is_empty()) {
+            return PathBuf::from(data_home).join("cratis");
+        }
+
+        let home = env::var_os("HOME").unwrap_or_default();
+        PathBuf::from(home).join(".local/share/cratis")
+    }
+
+    /// Returns the passphrase of an encrypted repository, if one is configured.
+    ///
+    /// The `CRATIS_PASSPHRASE` environment variable takes precedence over `storage.passphrase_file`.
+    /// Trailing newlines are stripped from the passphrase file.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the passphrase file cannot be read.
+    pub fn passphrase(&self) -> CratisResult<Option<String>> {
+        if let Ok(passphrase) = env::var("CRATIS_PASSPHRASE") {
+            return Ok(Some(passphrase));
+        }
+
+        match self.storage.as_ref().and_then(|storage| storage.passphrase_file.as_ref()) {
+            Some(path) => Ok(Some(fs::read_to_string(path)?.trim_end_matches(['\r', '\n']).to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the compression applied to stored objects.
+    ///
+    /// Uses `advanced.compression_level` if it is set (`0` disables compression), otherwise
+    /// the default zstd level.
+    pub fn compression(&self) -> Compression {
+        self.advanced
+            .as_ref()
+            .and_then(|advanced| advanced.compression_level)
+            .map(Compression::new)
+            .unwrap_or_default()
+    }
+
+    /// Returns the retry policy for transient failures of uploads and file reads.
+    ///
+    /// Uses `advanced.retry_attempts` and `advanced.retry_delay_seconds` if they are set,
+    /// otherwise 3 retries starting at 1 second.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let advanced = self.advanced.as_ref();
+        let attempts = advanced.and_then(|advanced| advanced.retry_attempts).unwrap_or(DEFAULT_RETRY_ATTEMPTS);
+        let delay = advanced
+            .and_then(|advanced| advanced.retry_delay_seconds)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RETRY_DELAY);
+
+        RetryPolicy::new(attempts, delay)
+    }
 }
 
-static CONFIG: OnceCell<CratisConfig> = OnceCell::new();
+impl ServerConfig {
+    /// Returns `true` if backups should be pushed to the server.
+    ///
+    /// Defaults to `false`, so a purely local setup never tries to reach a server.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+
+    /// Returns the socket address the API server binds to.
+    ///
+    /// Uses `server.listen` if it is set, otherwise the host and port of `server.address`,
+    /// e.g. `127.0.0.1:8080` for `http://127.0.0.1:8080/`.
+    pub fn listen_address(&self) -> String {
+        if let Some(listen) = &self.listen {
+            return listen.clone();
+        }
+
+        let address = self.address.split_once("://").map(|(_, rest)| rest).unwrap_or(&self.address);
+        address.split('/').next().unwrap_or_default().to_string()
+    }
+
+    /// Returns the directory of the repository the API server stores uploads in.
+    ///
+    /// # Errors
+    /// Returns `CratisError::ConfigError` if `server.storage_path` is not set. There is no
+    /// default, as falling back to the local repository would mix the server's index with the
+    /// client's.
+    pub fn storage_path(&self) -> CratisResult<PathBuf> {
+        self.storage_path
+            .as_ref()
+            .map(PathBuf::from)
+            .ok_or_else(|| CratisError::ConfigError("`server.storage_path` is not set, the API server needs a repository of its own".to_string()))
+    }
+}
 
-/// Loads the application configuration from a YAML file and initializes the global configuration.
+/// Environment variable naming the configuration file.
+pub const CONFIG_ENV: &str = "CRATIS_CONFIG";
+/// File name of the configuration inside the configuration directories.
+pub const CONFIG_FILE_NAME: &str = "cratis.yml";
+/// System-wide configuration file, used if no user configuration exists.
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/cratis/cratis.yml";
+
+/// Locates the configuration file shared by the watcher, the CLI and the API server.
 ///
-/// Reads the configuration file at the specified path, parses its contents as YAML into a `CratisConfig` instance, and stores it in the global configuration container. Panics if the file cannot be read, the YAML is invalid, or the configuration has already been initialized.
+/// The first of the following wins:
+/// 1. `explicit`, the path passed with `--config`
+/// 2. The `CRATIS_CONFIG` environment variable
+/// 3. `$XDG_CONFIG_HOME/cratis/cratis.yml`, or `$HOME/.config/cratis/cratis.yml` if
+///    `XDG_CONFIG_HOME` is not set
+/// 4. `/etc/cratis/cratis.yml`
 ///
-/// # Examples
+/// An explicitly requested path is returned even if it does not exist, so the error reported
+/// when loading it names the path the user asked for. The discovered locations are only used
+/// if the file exists.
+///
+/// # Arguments
+/// * `explicit` - The path given on the command line, if any
+///
+/// # Errors
+/// Returns `CratisError::ConfigError` listing every searched location if no configuration
+/// file was found.
 ///
+/// # Examples
 /// ```ignore
-/// load_config("config.yaml");
-/// let config = get_config();
-/// assert_eq!(config.client.name, "example-client");
+/// let path = find_config(config_flag(env::args().skip(1)).as_deref())?;
+/// let config = load_config(&path)?;
 /// ```
-pub fn load_config(path: &str) {
-    let contents = fs::read_to_string(path).expect("Failed to read config file");
-    let parsed: CratisConfig = serde_yaml::from_str(&contents).expect("Invalid config format");
-    CONFIG.set(parsed).expect("Config already initialized");
+pub fn find_config(explicit: Option<&Path>) -> CratisResult<PathBuf> {
+    discover_config(explicit, |name| env::var_os(name))
+}
+
+/// Implements `find_config`, reading environment variables through `var`.
+fn discover_config<F>(explicit: Option<&Path>, var: F) -> CratisResult<PathBuf>
+where
+    F: Fn(&str) -> Option<OsString>,
+{
+    let var = |name| var(name).filter(|value| !value.is_empty());
+
+    if let Some(path) = explicit {
+        return Ok(path.to_path_buf());
+    }
+
+    if let Some(path) = var(CONFIG_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+
+    let mut candidates = Vec::new();
+    if let Some(config_home) = var("XDG_CONFIG_HOME") {
+        candidates.push(PathBuf::from(config_home).join("cratis").join(CONFIG_FILE_NAME));
+    } else if let Some(home) = var("HOME") {
+        candidates.push(PathBuf::from(home).join(".config/cratis").join(CONFIG_FILE_NAME));
+    }
+    candidates.push(PathBuf::from(SYSTEM_CONFIG_PATH));
+
+    if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+        return Ok(path.clone());
+    }
+
+    let searched: Vec<_> = candidates.iter().map(|path| path.display().to_string()).collect();
+    Err(CratisError::ConfigError(format!(
+        "No configuration file found. Pass --config, set {} or create one of: {}",
+        CONFIG_ENV,
+        searched.join(", ")
+    )))
+}
+
+/// Extracts the value of a `--config <path>`, `--config=<path>` or `-c <path>` flag.
+///
+/// Used by the binaries without a full argument parser. Other arguments are ignored.
+///
+/// # Arguments
+/// * `args` - The command line arguments, without the program name
+pub fn config_flag<I: IntoIterator<Item = String>>(args: I) -> Option<PathBuf> {
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+        if arg == "--config" || arg == "-c" {
+            return args.next().map(PathBuf::from);
+        }
+    }
+
+    None
 }
 
-/// Returns a reference to the globally loaded application configuration.
+/// Loads and validates the application configuration from a YAML file.
+///
+/// The file is parsed into a `CratisConfig` and checked with `CratisConfig::validate`, so a
+/// configuration returned by this function is ready to use.
 ///
-/// Panics if the configuration has not been initialized with `load_config`.
+/// # Arguments
+/// * `path` - The configuration file, usually located with `find_config`
+///
+/// # Errors
+/// * `CratisError::ConfigError` if the file cannot be read
+/// * `CratisError::ConfigParseError` if the file is not valid YAML or does not match the
+///   expected structure, including the line and column of the problem
+/// * `CratisError::InvalidConfig` listing every problem found by the validation
 ///
 /// # Examples
 ///
 /// ```ignore
-/// // Initialize configuration once at startup
-/// load_config("config.yaml");
-///
-/// // Access configuration anywhere after initialization
-/// let config = get_config();
-/// assert_eq!(config.client.name, "example-client");
+/// let config = load_config(find_config(None)?)?;
+/// println!("Backing up {} sets", config.backup_sets().len());
 /// ```
-pub fn get_config() -> &'static CratisConfig {
-    CONFIG.get().expect("Config not initialized")
-}
\ No newline at end of file
+pub fn load_config<P: AsRef<Path>>(path: P) -> CratisResult<CratisConfig> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .map_err(|e| CratisError::ConfigError(format!("Cannot read {}: {}", path.display(), e)))?;
+
+    CratisConfig::from_yaml(&contents)
+}
+
+/// Returns `true` if `name` only consists of letters, digits, `-` and `_`, as required for
+/// backup set names and client ids.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A problem found while validating the configuration.
+///
+/// `line` and `column` are 1-based and point at the offending value in the YAML source, if it
+/// could be located.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// The offending setting, e.g. `backup.exclude[2]`.
+    pub field: String,
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{} (line {}, column {}): {}", self.field, line, column, self.message),
+            _ => write!(f, "{}: {}", self.field, self.message),
+        }
+    }
+}
+
+/// Finds the position of settings in the YAML source, for error messages.
+///
+/// serde_yaml does not keep the position of parsed values, so keys are searched for in the
+/// order of their path and the value is searched for after the last key. This works for both
+/// block and flow style, and points at the first occurrence if a value is repeated.
+struct SourceLocator<'a> {
+    source: &'a str,
+}
+
+impl<'a> SourceLocator<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { source }
+    }
+
+    /// Returns the position of `value` below the given key path, or of the value of the last
+    /// key if `value` is `None`.
+    fn locate(&self, keys: &[&str], value: Option<&str>) -> Option<(usize, usize)> {
+        let mut offset = 0;
+        for key in keys {
+            offset = self.find_key(offset, key)?;
+        }
+
+        let position = match value {
+            Some(value) if !value.is_empty() => offset + self.source[offset..].find(value)?,
+            _ => offset + (self.source[offset..].len() - self.source[offset..].trim_start_matches([' ', '\t']).len()),
+        };
+
+        let before = &self.source[..position];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        Some((line, column))
+    }
+
+    /// Returns the offset right after the colon of the first `key:` at or after `offset`.
+    fn find_key(&self, offset: usize, key: &str) -> Option<usize> {
+        let mut from = offset;
+
+        while let Some(found) = self.source[from..].find(key) {
+            let start = from + found;
+            let end = start + key.len();
+            from = end;
+
+            let preceded = self.source[..start].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+            let rest = self.source[end..].trim_start_matches(['"', '\'']).trim_start_matches([' ', '\t']);
+            if !preceded && rest.starts_with(':') {
+                return Some(self.source.len() - rest.len() + 1);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    /// Runs `discover_config` with only the given environment variables set.
+    fn discover(explicit: Option<&Path>, vars: &[(&str, &Path)]) -> CratisResult<PathBuf> {
+        let vars: HashMap<String, OsString> = vars.iter().map(|(name, value)| (name.to_string(), value.as_os_str().to_owned())).collect();
+        discover_config(explicit, |name| vars.get(name).cloned())
+    }
+
+    /// Creates `cratis/cratis.yml` below `dir` and returns its path.
+    fn create_config(dir: &Path) -> PathBuf {
+        let path = dir.join("cratis").join(CONFIG_FILE_NAME);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+        path
+    }
+
+    #[test]
+    fn config_discovery_order() {
+        let dir = TempDir::new().unwrap();
+        let flag = dir.path().join("flag.yml");
+        let from_env = dir.path().join("env.yml");
+        let config_home = dir.path().join("config");
+        let home = dir.path().join("home");
+        let in_config_home = create_config(&config_home);
+        let in_home = create_config(&home.join(".config"));
+        let all = [(CONFIG_ENV, from_env.as_path()), ("XDG_CONFIG_HOME", &config_home), ("HOME", &home)];
+
+        assert_eq!(discover(Some(&flag), &all).unwrap(), flag, "the flag wins even if the file is missing");
+        assert_eq!(discover(None, &all).unwrap(), from_env);
+        assert_eq!(discover(None, &all[1..]).unwrap(), in_config_home);
+        assert_eq!(discover(None, &all[2..]).unwrap(), in_home);
+        assert_eq!(discover(None, &[(CONFIG_ENV, Path::new("")), ("HOME", &home)]).unwrap(), in_home);
+
+        fs::remove_file(&in_config_home).unwrap();
+        let fallback = discover(None, &all[1..]);
+        if !Path::new(SYSTEM_CONFIG_PATH).is_file() {
+            let Err(CratisError::ConfigError(message)) = fallback else { panic!("Expected no configuration, got {fallback:?}") };
+            assert!(message.contains(&in_config_home.display().to_string()) && message.contains(SYSTEM_CONFIG_PATH), "{message}");
+        }
+    }
+
+    #[test]
+    fn config_flag_forms() {
+        let args = |args: &[&str]| config_flag(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(args(&["--config", "a.yml"]), Some(PathBuf::from("a.yml")));
+        assert_eq!(args(&["--config=b.yml"]), Some(PathBuf::from("b.yml")));
+        assert_eq!(args(&["status", "-c", "c.yml", "--config", "d.yml"]), Some(PathBuf::from("c.yml")));
+        assert_eq!(args(&["status", "--config"]), None);
+        assert_eq!(args(&["status", "--verbose"]), None);
+    }
+
+    /// Builds a configuration watching `dir` and storing into `dir/repo`, with the `backup` and
+    /// `server` sections extended by the given lines, and validates it against its source.
+    fn validate(dir: &Path, client_id: &str, backup: &str, server: &str, rest: &str) -> CratisResult<CratisConfig> {
+        let dir = dir.display();
+        let source = format!(
+            "client:\n  id: {client_id}\n  name: Laptop\n\
+             backup:\n  mode: full\n  watch_directories: [\"{dir}\"]\n{backup}\
+             storage:\n  path: \"{dir}/repo\"\n\
+             server:\n  address: http://localhost:8080\n  auth_token: secret\n{server}{rest}"
+        );
+        CratisConfig::from_yaml(&source)
+    }
+
+    /// Returns `(field, message, line, column)` of every problem found.
+    fn problems(result: CratisResult<CratisConfig>) -> Vec<(String, String, Option<usize>, Option<usize>)> {
+        match result {
+            Err(CratisError::InvalidConfig(problems)) => problems.into_iter().map(|p| (p.field, p.message, p.line, p.column)).collect(),
+            other => panic!("Expected validation problems, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn valid_configurations_pass() {
+        let dir = TempDir::new().unwrap();
+        let backup = "  exclude: [\"*.tmp\"]\n  temp_files:\n    rule_sets: [temporary, scratch]\n    rules:\n      scratch: [\"*.scratch\"]\n";
+        let server = format!("  storage_path: \"{}/server\"\n", dir.path().display());
+        assert!(validate(dir.path(), "laptop-1", backup, &server, "").is_ok());
+    }
+
+    #[test]
+    fn problems_are_reported_with_their_position() {
+        let dir = TempDir::new().unwrap();
+        let backup = "  exclude: [\"*.tmp\", \"[z-a]\"]\n  temp_files:\n    rule_sets: [temporary, scratch]\n";
+        let rest = "backup_sets:\n  \"my set\":\n    mode: full\n    watch_directories: []\n";
+
+        assert_eq!(
+            problems(validate(dir.path(), "my laptop", backup, "", rest)),
+            vec![
+                ("client.id".to_string(), "Client ids may only contain letters, digits, '-' and '_'".to_string(), Some(2), Some(7)),
+                ("backup.exclude[1]".to_string(), "Invalid exclusion pattern: error parsing glob '[z-a]': invalid range; 'z' > 'a'".to_string(), Some(7), Some(23)),
+                ("backup.temp_files.rule_sets[1]".to_string(), "Unknown rule set 'scratch', define it in `temp_files.rules`".to_string(), Some(9), Some(28)),
+                ("backup_sets.my set".to_string(), "Set names may only contain letters, digits, '-' and '_'".to_string(), Some(16), Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_server_repository_must_not_overlap_the_local_one() {
+        let dir = TempDir::new().unwrap();
+        let repo = dir.path().join("repo");
+
+        for path in [repo.clone(), repo.join("server"), dir.path().to_path_buf()] {
+            let server = format!("  storage_path: \"{}\"\n", path.display());
+            let problems = problems(validate(dir.path(), "laptop", "", &server, ""));
+            let message = format!("Overlaps the local repository at {}, the server needs a directory of its own", repo.display());
+            assert_eq!(problems, vec![("server.storage_path".to_string(), message, Some(12), Some(18))], "{path:?}");
+        }
+
+        let problems = problems(validate(dir.path(), "laptop", "", "  storage_path: \"\"\n", ""));
+        assert_eq!(problems, vec![("server.storage_path".to_string(), "Must not be empty".to_string(), Some(12), Some(17))]);
+    }
+}
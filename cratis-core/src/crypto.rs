@@ -0,0 +1,325 @@
+use std::fs;
+use std::path::Path;
+use argon2::{Algorithm, Argon2, Params, Version};
+use blake3::Hasher;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use crate::error::{CratisError, CratisResult};
+
+const KEY_FILE_VERSION: u32 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// The secret keys of an encrypted repository.
+///
+/// * `encryption_key` encrypts every object with XChaCha20-Poly1305
+/// * `hash_key` keys BLAKE3, so object names don't reveal which plaintexts are equal to anyone
+///   without the key (e.g. the server)
+///
+/// Both keys are generated randomly once per repository and never change. The passphrase only
+/// protects the key file they are stored in, so it can be changed without touching any data.
+#[derive(Clone)]
+pub struct RepoKey {
+    encryption_key: [u8; KEY_LEN],
+    hash_key: [u8; KEY_LEN],
+}
+
+impl RepoKey {
+    /// Generates a fresh random repository key.
+    pub fn generate() -> Self {
+        let mut encryption_key = [0u8; KEY_LEN];
+        let mut hash_key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut encryption_key);
+        OsRng.fill_bytes(&mut hash_key);
+
+        Self { encryption_key, hash_key }
+    }
+
+    /// Returns a BLAKE3 hasher keyed with the repository hash key.
+    pub fn hasher(&self) -> Hasher {
+        Hasher::new_keyed(&self.hash_key)
+    }
+
+    /// Computes the keyed BLAKE3 hex digest of a buffer.
+    pub fn keyed_hash(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.hash_key, data).to_hex().to_string()
+    }
+
+    /// Encrypts a buffer, returning `nonce || ciphertext`.
+    ///
+    /// # Errors
+    /// Returns `CratisError::EncryptionError` if encryption fails.
+    pub fn encrypt(&self, plaintext: &[u8]) -> CratisResult<Vec<u8>> {
+        seal(&self.encryption_key, plaintext)
+    }
+
+    /// Decrypts a buffer produced by `encrypt`.
+    ///
+    /// # Errors
+    /// Returns `CratisError::EncryptionError` if the data was encrypted with a different key or
+    /// has been tampered with.
+    pub fn decrypt(&self, data: &[u8]) -> CratisResult<Vec<u8>> {
+        open(&self.encryption_key, data)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [self.encryption_key, self.hash_key].concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> CratisResult<Self> {
+        if bytes.len() != 2 * KEY_LEN {
+            return Err(CratisError::EncryptionError("Key file contains a malformed key"));
+        }
+
+        let mut encryption_key = [0u8; KEY_LEN];
+        let mut hash_key = [0u8; KEY_LEN];
+        encryption_key.copy_from_slice(&bytes[..KEY_LEN]);
+        hash_key.copy_from_slice(&bytes[KEY_LEN..]);
+
+        Ok(Self { encryption_key, hash_key })
+    }
+}
+
+impl std::fmt::Debug for RepoKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RepoKey { .. }")
+    }
+}
+
+/// Argon2id parameters used to derive the key-encryption key from a passphrase.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String,
+}
+
+/// The on-disk key file of an encrypted repository.
+///
+/// Holds the `RepoKey` encrypted with a key derived from the passphrase via Argon2id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyFile {
+    pub version: u32,
+    pub kdf: KdfParams,
+    pub wrapped_key: String,
+}
+
+impl KeyFile {
+    /// Creates a key file protecting a freshly generated repository key.
+    ///
+    /// # Returns
+    /// The key file and the repository key it protects.
+    ///
+    /// # Errors
+    /// Returns `CratisError::EncryptionError` if key derivation or encryption fails.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let (key_file, key) = KeyFile::create("correct horse battery staple")?;
+    /// key_file.save(Path::new("/var/lib/cratis/keyfile"))?;
+    /// ```
+    pub fn create(passphrase: &str) -> CratisResult<(Self, RepoKey)> {
+        let key = RepoKey::generate();
+        let key_file = Self::wrap(&key, passphrase)?;
+
+        Ok((key_file, key))
+    }
+
+    /// Loads a key file from disk.
+    ///
+    /// # Errors
+    /// * `CratisError::IoError` if the file cannot be read
+    /// * `CratisError::SerializationError` if the file is malformed
+    pub fn load(path: &Path) -> CratisResult<Self> {
+        let data = fs::read(path)?;
+        let key_file: Self = serde_json::from_slice(&data)?;
+
+        if key_file.version != KEY_FILE_VERSION {
+            return Err(CratisError::Unsupported("Unknown key file version"));
+        }
+
+        Ok(key_file)
+    }
+
+    /// Writes the key file to disk, atomically replacing any existing file.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the file cannot be written.
+    pub fn save(&self, path: &Path) -> CratisResult<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Decrypts the repository key with the given passphrase.
+    ///
+    /// # Errors
+    /// Returns `CratisError::AuthFailure` if the passphrase is wrong or the key file has been
+    /// tampered with.
+    pub fn unlock(&self, passphrase: &str) -> CratisResult<RepoKey> {
+        let kek = derive_key(passphrase, &self.kdf)?;
+        let wrapped = hex::decode(&self.wrapped_key)
+            .map_err(|_| CratisError::EncryptionError("Key file contains malformed data"))?;
+
+        let plain = open(&kek, &wrapped).map_err(|_| CratisError::AuthFailure("Wrong passphrase or corrupt key file"))?;
+        RepoKey::from_bytes(&plain)
+    }
+
+    /// Re-encrypts the repository key under a new passphrase.
+    ///
+    /// Only the key file changes; every stored object stays encrypted with the same repository key.
+    ///
+    /// # Errors
+    /// Returns `CratisError::AuthFailure` if `old_passphrase` is wrong.
+    pub fn change_passphrase(&self, old_passphrase: &str, new_passphrase: &str) -> CratisResult<Self> {
+        let key = self.unlock(old_passphrase)?;
+        Self::wrap(&key, new_passphrase)
+    }
+
+    fn wrap(key: &RepoKey, passphrase: &str) -> CratisResult<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let defaults = Params::default();
+        let kdf = KdfParams {
+            m_cost: defaults.m_cost(),
+            t_cost: defaults.t_cost(),
+            p_cost: defaults.p_cost(),
+            salt: hex::encode(salt),
+        };
+
+        let kek = derive_key(passphrase, &kdf)?;
+        let wrapped_key = hex::encode(seal(&kek, &key.to_bytes())?);
+
+        Ok(Self { version: KEY_FILE_VERSION, kdf, wrapped_key })
+    }
+}
+
+fn derive_key(passphrase: &str, kdf: &KdfParams) -> CratisResult<[u8; KEY_LEN]> {
+    let salt = hex::decode(&kdf.salt).map_err(|_| CratisError::EncryptionError("Key file contains a malformed salt"))?;
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(KEY_LEN))
+        .map_err(|_| CratisError::EncryptionError("Key file contains invalid KDF parameters"))?;
+
+    let mut key = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|_| CratisError::EncryptionError("Failed to derive key from passphrase"))?;
+
+    Ok(key)
+}
+
+fn seal(key: &[u8; KEY_LEN], plaintext: &[u8]) -> CratisResult<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| CratisError::EncryptionError("Failed to encrypt data"))?;
+
+    let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+
+    Ok(out)
+}
+
+fn open(key: &[u8; KEY_LEN], data: &[u8]) -> CratisResult<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        return Err(CratisError::EncryptionError("Encrypted data is truncated"));
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CratisError::EncryptionError("Failed to decrypt data: wrong key or corrupt data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::ObjectStore;
+    use tempfile::TempDir;
+
+    #[test]
+    fn unlock_with_wrong_passphrase_fails() {
+        let (key_file, _) = KeyFile::create("correct horse").unwrap();
+
+        assert!(matches!(key_file.unlock("battery staple"), Err(CratisError::AuthFailure(_))));
+    }
+
+    #[test]
+    fn unlock_returns_the_wrapped_key() {
+        let (key_file, key) = KeyFile::create("correct horse").unwrap();
+        let unlocked = key_file.unlock("correct horse").unwrap();
+
+        assert_eq!(unlocked.to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn decrypt_with_another_key_fails() {
+        let encrypted = RepoKey::generate().encrypt(b"secret").unwrap();
+
+        assert!(matches!(RepoKey::generate().decrypt(&encrypted), Err(CratisError::EncryptionError(_))));
+    }
+
+    #[test]
+    fn decrypt_detects_tampering() {
+        let key = RepoKey::generate();
+        let mut encrypted = key.encrypt(b"secret").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+
+        assert!(matches!(key.decrypt(&encrypted), Err(CratisError::EncryptionError(_))));
+    }
+
+    #[test]
+    fn decrypt_detects_truncation() {
+        let key = RepoKey::generate();
+        let encrypted = key.encrypt(b"secret").unwrap();
+
+        assert!(matches!(key.decrypt(&encrypted[..encrypted.len() - 1]), Err(CratisError::EncryptionError(_))));
+        assert!(matches!(key.decrypt(&encrypted[..NONCE_LEN - 1]), Err(CratisError::EncryptionError(_))));
+    }
+
+    #[test]
+    fn change_passphrase_keeps_objects_readable() {
+        let dir = TempDir::new().unwrap();
+        let (key_file, key) = KeyFile::create("old passphrase").unwrap();
+        let hash = ObjectStore::open(dir.path()).unwrap().with_key(key).put_bytes(b"stored before").unwrap();
+
+        let changed = key_file.change_passphrase("old passphrase", "new passphrase").unwrap();
+        assert!(matches!(changed.unlock("old passphrase"), Err(CratisError::AuthFailure(_))));
+
+        let store = ObjectStore::open(dir.path()).unwrap().with_key(changed.unlock("new passphrase").unwrap());
+        assert_eq!(store.get(&hash).unwrap(), b"stored before");
+    }
+
+    #[test]
+    fn change_passphrase_requires_the_old_one() {
+        let (key_file, _) = KeyFile::create("old passphrase").unwrap();
+
+        assert!(matches!(key_file.change_passphrase("wrong", "new passphrase"), Err(CratisError::AuthFailure(_))));
+    }
+
+    #[test]
+    fn encrypted_store_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let key = RepoKey::generate();
+        let store = ObjectStore::open(dir.path()).unwrap().with_key(key.clone());
+
+        let hash = store.put_bytes(b"hello cratis").unwrap();
+        assert_eq!(hash, key.keyed_hash(b"hello cratis"));
+        assert_eq!(store.get(&hash).unwrap(), b"hello cratis");
+
+        let on_disk = store.get_encoded(&hash).unwrap();
+        assert!(!on_disk.windows(b"hello cratis".len()).any(|window| window == b"hello cratis"));
+
+        let other = ObjectStore::open(dir.path()).unwrap().with_key(RepoKey::generate());
+        assert!(matches!(other.get(&hash), Err(CratisError::EncryptionError(_))));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::config::ConfigProblem;
+
 #[derive(Debug, thiserror::Error)]
 pub enum CratisError {
     #[error("Failed read/write file: {0}")]
@@ -9,6 +11,9 @@ pub enum CratisError {
     #[error("Failed to parse configuration: {0}")]
     ConfigParseError(#[from] serde_yaml::Error),
 
+    #[error("Invalid configuration:{}", list_problems(.0))]
+    InvalidConfig(Vec<ConfigProblem>),
+
     #[error("Invalid input provided: {0}")]
     InvalidInput(&'static str),
 
@@ -21,6 +26,9 @@ pub enum CratisError {
     #[error("Authentication failed: {0}")]
     AuthFailure(&'static str),
 
+    #[error("Encryption error: {0}")]
+    EncryptionError(&'static str),
+
     #[error("Operation timed out")]
     Timeout,
 
@@ -39,12 +47,73 @@ pub enum CratisError {
     #[error("Channel error: {0}")]
     ChannelError(String),
 
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sled::Error),
+
+    #[error("Failed to (de)serialize data: {0}")]
+    SerializationError(#[from] serde_json::Error),
+
     #[error("Unknown error")]
     Unknown,
 }
 
 pub type CratisResult<T> = Result<T, CratisError>;
 
+impl CratisError {
+    /// Returns `true` if the operation that failed with this error may succeed when retried.
+    ///
+    /// Transient errors are network problems (`ConnectionIssue`, `Timeout`) and I/O errors
+    /// caused by a busy, locked or temporarily unavailable file or connection. Everything else,
+    /// in particular `AuthFailure`, `InvalidPath` and missing files, is permanent and retrying
+    /// would only delay the failure.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// assert!(CratisError::Timeout.is_transient());
+    /// assert!(!CratisError::AuthFailure("Wrong token").is_transient());
+    /// ```
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CratisError::ConnectionIssue(_) | CratisError::Timeout => true,
+            CratisError::IoError(e) => is_transient_io(e),
+            _ => false,
+        }
+    }
+}
+
+/// Classifies I/O errors that are typically resolved by waiting, such as a file locked by
+/// another process or an interrupted connection.
+fn is_transient_io(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    // EBUSY and ETXTBSY on Unix, ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION on Windows.
+    #[cfg(unix)]
+    const BUSY_CODES: &[i32] = &[16, 26];
+    #[cfg(windows)]
+    const BUSY_CODES: &[i32] = &[32, 33];
+    #[cfg(not(any(unix, windows)))]
+    const BUSY_CODES: &[i32] = &[];
+
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ResourceBusy
+            | ErrorKind::ExecutableFileBusy
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    ) || error.raw_os_error().is_some_and(|code| BUSY_CODES.contains(&code))
+}
+
+/// Formats configuration problems as an indented list, one per line.
+fn list_problems(problems: &[ConfigProblem]) -> String {
+    problems.iter().map(|problem| format!("\n  - {problem}")).collect()
+}
+
 /// Displays a Cratis error message to standard error (stderr).
 ///
 /// # Arguments
@@ -54,6 +123,7 @@ pub type CratisResult<T> = Result<T, CratisError>;
 ///
 /// When `debug` is true, displays the error using pretty-printed debug formatting ({:#?}).
 /// When `debug` is false, displays a simple user-friendly error message using the Display trait.
+/// The process then exits with status 1.
 ///
 /// # Examples
 ///
@@ -64,7 +134,7 @@ pub type CratisResult<T> = Result<T, CratisError>;
 /// display_error(&error, false); // Displays: "Invalid input provided: Invalid configuration"
 /// display_error(&error, true);  // Displays detailed debug structure with formatting
 /// ```
-pub fn display_error(error: &CratisError, debug: bool) {
+pub fn display_error(error: &CratisError, debug: bool) -> ! {
     if debug {
         eprintln!("Error (debug): {:#?}", error);
     } else {
@@ -73,3 +143,28 @@ pub fn display_error(error: &CratisError, debug: bool) {
     
     std::process::exit(1);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn only_errors_resolved_by_waiting_are_transient() {
+        for error in [CratisError::Timeout, CratisError::ConnectionIssue("Connection refused")] {
+            assert!(error.is_transient(), "{error:?}");
+        }
+        for kind in [io::ErrorKind::Interrupted, io::ErrorKind::TimedOut, io::ErrorKind::ConnectionReset, io::ErrorKind::ResourceBusy] {
+            assert!(CratisError::from(io::Error::from(kind)).is_transient(), "{kind:?}");
+        }
+        #[cfg(unix)]
+        assert!(CratisError::from(io::Error::from_raw_os_error(16)).is_transient());
+
+        for error in [CratisError::AuthFailure("Wrong token"), CratisError::BackupFailure("Conflict"), CratisError::InvalidInput("Bad digest")] {
+            assert!(!error.is_transient(), "{error:?}");
+        }
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied, io::ErrorKind::InvalidData] {
+            assert!(!CratisError::from(io::Error::from(kind)).is_transient(), "{kind:?}");
+        }
+    }
+}
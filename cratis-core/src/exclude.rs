@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use crate::config::{BackupConfig, TempFileConfig};
+use crate::error::{CratisError, CratisResult};
+
+/// Name of the per-directory exclusion files.
+pub const IGNORE_FILE_NAME: &str = ".cratisignore";
+/// Name of git's per-directory exclusion files, honored if `gitignore` is set.
+pub const GITIGNORE_FILE_NAME: &str = ".gitignore";
+/// The built-in rule sets for temporary files, see `TempFileConfig`.
+pub const BUILTIN_RULE_SETS: &[(&str, &[&str])] = &[
+    ("editor_swap", &["*.swp", "*.swo", "4913", ".#*", "~*"]),
+    ("office_lock", &["~$*", ".~lock.*#"]),
+    ("temporary", &["*.tmp", "*.temp"]),
+    ("build_artifacts", &["target/", "node_modules/", "__pycache__/", "*.pyc", "*.o", "*.class"]),
+];
+/// The rule sets used unless `temp_files.rule_sets` is configured.
+pub const DEFAULT_RULE_SETS: &[&str] = &["editor_swap", "office_lock", "temporary"];
+/// How long the exclusion files of a directory are trusted before checking them for changes.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The rule that excluded a path, see `ExcludeRules::explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExcludeRule {
+    /// The pattern as written, e.g. `target/` or `/*.log`.
+    pub pattern: String,
+    /// Where the pattern comes from.
+    pub source: RuleSource,
+    /// The path the pattern matched: the path itself or the excluded directory containing it.
+    pub matched: PathBuf,
+}
+
+impl fmt::Display for ExcludeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" from {}, matching {}", self.pattern, self.source, self.matched.display())
+    }
+}
+
+/// Where an exclusion pattern comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleSource {
+    /// The `exclude` patterns of the backup set.
+    Config,
+    /// A `.cratisignore` or `.gitignore` file.
+    File(PathBuf),
+    /// A rule set for temporary files, by name.
+    RuleSet(String),
+}
+
+impl fmt::Display for RuleSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleSource::Config => write!(f, "the configuration"),
+            RuleSource::File(path) => write!(f, "{}", path.display()),
+            RuleSource::RuleSet(name) => write!(f, "the {name} rule set"),
+        }
+    }
+}
+
+/// The gitignore-style exclusion rules of a backup set.
+///
+/// Patterns follow the semantics of `.gitignore` files:
+/// * Patterns are relative to the watch directory, or to the directory of the exclusion file
+///   they come from
+/// * A pattern without a slash matches at any depth (`*.log`), a pattern with a slash is
+///   anchored (`/build`, `docs/*.pdf`)
+/// * `**` matches any number of directories (`**/node_modules`, `logs/**`)
+/// * A trailing slash only matches directories (`target/`)
+/// * A leading `!` re-includes a path excluded by an earlier pattern, except for paths inside
+///   an excluded directory
+/// * The last matching pattern wins
+///
+/// Besides `exclude` in the configuration, every directory below a watch directory may contain
+/// a `.cratisignore` file, and a `.gitignore` file if `gitignore` is set. Files deeper in the
+/// tree take precedence over files further up, which take precedence over the configuration.
+/// Within a directory, `.cratisignore` takes precedence over `.gitignore`. Exclusion files are
+/// read again when they change, so a running watcher picks up edits.
+///
+/// Paths not matched by any of these are finally checked against the rule sets for temporary
+/// files, see `TempFileConfig`. A path re-included with `!` is never treated as temporary.
+///
+/// ```yaml
+/// backup:
+///   watch_directories: ["/home/user/src"]
+///   gitignore: true
+///   exclude:
+///     - "target/"
+///     - "*.log"
+///     - "!release.log"
+/// ```
+#[derive(Debug)]
+pub struct ExcludeRules {
+    roots: Vec<RootRules>,
+    file_names: Vec<&'static str>,
+    directories: Mutex<HashMap<PathBuf, DirectoryRules>>,
+}
+
+/// The configured patterns, compiled for one watch directory.
+#[derive(Debug)]
+struct RootRules {
+    root: PathBuf,
+    rules: Gitignore,
+    /// The patterns as configured, by the anchored pattern they were compiled to.
+    anchored: HashMap<String, String>,
+    /// The rule sets for temporary files by name, in the order they are checked.
+    temp_files: Vec<(String, Gitignore)>,
+    /// Patterns of temporary files that are backed up regardless.
+    include: Gitignore,
+}
+
+impl RootRules {
+    /// Returns the rule set pattern matching `path`, unless it is included explicitly.
+    fn temp_file(&self, path: &Path, is_dir: bool) -> Option<ExcludeRule> {
+        if self.include.matched(path, is_dir).is_ignore() {
+            return None;
+        }
+
+        self.temp_files.iter().find_map(|(name, rules)| match rules.matched(path, is_dir) {
+            Match::Ignore(glob) => Some(ExcludeRule {
+                pattern: glob.original().to_string(),
+                source: RuleSource::RuleSet(name.clone()),
+                matched: path.to_path_buf(),
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// The exclusion files of a directory, as of the modification times they were read at.
+#[derive(Debug)]
+struct DirectoryRules {
+    modified: Vec<Option<SystemTime>>,
+    checked: Instant,
+    rules: Option<Arc<Gitignore>>,
+}
+
+impl ExcludeRules {
+    /// Compiles the `exclude` and `temp_files` rules of a backup set for each of its watch
+    /// directories.
+    ///
+    /// # Errors
+    /// Returns `CratisError::ConfigError` if a pattern is invalid.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let rules = ExcludeRules::from_config(config.backup_set("code")?)?;
+    /// if let Some(rule) = rules.explain(Path::new("/home/user/src/target/debug")) {
+    ///     println!("Excluded by {rule}");
+    /// }
+    /// ```
+    pub fn from_config(backup: &BackupConfig) -> CratisResult<Self> {
+        let patterns = backup.exclude.as_deref().unwrap_or_default();
+        Self::new(&backup.watch_directories, patterns, backup.gitignore.unwrap_or(false))?
+            .with_temp_files(&backup.temp_files.clone().unwrap_or_default())
+    }
+
+    /// Compiles exclusion patterns for the given watch directories.
+    ///
+    /// For compatibility with plain glob patterns, an absolute pattern starting with a watch
+    /// directory is anchored to it, e.g. `/home/user/src/target/` becomes `/target/` for the
+    /// watch directory `/home/user/src`.
+    ///
+    /// No temporary files are skipped until rule sets are added with `with_temp_files`.
+    ///
+    /// # Arguments
+    /// * `roots` - The watch directories the patterns are relative to
+    /// * `patterns` - The patterns, in order of increasing precedence
+    /// * `gitignore` - Whether `.gitignore` files are honored next to `.cratisignore` files
+    ///
+    /// # Errors
+    /// Returns `CratisError::ConfigError` if a pattern is invalid.
+    pub fn new(roots: &[String], patterns: &[String], gitignore: bool) -> CratisResult<Self> {
+        let mut compiled = Vec::new();
+
+        for root in roots {
+            let mut builder = GitignoreBuilder::new(root);
+            let mut anchored = HashMap::new();
+            for pattern in patterns {
+                let line = anchor(root, pattern);
+                builder
+                    .add_line(None, &line)
+                    .map_err(|e| CratisError::ConfigError(format!("Invalid exclusion pattern '{}': {}", pattern, e)))?;
+                if line != *pattern {
+                    anchored.insert(line, pattern.clone());
+                }
+            }
+
+            let rules = builder.build().map_err(|e| CratisError::ConfigError(format!("Invalid exclusion patterns: {e}")))?;
+            compiled.push(RootRules { root: PathBuf::from(root), rules, anchored, temp_files: Vec::new(), include: Gitignore::empty() });
+        }
+
+        let file_names = if gitignore { vec![GITIGNORE_FILE_NAME, IGNORE_FILE_NAME] } else { vec![IGNORE_FILE_NAME] };
+
+        Ok(Self { roots: compiled, file_names, directories: Mutex::new(HashMap::new()) })
+    }
+
+    /// Adds the rule sets for temporary files.
+    ///
+    /// The sets listed in `rule_sets` are used, or `DEFAULT_RULE_SETS` if it is not configured,
+    /// with their patterns taken from `rules` or else `BUILTIN_RULE_SETS`.
+    ///
+    /// # Errors
+    /// Returns `CratisError::ConfigError` if a rule set is unknown or a pattern is invalid.
+    pub fn with_temp_files(mut self, config: &TempFileConfig) -> CratisResult<Self> {
+        let names: Vec<&str> = match &config.rule_sets {
+            Some(names) => names.iter().map(String::as_str).collect(),
+            None => DEFAULT_RULE_SETS.to_vec(),
+        };
+
+        for root in &mut self.roots {
+            for name in &names {
+                let patterns: Vec<&str> = match config.rules.as_ref().and_then(|rules| rules.get(*name)) {
+                    Some(patterns) => patterns.iter().map(String::as_str).collect(),
+                    None => builtin_rule_set(name)
+                        .ok_or_else(|| CratisError::ConfigError(format!("Unknown rule set '{}'", name)))?
+                        .to_vec(),
+                };
+
+                let rules = compile(&root.root, &patterns)?;
+                root.temp_files.push((name.to_string(), rules));
+            }
+
+            let include: Vec<&str> = config.include.iter().flatten().map(String::as_str).collect();
+            root.include = compile(&root.root, &include)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Returns `true` if `path` is excluded from the backup.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.explain(path).is_some()
+    }
+
+    /// Explains why a path is excluded from the backup.
+    ///
+    /// The parent directories of `path` below the watch directory are checked first, from the
+    /// top, so a path inside an excluded directory is reported with the rule excluding the
+    /// directory. Whether `path` itself is a directory is read from the filesystem, a path that
+    /// no longer exists is matched as a file.
+    ///
+    /// # Returns
+    /// The rule that excluded the path, or `None` if it is backed up. Paths outside the watch
+    /// directories are never excluded.
+    pub fn explain(&self, path: &Path) -> Option<ExcludeRule> {
+        let root = self
+            .roots
+            .iter()
+            .filter(|root| path.starts_with(&root.root))
+            .max_by_key(|root| root.root.components().count())?;
+        let relative = path.strip_prefix(&root.root).ok()?;
+
+        let mut current = root.root.clone();
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            current.push(component);
+            let is_dir = components.peek().is_some() || fs::symlink_metadata(&current).is_ok_and(|m| m.is_dir());
+
+            match self.matched(root, &current, is_dir) {
+                Match::Ignore(glob) => {
+                    let pattern = root.anchored.get(glob.original()).map(String::as_str).unwrap_or(glob.original());
+                    return Some(ExcludeRule {
+                        pattern: pattern.to_string(),
+                        source: glob.from().map_or(RuleSource::Config, |file| RuleSource::File(file.to_path_buf())),
+                        matched: current,
+                    });
+                }
+                Match::Whitelist(_) => {}
+                Match::None => {
+                    if let Some(rule) = root.temp_file(&current, is_dir) {
+                        return Some(rule);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Matches a single path against the exclusion files of its parent directories, deepest
+    /// first, and then against the configured patterns.
+    fn matched(&self, root: &RootRules, path: &Path, is_dir: bool) -> Match<Glob> {
+        for dir in path.ancestors().skip(1).take_while(|dir| dir.starts_with(&root.root)) {
+            if let Some(rules) = self.directory_rules(dir) {
+                let matched = rules.matched(path, is_dir);
+                if !matched.is_none() {
+                    return matched.map(Glob::clone);
+                }
+            }
+        }
+
+        root.rules.matched(path, is_dir).map(Glob::clone)
+    }
+
+    /// Returns the rules of the exclusion files in `dir`, reading them again if they changed.
+    ///
+    /// Invalid lines in exclusion files are skipped.
+    fn directory_rules(&self, dir: &Path) -> Option<Arc<Gitignore>> {
+        let mut directories = self.directories.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(cached) = directories.get(dir)
+            && cached.checked.elapsed() < RECHECK_INTERVAL
+        {
+            return cached.rules.clone();
+        }
+
+        let modified: Vec<Option<SystemTime>> = self
+            .file_names
+            .iter()
+            .map(|name| fs::metadata(dir.join(name)).and_then(|metadata| metadata.modified()).ok())
+            .collect();
+
+        if let Some(cached) = directories.get_mut(dir)
+            && cached.modified == modified
+        {
+            cached.checked = Instant::now();
+            return cached.rules.clone();
+        }
+
+        let rules = if modified.iter().any(Option::is_some) {
+            let mut builder = GitignoreBuilder::new(dir);
+            for (name, modified) in self.file_names.iter().zip(&modified) {
+                if modified.is_some() {
+                    builder.add(dir.join(name));
+                }
+            }
+            builder.build().ok().filter(|rules| !rules.is_empty()).map(Arc::new)
+        } else {
+            None
+        };
+
+        directories.insert(dir.to_path_buf(), DirectoryRules { modified, checked: Instant::now(), rules: rules.clone() });
+        rules
+    }
+}
+
+/// Checks that `pattern` is a valid exclusion pattern.
+///
+/// # Errors
+/// Returns a description of the problem.
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    GitignoreBuilder::new("/").add_line(None, pattern).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Returns `true` if `name` is one of `BUILTIN_RULE_SETS`.
+pub fn is_builtin_rule_set(name: &str) -> bool {
+    builtin_rule_set(name).is_some()
+}
+
+fn builtin_rule_set(name: &str) -> Option<&'static [&'static str]> {
+    BUILTIN_RULE_SETS.iter().find(|(builtin, _)| *builtin == name).map(|(_, patterns)| *patterns)
+}
+
+/// Compiles patterns relative to `root`.
+fn compile(root: &Path, patterns: &[&str]) -> CratisResult<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .map_err(|e| CratisError::ConfigError(format!("Invalid pattern '{}': {}", pattern, e)))?;
+    }
+    builder.build().map_err(|e| CratisError::ConfigError(format!("Invalid patterns: {e}")))
+}
+
+/// Anchors an absolute pattern starting with `root` to it, keeping any other pattern as is.
+fn anchor(root: &str, pattern: &str) -> String {
+    let (negation, glob) = match pattern.strip_prefix('!') {
+        Some(glob) => ("!", glob),
+        None => ("", pattern),
+    };
+
+    match glob.strip_prefix(root.trim_end_matches('/')) {
+        Some(rest) if rest.len() > 1 && rest.starts_with('/') => format!("{negation}{rest}"),
+        _ => pattern.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    /// A watched directory and the rules for it, without any temporary file rule sets.
+    fn setup(patterns: &[&str]) -> (TempDir, PathBuf, ExcludeRules) {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_path_buf();
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        let rules = ExcludeRules::new(&[root.to_string_lossy().into_owned()], &patterns, false).unwrap();
+        (dir, root, rules)
+    }
+
+    /// Creates a file with its parent directories.
+    fn touch(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn nested_ignore_files_take_precedence() {
+        let (_dir, root, rules) = setup(&["*.txt"]);
+        touch(&root.join(IGNORE_FILE_NAME), "*.log\n!notes.txt\n");
+        touch(&root.join("logs").join(IGNORE_FILE_NAME), "!keep.log\n");
+
+        let rule = rules.explain(&root.join("debug.log")).unwrap();
+        assert_eq!(rule.pattern, "*.log");
+        assert_eq!(rule.source, RuleSource::File(root.join(IGNORE_FILE_NAME)));
+
+        assert!(rules.is_excluded(&root.join("logs").join("debug.log")));
+        assert!(!rules.is_excluded(&root.join("logs").join("keep.log")));
+
+        assert_eq!(rules.explain(&root.join("todo.txt")).unwrap().source, RuleSource::Config);
+        assert!(!rules.is_excluded(&root.join("notes.txt")));
+    }
+
+    #[test]
+    fn negated_patterns_are_backed_up() {
+        let (_dir, root, rules) = setup(&["*.log", "!important.log"]);
+
+        assert!(rules.is_excluded(&root.join("debug.log")));
+        assert!(!rules.is_excluded(&root.join("important.log")));
+        assert!(!rules.is_excluded(&root.join("src").join("important.log")));
+    }
+
+    #[test]
+    fn negation_inside_an_excluded_directory_has_no_effect() {
+        let (_dir, root, rules) = setup(&["build/", "!build/keep.txt"]);
+        touch(&root.join("build").join("keep.txt"), "");
+
+        let rule = rules.explain(&root.join("build").join("keep.txt")).unwrap();
+        assert_eq!(rule.pattern, "build/");
+        assert_eq!(rule.matched, root.join("build"));
+    }
+
+    #[test]
+    fn absolute_patterns_are_anchored_to_the_watch_directory() {
+        assert_eq!(anchor("/home/user/src", "/home/user/src/target/"), "/target/");
+        assert_eq!(anchor("/home/user/src/", "/home/user/src/target/"), "/target/");
+        assert_eq!(anchor("/home/user/src", "!/home/user/src/target/keep"), "!/target/keep");
+        assert_eq!(anchor("/home/user/src", "/home/user/srcs/target/"), "/home/user/srcs/target/");
+        assert_eq!(anchor("/home/user/src", "/home/user/src"), "/home/user/src");
+        assert_eq!(anchor("/home/user/src", "*.log"), "*.log");
+
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_string_lossy().into_owned();
+        let pattern = format!("{root}/target/");
+        let rules = ExcludeRules::new(std::slice::from_ref(&root), std::slice::from_ref(&pattern), false).unwrap();
+
+        let rule = rules.explain(&dir.path().join("target").join("debug")).unwrap();
+        assert_eq!(rule.pattern, pattern);
+        assert_eq!(rule.matched, dir.path().join("target"));
+        assert!(!rules.is_excluded(&dir.path().join("src").join("target").join("debug")));
+    }
+
+    /// A watched directory and the rules for temporary files configured by `config`.
+    fn setup_temp_files(config: TempFileConfig) -> (TempDir, PathBuf, ExcludeRules) {
+        let (dir, root, rules) = setup(&[]);
+        (dir, root, rules.with_temp_files(&config).unwrap())
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn default_rule_sets_exclude_editor_and_lock_files() {
+        let (_dir, root, rules) = setup_temp_files(TempFileConfig::default());
+
+        let rule = rules.explain(&root.join("4913")).unwrap();
+        assert_eq!(rule.pattern, "4913");
+        assert_eq!(rule.source, RuleSource::RuleSet("editor_swap".to_string()));
+
+        for name in [".#notes.txt", "~notes.txt", ".notes.txt.swp", "~$report.docx", ".~lock.report.odt#", "download.tmp"] {
+            assert!(rules.is_excluded(&root.join("docs").join(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn dotfiles_and_backup_copies_are_backed_up() {
+        let (_dir, root, rules) = setup_temp_files(TempFileConfig::default());
+
+        for name in [".env", ".bashrc", "foo.bak", "notes.txt"] {
+            assert!(!rules.is_excluded(&root.join(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn include_overrides_rule_sets() {
+        let config = TempFileConfig { include: Some(strings(&["*.keep.tmp"])), ..TempFileConfig::default() };
+        let (_dir, root, rules) = setup_temp_files(config);
+
+        assert!(!rules.is_excluded(&root.join("state.keep.tmp")));
+        assert!(rules.is_excluded(&root.join("download.tmp")));
+    }
+
+    #[test]
+    fn custom_rules_replace_builtin_sets() {
+        let config = TempFileConfig {
+            rule_sets: Some(strings(&["editor_swap", "scratch"])),
+            rules: Some(BTreeMap::from([
+                ("editor_swap".to_string(), strings(&["*.bak"])),
+                ("scratch".to_string(), strings(&["scratch/"])),
+            ])),
+            include: None,
+        };
+        let (_dir, root, rules) = setup_temp_files(config);
+
+        let rule = rules.explain(&root.join("foo.bak")).unwrap();
+        assert_eq!(rule.source, RuleSource::RuleSet("editor_swap".to_string()));
+        assert_eq!(rules.explain(&root.join("scratch").join("a.txt")).unwrap().source, RuleSource::RuleSet("scratch".to_string()));
+
+        assert!(!rules.is_excluded(&root.join("4913")));
+        assert!(!rules.is_excluded(&root.join(".notes.txt.swp")));
+        assert!(!rules.is_excluded(&root.join("download.tmp")));
+    }
+
+    #[test]
+    fn unknown_rule_sets_are_rejected() {
+        let config = TempFileConfig { rule_sets: Some(strings(&["unknown"])), ..TempFileConfig::default() };
+        let (_dir, _root, rules) = setup(&[]);
+        assert!(matches!(rules.with_temp_files(&config), Err(CratisError::ConfigError(_))));
+    }
+}
@@ -0,0 +1,655 @@
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config::{BackupMode, DEFAULT_BACKUP_SET};
+use crate::error::{CratisError, CratisResult};
+use crate::utils::EventAction;
+
+const VERSIONS_TREE: &str = "versions";
+const SNAPSHOTS_TREE: &str = "snapshots";
+const SKIPPED_TREE: &str = "skipped";
+const KEY_SEPARATOR: u8 = 0;
+/// Prefix of the trees of named backup sets, followed by `<set>/<tree>`.
+const NAMESPACE_PREFIX: &str = "sets/";
+/// How many moves `VersionIndex::history` follows back before giving up.
+const MAX_MOVE_DEPTH: usize = 64;
+
+/// A single recorded revision of a watched file.
+///
+/// `hash` is the BLAKE3 hex digest of the contents as stored in the object store. It is `None`
+/// for revisions that carry no content, such as a deletion. `mtime` is the modification time of
+/// the file when the revision was recorded, `0` if unknown.
+///
+/// A move is recorded as a pair of revisions sharing a timestamp: a deletion at the old path
+/// with `moved_to` set, and an `EventAction::Rename` revision carrying the unchanged content at
+/// the new path.
+///
+/// Directories are recorded as `EventAction::CreateDir` and `EventAction::DeleteDir` revisions
+/// without content, so empty directories are part of the backup as well.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub timestamp: u64,
+    pub hash: Option<String>,
+    pub size: u64,
+    #[serde(default)]
+    pub mtime: u64,
+    pub action: EventAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moved_to: Option<PathBuf>,
+}
+
+impl FileVersion {
+    /// Returns `true` if the revision records the deletion of a file or directory.
+    pub fn is_deleted(&self) -> bool {
+        matches!(self.action, EventAction::Delete | EventAction::DeleteDir)
+    }
+
+    /// Returns `true` if the revision belongs to a directory rather than a file.
+    pub fn is_directory(&self) -> bool {
+        matches!(self.action, EventAction::CreateDir | EventAction::DeleteDir)
+    }
+}
+
+/// A recorded snapshot: the id of its manifest object plus a small summary for listings.
+///
+/// `files` and `size` describe the complete tree of the snapshot, also for incremental snapshots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotRecord {
+    pub id: String,
+    pub timestamp: u64,
+    pub mode: BackupMode,
+    pub files: u64,
+    pub size: u64,
+}
+
+/// A file that was not backed up because it exceeds `max_file_size_mb`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedFile {
+    pub timestamp: u64,
+    pub size: u64,
+    /// The size limit in bytes that was exceeded.
+    pub limit: u64,
+}
+
+/// A sled-backed index of every recorded revision of every watched file.
+///
+/// Revisions are stored in a dedicated tree under keys of the form
+/// `<path> 0x00 <timestamp: u64 BE> <sequence: u64 BE>`, so all revisions of a path are
+/// contiguous and sorted chronologically. The sequence number keeps revisions recorded within
+/// the same second in insertion order.
+///
+/// Snapshots are recorded in a second tree under `<timestamp: u64 BE> <sequence: u64 BE>`, and
+/// files skipped for their size in a third tree keyed by path.
+///
+/// Every backup set has its own namespace of these trees in the same database, see `namespace`.
+///
+/// The index is never encrypted, also not in an encrypted repository: paths, sizes,
+/// modification times and object digests are stored in plaintext. It is meant to stay on the
+/// machine it describes, protected by the permissions of the repository directory.
+#[derive(Debug, Clone)]
+pub struct VersionIndex {
+    db: sled::Db,
+    name: String,
+    versions: sled::Tree,
+    snapshots: sled::Tree,
+    skipped: sled::Tree,
+}
+
+impl VersionIndex {
+    /// Opens (or creates) the version index stored in the given directory.
+    ///
+    /// # Arguments
+    /// * `path` - The directory holding the sled database
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the database cannot be opened.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let index = VersionIndex::open("/var/lib/cratis/index")?;
+    /// let latest = index.latest(Path::new("/home/user/notes.txt"))?;
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> CratisResult<Self> {
+        let db = sled::open(path)?;
+        Self::from_db(db)
+    }
+
+    /// Builds a version index on top of an already opened sled database.
+    ///
+    /// The index is opened in the namespace of the default backup set.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the trees cannot be opened.
+    pub fn from_db(db: sled::Db) -> CratisResult<Self> {
+        Self::open_namespace(db, DEFAULT_BACKUP_SET)
+    }
+
+    /// Returns the index of the given backup set in the same database.
+    ///
+    /// The default backup set uses the unprefixed trees, so repositories created before backup
+    /// sets existed keep their history. Every other set uses trees named `sets/<set>/<tree>`.
+    /// Namespaces are created on first use.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the trees cannot be opened.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let photos = repo.index.namespace("photos")?;
+    /// let snapshots = photos.snapshots()?;
+    /// ```
+    pub fn namespace(&self, name: &str) -> CratisResult<Self> {
+        Self::open_namespace(self.db.clone(), name)
+    }
+
+    /// Returns the names of every backup set with a namespace in the database, the default set
+    /// first.
+    ///
+    /// # Errors
+    /// Returns `CratisError::Internal` if a tree name is not valid UTF-8.
+    pub fn namespaces(&self) -> CratisResult<Vec<String>> {
+        let mut names = vec![DEFAULT_BACKUP_SET.to_string()];
+
+        for tree in self.db.tree_names() {
+            let tree = std::str::from_utf8(&tree).map_err(|_| CratisError::Internal("Malformed tree name"))?;
+            if let Some(rest) = tree.strip_prefix(NAMESPACE_PREFIX)
+                && let Some(name) = rest.strip_suffix(VERSIONS_TREE).and_then(|rest| rest.strip_suffix('/'))
+            {
+                names.push(name.to_string());
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Returns the name of the backup set this index belongs to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the underlying sled database.
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    /// Returns the name of a tree within the namespace of this index.
+    pub(crate) fn tree_name(&self, tree: &str) -> String {
+        namespaced(&self.name, tree)
+    }
+
+    fn open_namespace(db: sled::Db, name: &str) -> CratisResult<Self> {
+        let versions = db.open_tree(namespaced(name, VERSIONS_TREE))?;
+        let snapshots = db.open_tree(namespaced(name, SNAPSHOTS_TREE))?;
+        let skipped = db.open_tree(namespaced(name, SKIPPED_TREE))?;
+        Ok(Self { db, name: name.to_string(), versions, snapshots, skipped })
+    }
+
+    /// Records a new revision of a file.
+    ///
+    /// # Arguments
+    /// * `path` - The watched path the revision belongs to
+    /// * `version` - The revision to record
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidPath` if the path is not valid UTF-8
+    /// * `CratisError::DatabaseError` if the revision cannot be written
+    pub fn record(&self, path: &Path, version: &FileVersion) -> CratisResult<()> {
+        let sequence = self.db.generate_id()?;
+        let key = version_key(path, version.timestamp, sequence)?;
+        let value = serde_json::to_vec(version)?;
+
+        self.versions.insert(key, value)?;
+
+        Ok(())
+    }
+
+    /// Returns the most recent revision of a file, if any has been recorded.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn latest(&self, path: &Path) -> CratisResult<Option<FileVersion>> {
+        let prefix = path_prefix(path)?;
+
+        self.versions
+            .scan_prefix(prefix)
+            .next_back()
+            .map(|entry| decode_version(entry?.1))
+            .transpose()
+    }
+
+    /// Returns the revision of a file that was current at the given timestamp.
+    ///
+    /// This is the most recent revision recorded at or before `timestamp`. Returns `None` if the
+    /// file had no recorded revision yet at that point in time.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn at(&self, path: &Path, timestamp: u64) -> CratisResult<Option<FileVersion>> {
+        let prefix = path_prefix(path)?;
+        let upper = version_key(path, timestamp, u64::MAX)?;
+
+        self.versions
+            .range(prefix..=upper)
+            .next_back()
+            .map(|entry| decode_version(entry?.1))
+            .transpose()
+    }
+
+    /// Returns every recorded revision of a file, oldest first.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn versions(&self, path: &Path) -> CratisResult<Vec<FileVersion>> {
+        let prefix = path_prefix(path)?;
+
+        self.versions
+            .scan_prefix(prefix)
+            .map(|entry| decode_version(entry?.1))
+            .collect()
+    }
+
+    /// Records that a file was moved from one path to another without its content being read.
+    ///
+    /// The live revision of `from` is carried over to `to` as an `EventAction::Rename`, so the
+    /// content is neither read nor stored again, and `from` is recorded as deleted. Both
+    /// revisions point at each other, see `history` for following a file across moves.
+    ///
+    /// A recorded directory is moved as an `EventAction::CreateDir` at `to`, the files below it
+    /// have to be moved one by one.
+    ///
+    /// # Arguments
+    /// * `from` - The path the file was moved away from
+    /// * `to` - The path the file was moved to
+    /// * `timestamp` - Timestamp of both recorded revisions
+    ///
+    /// # Returns
+    /// The revision recorded at `to`, or `None` if `from` has no live revision to move, in which
+    /// case nothing is recorded.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidPath` if a path is not valid UTF-8
+    /// * `CratisError::DatabaseError` if the index cannot be read or written
+    pub fn record_move(&self, from: &Path, to: &Path, timestamp: u64) -> CratisResult<Option<FileVersion>> {
+        let Some(latest) = self.latest(from)?.filter(|v| !v.is_deleted()) else {
+            return Ok(None);
+        };
+
+        let (deleted, moved) = if latest.is_directory() {
+            (EventAction::DeleteDir, EventAction::CreateDir)
+        } else {
+            (EventAction::Delete, EventAction::Rename { from: from.to_path_buf(), to: to.to_path_buf() })
+        };
+
+        let tombstone = FileVersion {
+            timestamp,
+            hash: None,
+            size: 0,
+            mtime: 0,
+            action: deleted,
+            moved_to: Some(to.to_path_buf()),
+        };
+        let moved = FileVersion { timestamp, action: moved, moved_to: None, ..latest };
+
+        self.record(from, &tombstone)?;
+        self.record(to, &moved)?;
+
+        Ok(Some(moved))
+    }
+
+    /// Returns the history of a file across moves, oldest first.
+    ///
+    /// Starting at the most recent revision of `path`, revisions are collected until one that
+    /// was moved in from another path, whose history up to the move is then followed in turn.
+    /// Revisions recorded at `path` before the move belong to an earlier file and are left out.
+    ///
+    /// # Returns
+    /// Every revision of the file together with the path it was recorded at.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn history(&self, path: &Path) -> CratisResult<Vec<(PathBuf, FileVersion)>> {
+        let mut history = Vec::new();
+        let mut next = Some((path.to_path_buf(), u64::MAX));
+
+        for _ in 0..MAX_MOVE_DEPTH {
+            let Some((path, until)) = next.take() else {
+                break;
+            };
+
+            for version in self.versions(&path)?.into_iter().rev() {
+                // The tombstone of the move that is being followed back.
+                if version.timestamp > until || (version.timestamp == until && version.moved_to.is_some()) {
+                    continue;
+                }
+
+                if let EventAction::Rename { from, .. } = &version.action {
+                    next = Some((from.clone(), version.timestamp));
+                    history.push((path.clone(), version));
+                    break;
+                }
+                history.push((path.clone(), version));
+            }
+        }
+
+        history.reverse();
+        Ok(history)
+    }
+
+    /// Returns every path below a directory that has at least one recorded revision, in sorted order.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidPath` if the path is not valid UTF-8
+    /// * `CratisError::DatabaseError` if the index cannot be read
+    pub fn paths_under(&self, dir: &Path) -> CratisResult<Vec<PathBuf>> {
+        let mut prefix = path_key(dir)?.trim_end_matches(std::path::MAIN_SEPARATOR).to_owned();
+        prefix.push(std::path::MAIN_SEPARATOR);
+
+        let mut paths: Vec<PathBuf> = Vec::new();
+
+        for entry in self.versions.scan_prefix(prefix.as_bytes()) {
+            let (key, _) = entry?;
+            let path = decode_path(&key)?;
+
+            if paths.last() != Some(&path) {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns every path that has at least one recorded revision, in sorted order.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the index cannot be read.
+    pub fn paths(&self) -> CratisResult<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+
+        for entry in self.versions.iter() {
+            let (key, _) = entry?;
+            let path = decode_path(&key)?;
+
+            if paths.last() != Some(&path) {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Removes revisions of a file that were superseded before `cutoff`.
+    ///
+    /// The revision that was current at `cutoff` is kept so the state of the file at `cutoff`
+    /// stays restorable, unless it is a deletion, in which case nothing before `cutoff` is needed.
+    /// All revisions recorded at or after `cutoff` are kept.
+    ///
+    /// # Arguments
+    /// * `path` - The file whose history is pruned
+    /// * `cutoff` - Timestamp before which superseded revisions are removed
+    /// * `dry_run` - If `true`, nothing is removed
+    ///
+    /// # Returns
+    /// The revisions that are kept (oldest first) and the number of removed revisions.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read or written.
+    pub fn prune_versions(&self, path: &Path, cutoff: u64, dry_run: bool) -> CratisResult<(Vec<FileVersion>, usize)> {
+        let entries = self
+            .versions
+            .scan_prefix(path_prefix(path)?)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key, decode_version(value)?))
+            })
+            .collect::<CratisResult<Vec<_>>>()?;
+
+        let before_cutoff = entries.iter().take_while(|(_, v)| v.timestamp < cutoff).count();
+        let keep_from = match entries.get(before_cutoff.wrapping_sub(1)) {
+            Some((_, current)) if !current.is_deleted() => before_cutoff - 1,
+            _ => before_cutoff,
+        };
+
+        if !dry_run {
+            for (key, _) in &entries[..keep_from] {
+                self.versions.remove(key)?;
+            }
+        }
+
+        let kept = entries.into_iter().skip(keep_from).map(|(_, v)| v).collect();
+        Ok((kept, keep_from))
+    }
+
+    /// Records a snapshot.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the snapshot cannot be written.
+    pub fn record_snapshot(&self, record: &SnapshotRecord) -> CratisResult<()> {
+        let sequence = self.db.generate_id()?;
+        let key = [record.timestamp.to_be_bytes(), sequence.to_be_bytes()].concat();
+
+        self.snapshots.insert(key, serde_json::to_vec(record)?)?;
+
+        Ok(())
+    }
+
+    /// Returns every recorded snapshot, oldest first.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn snapshots(&self) -> CratisResult<Vec<SnapshotRecord>> {
+        self.snapshots
+            .iter()
+            .map(|entry| Ok(serde_json::from_slice(&entry?.1)?))
+            .collect()
+    }
+
+    /// Returns the most recently recorded snapshot.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn latest_snapshot(&self) -> CratisResult<Option<SnapshotRecord>> {
+        self.snapshot_at(u64::MAX)
+    }
+
+    /// Returns the most recent snapshot taken at or before the given timestamp.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn snapshot_at(&self, timestamp: u64) -> CratisResult<Option<SnapshotRecord>> {
+        let upper = [timestamp.to_be_bytes(), u64::MAX.to_be_bytes()].concat();
+
+        self.snapshots
+            .range(..=upper)
+            .next_back()
+            .map(|entry| Ok(serde_json::from_slice(&entry?.1)?))
+            .transpose()
+    }
+
+    /// Removes the record of a snapshot.
+    ///
+    /// The manifest object itself is left in the object store.
+    ///
+    /// # Returns
+    /// `true` if a snapshot with the given id was recorded.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read or written.
+    pub fn remove_snapshot(&self, id: &str) -> CratisResult<bool> {
+        for entry in self.snapshots.iter() {
+            let (key, value) = entry?;
+            let record: SnapshotRecord = serde_json::from_slice(&value)?;
+
+            if record.id == id {
+                self.snapshots.remove(key)?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Records that a file was skipped because it is too large, replacing an earlier record.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidPath` if the path is not valid UTF-8
+    /// * `CratisError::DatabaseError` if the record cannot be written
+    pub fn record_skipped(&self, path: &Path, skipped: &SkippedFile) -> CratisResult<()> {
+        self.skipped.insert(path_key(path)?, serde_json::to_vec(skipped)?)?;
+        Ok(())
+    }
+
+    /// Clears the skip record of a file, e.g. once it has been backed up or deleted.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidPath` if the path is not valid UTF-8
+    /// * `CratisError::DatabaseError` if the record cannot be removed
+    pub fn remove_skipped(&self, path: &Path) -> CratisResult<()> {
+        self.skipped.remove(path_key(path)?)?;
+        Ok(())
+    }
+
+    /// Returns every file currently skipped because of its size, sorted by path.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the index cannot be read.
+    pub fn skipped(&self) -> CratisResult<Vec<(PathBuf, SkippedFile)>> {
+        self.skipped
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let path = std::str::from_utf8(&key).map_err(|_| CratisError::Internal("Malformed skipped file key"))?;
+                Ok((PathBuf::from(path), serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    /// Flushes all pending writes to disk.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the flush fails.
+    pub fn flush(&self) -> CratisResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+fn namespaced(name: &str, tree: &str) -> String {
+    if name == DEFAULT_BACKUP_SET {
+        tree.to_string()
+    } else {
+        format!("{NAMESPACE_PREFIX}{name}/{tree}")
+    }
+}
+
+fn path_key(path: &Path) -> CratisResult<&str> {
+    path.to_str().ok_or_else(|| CratisError::InvalidPath(path.to_string_lossy().into_owned()))
+}
+
+fn path_prefix(path: &Path) -> CratisResult<Vec<u8>> {
+    let path_str = path_key(path)?;
+
+    let mut prefix = Vec::with_capacity(path_str.len() + 1);
+    prefix.extend_from_slice(path_str.as_bytes());
+    prefix.push(KEY_SEPARATOR);
+
+    Ok(prefix)
+}
+
+fn version_key(path: &Path, timestamp: u64, sequence: u64) -> CratisResult<Vec<u8>> {
+    let mut key = path_prefix(path)?;
+    key.extend_from_slice(&timestamp.to_be_bytes());
+    key.extend_from_slice(&sequence.to_be_bytes());
+
+    Ok(key)
+}
+
+fn decode_path(key: &[u8]) -> CratisResult<PathBuf> {
+    let end = key
+        .iter()
+        .position(|b| *b == KEY_SEPARATOR)
+        .ok_or(CratisError::Internal("Malformed version index key"))?;
+
+    std::str::from_utf8(&key[..end])
+        .map(PathBuf::from)
+        .map_err(|_| CratisError::Internal("Malformed version index key"))
+}
+
+fn decode_version(value: sled::IVec) -> CratisResult<FileVersion> {
+    Ok(serde_json::from_slice(&value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn version(timestamp: u64, hash: &str) -> FileVersion {
+        FileVersion { timestamp, hash: Some(hash.to_string()), size: 1, mtime: timestamp, action: EventAction::Modify, moved_to: None }
+    }
+
+    fn snapshot(id: &str, timestamp: u64) -> SnapshotRecord {
+        SnapshotRecord { id: id.to_string(), timestamp, mode: BackupMode::Full, files: 1, size: 1 }
+    }
+
+    /// Reopens the index at `path`. sled may hold its file lock for a moment after the last
+    /// handle was dropped, so a failed attempt is retried.
+    fn reopen(path: &Path) -> VersionIndex {
+        for _ in 0..100 {
+            if let Ok(index) = VersionIndex::open(path) {
+                return index;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        VersionIndex::open(path).unwrap()
+    }
+
+    #[test]
+    fn backup_sets_are_isolated() {
+        let dir = TempDir::new().unwrap();
+        let index = VersionIndex::open(dir.path()).unwrap();
+        let photos = index.namespace("photos").unwrap();
+        let path = Path::new("/data/a.txt");
+
+        index.record(path, &version(100, "aa")).unwrap();
+        photos.record(path, &version(200, "bb")).unwrap();
+        photos.record_snapshot(&snapshot("s1", 200)).unwrap();
+        photos.record_skipped(path, &SkippedFile { timestamp: 200, size: 10, limit: 5 }).unwrap();
+
+        assert_eq!(index.name(), DEFAULT_BACKUP_SET);
+        assert_eq!(photos.name(), "photos");
+        assert_eq!(index.versions(path).unwrap(), vec![version(100, "aa")]);
+        assert_eq!(photos.versions(path).unwrap(), vec![version(200, "bb")]);
+
+        assert!(index.snapshots().unwrap().is_empty());
+        assert_eq!(photos.snapshots().unwrap(), vec![snapshot("s1", 200)]);
+        assert!(index.skipped().unwrap().is_empty());
+        assert_eq!(photos.skipped().unwrap().len(), 1);
+
+        assert!(index.namespace("other").unwrap().paths().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backup_sets_survive_reopening() {
+        let dir = TempDir::new().unwrap();
+        let path = Path::new("/data/a.txt");
+
+        {
+            let index = VersionIndex::open(dir.path()).unwrap();
+            let photos = index.namespace("photos").unwrap();
+            photos.record(path, &version(100, "aa")).unwrap();
+            photos.record_snapshot(&snapshot("s1", 100)).unwrap();
+            index.namespace("laptop/default").unwrap();
+            index.flush().unwrap();
+        }
+
+        let index = reopen(dir.path());
+        let mut names = index.namespaces().unwrap();
+        assert_eq!(names.remove(0), DEFAULT_BACKUP_SET);
+        names.sort();
+        assert_eq!(names, vec!["laptop/default", "photos"]);
+
+        let photos = index.namespace("photos").unwrap();
+        assert_eq!(photos.latest(path).unwrap(), Some(version(100, "aa")));
+        assert_eq!(photos.latest_snapshot().unwrap(), Some(snapshot("s1", 100)));
+        assert!(index.latest(path).unwrap().is_none());
+    }
+}
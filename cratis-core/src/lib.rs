@@ -1,3 +1,19 @@
+pub mod client;
+pub mod compress;
 pub mod config;
+pub mod crypto;
 pub mod error;
-pub mod utils;
\ No newline at end of file
+pub mod exclude;
+pub mod index;
+pub mod limits;
+pub mod maintenance;
+pub mod protocol;
+pub mod queue;
+pub mod reconcile;
+pub mod repository;
+pub mod restore;
+pub mod retry;
+pub mod snapshot;
+pub mod store;
+pub mod sync;
+pub mod utils;
@@ -0,0 +1,157 @@
+use std::path::Path;
+use glob::Pattern;
+use crate::config::{BackupConfig, CratisConfig, SizePolicy};
+use crate::error::{CratisError, CratisResult};
+
+/// Policy for files exceeding the limit that match no rule.
+pub const DEFAULT_SIZE_POLICY: SizePolicy = SizePolicy::Warn;
+
+/// The compiled `advanced.max_file_size_mb` limit and its per-pattern policies.
+///
+/// Files up to the limit are always backed up. For larger files the first rule whose pattern
+/// matches the path decides, falling back to `SizePolicy::Warn`. A catch-all rule (`"**"`)
+/// at the end changes the fallback.
+///
+/// ```yaml
+/// advanced:
+///   max_file_size_mb: 1024
+///   large_files:
+///     - pattern: "**/*.iso"
+///       policy: skip
+///     - pattern: "/home/user/Videos/**"
+///       policy: allow
+/// ```
+///
+/// A backup set can override the limit with its own `max_file_size_mb`, its `large_files`
+/// rules are checked before the global ones.
+#[derive(Debug, Clone, Default)]
+pub struct SizeLimit {
+    max_bytes: Option<u64>,
+    rules: Vec<(Pattern, SizePolicy)>,
+}
+
+impl SizeLimit {
+    /// Creates a limit of `max_bytes` with the given rules, in priority order.
+    pub fn new(max_bytes: Option<u64>, rules: Vec<(Pattern, SizePolicy)>) -> Self {
+        Self { max_bytes, rules }
+    }
+
+    /// A limit that allows files of any size.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Compiles the limit and rules of a backup set from its `max_file_size_mb` and
+    /// `large_files`, falling back to `advanced.max_file_size_mb` and `advanced.large_files`.
+    ///
+    /// # Errors
+    /// Returns `CratisError::ConfigError` if a rule pattern is not a valid glob.
+    pub fn from_config(config: &CratisConfig, backup: &BackupConfig) -> CratisResult<Self> {
+        let advanced = config.advanced.as_ref();
+        let global_rules = advanced.and_then(|advanced| advanced.large_files.as_ref());
+
+        let mut rules = Vec::new();
+        for rule in backup.large_files.iter().chain(global_rules).flatten() {
+            let pattern = Pattern::new(&rule.pattern)
+                .map_err(|e| CratisError::ConfigError(format!("Invalid large file pattern '{}': {}", rule.pattern, e)))?;
+            rules.push((pattern, rule.policy));
+        }
+
+        let max_file_size_mb = backup.max_file_size_mb.or(advanced.and_then(|advanced| advanced.max_file_size_mb));
+        let max_bytes = max_file_size_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+        Ok(Self::new(max_bytes, rules))
+    }
+
+    /// Returns the limit in bytes, if any.
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    /// Decides how to treat a file of the given size.
+    ///
+    /// # Returns
+    /// `SizePolicy::Allow` for files within the limit, otherwise the policy of the first
+    /// matching rule or `DEFAULT_SIZE_POLICY`.
+    pub fn decide(&self, path: &Path, size: u64) -> SizePolicy {
+        match self.max_bytes {
+            Some(max_bytes) if size > max_bytes => self
+                .rules
+                .iter()
+                .find(|(pattern, _)| pattern.matches_path(path))
+                .map(|(_, policy)| *policy)
+                .unwrap_or(DEFAULT_SIZE_POLICY),
+            _ => SizePolicy::Allow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// Parses a configuration with the given `advanced` section and a backup set with the given
+    /// extra settings, without validating it.
+    fn config(advanced: &str, backup: &str) -> CratisConfig {
+        let source = format!(
+            "client: {{ id: laptop, name: Laptop }}\n\
+             backup: {{ mode: full, watch_directories: [/data]{backup} }}\n\
+             server: {{ address: http://localhost:8080, auth_token: secret }}\n\
+             advanced: {{ {advanced} }}\n"
+        );
+        serde_yaml::from_str(&source).unwrap()
+    }
+
+    fn limit(config: &CratisConfig) -> CratisResult<SizeLimit> {
+        SizeLimit::from_config(config, config.backup.as_ref().unwrap())
+    }
+
+    #[test]
+    fn limits_are_read_in_megabytes() {
+        let global = config("max_file_size_mb: 5", "");
+        assert_eq!(limit(&global).unwrap().max_bytes(), Some(5 * MB));
+
+        let overridden = config("max_file_size_mb: 5", ", max_file_size_mb: 2");
+        assert_eq!(limit(&overridden).unwrap().max_bytes(), Some(2 * MB));
+
+        assert_eq!(limit(&config("max_file_size_mb: 18446744073709551615", "")).unwrap().max_bytes(), Some(u64::MAX));
+        assert_eq!(limit(&config("", "")).unwrap().max_bytes(), None);
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let source = "client: { id: laptop, name: Laptop }\nbackup: { mode: full, watch_directories: [/data] }\n\
+                      server: { address: http://localhost:8080, auth_token: secret }\nadvanced: { max_file_size_mb: 10MB }\n";
+        assert!(serde_yaml::from_str::<CratisConfig>(source).is_err());
+
+        let invalid = config("max_file_size_mb: 1, large_files: [{ pattern: '**/[.iso', policy: skip }]", "");
+        assert!(matches!(limit(&invalid), Err(CratisError::ConfigError(message)) if message.contains("**/[.iso")));
+    }
+
+    #[test]
+    fn files_up_to_the_limit_are_allowed() {
+        let limit = SizeLimit::new(Some(MB), Vec::new());
+        let path = Path::new("/data/video.mp4");
+
+        assert_eq!(limit.decide(path, 0), SizePolicy::Allow);
+        assert_eq!(limit.decide(path, MB), SizePolicy::Allow);
+        assert_eq!(limit.decide(path, MB + 1), DEFAULT_SIZE_POLICY);
+        assert_eq!(SizeLimit::unlimited().decide(path, u64::MAX), SizePolicy::Allow);
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides() {
+        let config = config(
+            "max_file_size_mb: 1, large_files: [{ pattern: '**/*.iso', policy: allow }, { pattern: '**', policy: skip }]",
+            ", large_files: [{ pattern: '/data/images/**', policy: warn }]",
+        );
+        let limit = limit(&config).unwrap();
+        let size = 2 * MB;
+
+        assert_eq!(limit.decide(Path::new("/data/images/disk.iso"), size), SizePolicy::Warn);
+        assert_eq!(limit.decide(Path::new("/data/disk.iso"), size), SizePolicy::Allow);
+        assert_eq!(limit.decide(Path::new("/data/video.mp4"), size), SizePolicy::Skip);
+        assert_eq!(limit.decide(Path::new("/data/video.mp4"), MB), SizePolicy::Allow);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+use crate::error::{CratisError, CratisResult};
+use crate::index::VersionIndex;
+use crate::repository::Repository;
+use crate::snapshot::load_snapshot;
+use crate::utils::HashingWriter;
+
+/// Retention settings for `prune`.
+#[derive(Debug, Clone, Default)]
+pub struct PruneOptions {
+    /// Number of most recent snapshots to keep. `None` keeps all snapshots.
+    pub keep_snapshots: Option<usize>,
+    /// Timestamp before which superseded file revisions are removed. `None` keeps all revisions.
+    pub keep_versions_since: Option<u64>,
+    /// Only report what would be removed.
+    pub dry_run: bool,
+}
+
+/// What `prune` removed (or would remove in a dry run).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub snapshots_removed: usize,
+    pub versions_removed: usize,
+    pub files_removed: usize,
+    pub objects_removed: usize,
+    pub bytes_freed: u64,
+}
+
+/// The result of `verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub files_checked: usize,
+    pub snapshots_checked: usize,
+    /// Every file or snapshot that failed verification, by digest or snapshot id.
+    pub errors: Vec<(String, CratisError)>,
+}
+
+/// Applies the retention settings to a backup set and deletes every object no longer referenced.
+///
+/// Only the backup set of `repo.index` is pruned (see `Repository::backup_set`). Objects are
+/// shared between backup sets, so everything still referenced by another set is kept.
+///
+/// 1. Keeps the `keep_snapshots` most recent snapshots plus every snapshot their incremental
+///    chains depend on, and removes all other snapshot records
+/// 2. Removes file revisions superseded before `keep_versions_since` (see `VersionIndex::prune_versions`)
+/// 3. Deletes every file recipe, chunk and manifest that is no longer reachable from the
+///    remaining revisions and snapshots of any backup set
+///
+/// Must not run while a watcher writes into the same repository, since objects stored by the
+/// watcher but not yet recorded in the index would be considered unreachable.
+///
+/// # Errors
+/// Returns an error if the index or the object store cannot be read or written. A snapshot
+/// manifest that cannot be loaded aborts the prune, since the objects it references could
+/// otherwise be deleted.
+///
+/// # Examples
+/// ```ignore
+/// let options = PruneOptions { keep_snapshots: Some(30), keep_versions_since: Some(now - 90 * 86400), dry_run: true };
+/// let report = prune(&repo, &options)?;
+/// ```
+pub fn prune(repo: &Repository, options: &PruneOptions) -> CratisResult<PruneReport> {
+    let mut report = PruneReport::default();
+
+    let records = repo.index.snapshots()?;
+    let keep_from = options.keep_snapshots.map(|n| records.len().saturating_sub(n)).unwrap_or(0);
+
+    let mut kept_snapshots: BTreeSet<String> = BTreeSet::new();
+    let mut reachable_files: HashSet<String> = HashSet::new();
+    for record in &records[keep_from..] {
+        let mut next = Some(record.id.clone());
+        while let Some(id) = next {
+            if !kept_snapshots.insert(id.clone()) {
+                break;
+            }
+
+            let snapshot = load_snapshot(repo, &id)?;
+            reachable_files.extend(snapshot.entries.into_iter().map(|entry| entry.hash));
+            next = snapshot.parent;
+        }
+    }
+
+    for record in &records {
+        if !kept_snapshots.contains(&record.id) {
+            report.snapshots_removed += 1;
+            if !options.dry_run {
+                repo.index.remove_snapshot(&record.id)?;
+            }
+        }
+    }
+
+    for path in repo.index.paths()? {
+        let cutoff = options.keep_versions_since.unwrap_or(0);
+        let (kept, removed) = repo.index.prune_versions(&path, cutoff, options.dry_run)?;
+
+        report.versions_removed += removed;
+        reachable_files.extend(kept.into_iter().filter_map(|version| version.hash));
+    }
+
+    let mut reachable_objects: HashSet<String> = kept_snapshots.into_iter().collect();
+    for name in repo.index.namespaces()? {
+        if name != repo.index.name() {
+            let index = repo.index.namespace(&name)?;
+            referenced(repo, &index, &mut reachable_files, &mut reachable_objects)?;
+        }
+    }
+
+    for hash in repo.store.file_hashes()? {
+        if reachable_files.contains(&hash) {
+            let recipe = repo.store.file_recipe(&hash)?;
+            reachable_objects.extend(recipe.chunks.into_iter().map(|chunk| chunk.hash));
+            continue;
+        }
+
+        report.files_removed += 1;
+        report.bytes_freed += if options.dry_run { repo.store.file_size(&hash)? } else { repo.store.remove_file(&hash)? };
+    }
+
+    for hash in repo.store.object_hashes()? {
+        if reachable_objects.contains(&hash) {
+            continue;
+        }
+
+        report.objects_removed += 1;
+        report.bytes_freed += if options.dry_run { repo.store.object_size(&hash)? } else { repo.store.remove_object(&hash)? };
+    }
+
+    if !options.dry_run {
+        repo.index.flush()?;
+    }
+
+    Ok(report)
+}
+
+/// Collects every file and manifest referenced by the revisions and snapshots of an index.
+///
+/// # Errors
+/// Returns an error if the index cannot be read or a snapshot manifest cannot be loaded.
+fn referenced(repo: &Repository, index: &VersionIndex, files: &mut HashSet<String>, objects: &mut HashSet<String>) -> CratisResult<()> {
+    for record in index.snapshots()? {
+        let snapshot = load_snapshot(repo, &record.id)?;
+        files.extend(snapshot.entries.into_iter().map(|entry| entry.hash));
+        objects.insert(record.id);
+    }
+
+    for path in index.paths()? {
+        files.extend(index.versions(&path)?.into_iter().filter_map(|version| version.hash));
+    }
+
+    Ok(())
+}
+
+/// Checks the integrity of the repository.
+///
+/// Reassembles every file referenced by the version index or a snapshot of any backup set and
+/// checks every chunk and the whole file against their digests, and checks that every snapshot
+/// manifest can be loaded.
+///
+/// # Errors
+/// Returns an error only if the index cannot be read. Integrity problems are reported in
+/// `VerifyReport::errors`.
+pub fn verify(repo: &Repository) -> CratisResult<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut files: BTreeSet<String> = BTreeSet::new();
+
+    for name in repo.index.namespaces()? {
+        let index = repo.index.namespace(&name)?;
+
+        for record in index.snapshots()? {
+            report.snapshots_checked += 1;
+            match load_snapshot(repo, &record.id) {
+                Ok(snapshot) => files.extend(snapshot.entries.into_iter().map(|entry| entry.hash)),
+                Err(e) => report.errors.push((record.id, e)),
+            }
+        }
+
+        for path in index.paths()? {
+            files.extend(index.versions(&path)?.into_iter().filter_map(|version| version.hash));
+        }
+    }
+
+    for hash in files {
+        report.files_checked += 1;
+        if let Err(e) = verify_file(repo, &hash) {
+            report.errors.push((hash, e));
+        }
+    }
+
+    Ok(report)
+}
+
+/// Reassembles a stored file and checks it against its digest.
+///
+/// # Errors
+/// Returns `CratisError::BackupFailure` if the content does not match, or any error raised
+/// while reading the file.
+pub fn verify_file(repo: &Repository, hash: &str) -> CratisResult<()> {
+    let mut writer = HashingWriter::new(io::sink(), repo.store.hasher());
+    repo.store.read_file(hash, &mut writer)?;
+
+    if writer.finish().1 != hash {
+        return Err(CratisError::BackupFailure("Stored file does not match its hash"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::config::BackupMode;
+    use crate::snapshot::take_snapshot;
+    use crate::sync::SyncEngine;
+    use tempfile::TempDir;
+
+    #[test]
+    fn prune_removes_superseded_incremental_chains() {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+        let roots = vec![data.to_string_lossy().into_owned()];
+        let engine = SyncEngine::new(Repository::open(dir.path().join("repo")).unwrap());
+
+        for i in 0..6 {
+            fs::write(data.join("notes.txt"), format!("revision {i}")).unwrap();
+            take_snapshot(&engine, &roots, 1000 + i, BackupMode::Incremental, 3, |_| true).unwrap();
+        }
+
+        let repo = engine.repository();
+        let options = PruneOptions { keep_snapshots: Some(1), ..PruneOptions::default() };
+        let report = prune(repo, &options).unwrap();
+
+        // The latest snapshot is the last of the second chain, the whole first chain goes.
+        assert_eq!(report.snapshots_removed, 3);
+        assert_eq!(repo.index.snapshots().unwrap().len(), 3);
+        assert!(verify(repo).unwrap().errors.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use crate::config::DEFAULT_BACKUP_SET;
+use crate::index::{FileVersion, SnapshotRecord};
+
+/// Prefix of every endpoint of the cratis-api server.
+///
+/// ```text
+/// POST /api/v1/objects/missing       MissingRequest -> MissingResponse (chunks and manifests)
+/// GET  /api/v1/objects/{hash}        encoded object
+/// PUT  /api/v1/objects/{hash}        encoded object
+/// POST /api/v1/files/missing         MissingRequest -> MissingResponse (file recipes)
+/// GET  /api/v1/files/{hash}          encoded file recipe
+/// PUT  /api/v1/files/{hash}          encoded file recipe
+/// GET  /api/v1/files/{hash}/content  reassembled file contents, streamed
+/// POST /api/v1/commit                CommitRequest -> CommitResponse
+/// GET  /api/v1/snapshots?set={set}   Vec<SnapshotRecord>
+/// ```
+///
+/// Objects and recipes are transferred in their encoded form (see `ObjectStore::get_encoded`),
+/// so the server never sees the plaintext of an encrypted repository. Every request has to carry
+/// the configured `server.auth_token` as `Authorization: Bearer <token>`.
+///
+/// Objects of unencrypted repositories are checked against their digest on upload and shared by
+/// all clients of a server. Objects of encrypted repositories cannot be checked without the
+/// key, so they are kept apart for every client (see `ENCRYPTED_HEADER`). Commits and snapshot
+/// listings belong to the client named in the `CLIENT_HEADER` and to a backup set of that
+/// client, so neither clients nor backup sets backing up the same paths ever see or overwrite
+/// each other's history.
+pub const API_PREFIX: &str = "/api/v1";
+
+/// Header carrying the `client.id` of the sending client on every request.
+pub const CLIENT_HEADER: &str = "x-cratis-client";
+
+/// Header telling the server whether the objects of a request belong to an encrypted
+/// repository, `true` or `false`. Requests without it are treated as unencrypted.
+pub const ENCRYPTED_HEADER: &str = "x-cratis-encrypted";
+
+/// A list of digests, asking the server which of them it does not store yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MissingRequest {
+    pub hashes: Vec<String>,
+}
+
+/// The subset of the requested digests the server does not store.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MissingResponse {
+    pub missing: Vec<String>,
+}
+
+/// A file revision to record in the server's version index.
+///
+/// For encrypted repositories `path`, as well as the rename paths in `version`, are keyed
+/// digests rather than real paths (see `client::remote_path`), so file names stay private.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionEntry {
+    pub path: String,
+    pub version: FileVersion,
+}
+
+/// Records file revisions and optionally a snapshot whose objects have been uploaded.
+///
+/// The server rejects the whole commit if a revision refers to a file recipe it does not store,
+/// or if the manifest object of the snapshot is missing. Everything is recorded in the history
+/// of the backup set `set`, which defaults to `DEFAULT_BACKUP_SET`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitRequest {
+    #[serde(default = "default_set")]
+    pub set: String,
+    pub versions: Vec<VersionEntry>,
+    pub snapshot: Option<SnapshotRecord>,
+}
+
+impl Default for CommitRequest {
+    fn default() -> Self {
+        Self { set: default_set(), versions: Vec::new(), snapshot: None }
+    }
+}
+
+/// Query of `GET /snapshots`, selecting the backup set whose snapshots are listed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotsQuery {
+    #[serde(default = "default_set")]
+    pub set: String,
+}
+
+/// Summary of an accepted commit.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitResponse {
+    pub versions: usize,
+    pub snapshot: Option<String>,
+}
+
+/// The body of every error response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+fn default_set() -> String {
+    DEFAULT_BACKUP_SET.to_string()
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use crate::error::{CratisError, CratisResult};
+use crate::index::VersionIndex;
+use crate::utils::EventAction;
+
+const QUEUE_TREE: &str = "queue";
+const MOVES_TREE: &str = "queue_moves";
+
+/// A durable queue of filesystem events waiting to be backed up.
+///
+/// Events are stored in a sled tree next to the version index, keyed by path, with every
+/// distinct action reported for the path since it was last backed up. Events survive a crash or
+/// restart of the watcher and are replayed on startup.
+///
+/// The intended flow is:
+/// 1. `push` every incoming event and `flush` before acknowledging it
+/// 2. Take a `batch` once the debounce window has passed and sync it
+/// 3. `complete` every path that was backed up
+///
+/// `complete` only removes a path if no new event arrived for it since the batch was taken, so
+/// a change made while the batch was being synced is never lost.
+///
+/// Renames the watcher paired up are queued separately with `push_move`, in the order they
+/// happened, since applying them out of order would attach history to the wrong path.
+#[derive(Debug, Clone)]
+pub struct WorkQueue {
+    db: sled::Db,
+    tree: sled::Tree,
+    moves: sled::Tree,
+}
+
+/// A consistent view of the queue, taken by `WorkQueue::batch`.
+#[derive(Debug, Default)]
+pub struct QueuedBatch {
+    /// The queued events in the shape expected by `SyncEngine::sync_batch`.
+    pub events: HashSet<(PathBuf, EventAction)>,
+    /// The queued moves as `(from, to)`, oldest first, as expected by `SyncEngine::sync_moves`.
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    entries: HashMap<PathBuf, sled::IVec>,
+    move_keys: Vec<sled::IVec>,
+}
+
+impl QueuedBatch {
+    /// Returns `true` if the batch contains no events and no moves.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.moves.is_empty()
+    }
+}
+
+impl WorkQueue {
+    /// Opens the work queue stored in the database of the given version index.
+    ///
+    /// Every backup set has its own queue, in the namespace of its index.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the queue tree cannot be opened.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let queue = WorkQueue::open(&repo.index)?;
+    /// queue.push(Path::new("/home/user/notes.txt"), EventAction::Modify)?;
+    /// queue.flush()?;
+    /// ```
+    pub fn open(index: &VersionIndex) -> CratisResult<Self> {
+        let db = index.db().clone();
+        let tree = db.open_tree(index.tree_name(QUEUE_TREE))?;
+        let moves = db.open_tree(index.tree_name(MOVES_TREE))?;
+        Ok(Self { db, tree, moves })
+    }
+
+    /// Adds an event to the queue.
+    ///
+    /// The event is only guaranteed to be on disk after the next `flush`.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidPath` if the path is not valid UTF-8
+    /// * `CratisError::DatabaseError` if the event cannot be written
+    pub fn push(&self, path: &Path, action: EventAction) -> CratisResult<()> {
+        let key = queue_key(path)?;
+
+        loop {
+            let current = self.tree.get(key)?;
+            let mut actions: Vec<EventAction> = match &current {
+                Some(value) => serde_json::from_slice(value)?,
+                None => Vec::new(),
+            };
+
+            if actions.contains(&action) {
+                return Ok(());
+            }
+            actions.push(action.clone());
+
+            let updated = serde_json::to_vec(&actions)?;
+            if self.tree.compare_and_swap(key, current, Some(updated))?.is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Adds a move to the queue.
+    ///
+    /// Only the move itself is queued. The caller should also `push` a deletion of `from` and
+    /// a creation of `to`, which settle both paths should the move not be recorded.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the move cannot be written.
+    pub fn push_move(&self, from: &Path, to: &Path) -> CratisResult<()> {
+        // Generated ids only grow, so the tree iterates in the order moves were pushed.
+        let key = self.db.generate_id()?.to_be_bytes();
+        self.moves.insert(key, serde_json::to_vec(&(from, to))?)?;
+        Ok(())
+    }
+
+    /// Returns every queued event without removing it.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` or `CratisError::SerializationError` if the queue
+    /// cannot be read.
+    pub fn batch(&self) -> CratisResult<QueuedBatch> {
+        let mut batch = QueuedBatch::default();
+
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            let path = std::str::from_utf8(&key)
+                .map(PathBuf::from)
+                .map_err(|_| CratisError::Internal("Malformed work queue key"))?;
+
+            let actions: Vec<EventAction> = serde_json::from_slice(&value)?;
+            batch.events.extend(actions.into_iter().map(|action| (path.clone(), action)));
+            batch.entries.insert(path, value);
+        }
+
+        for entry in self.moves.iter() {
+            let (key, value) = entry?;
+            batch.moves.push(serde_json::from_slice(&value)?);
+            batch.move_keys.push(key);
+        }
+
+        Ok(batch)
+    }
+
+    /// Removes a path that was backed up as part of `batch`.
+    ///
+    /// # Returns
+    /// `false` if a new event was queued for the path since the batch was taken, in which case
+    /// the path stays queued.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the queue cannot be written.
+    pub fn complete(&self, batch: &QueuedBatch, path: &Path) -> CratisResult<bool> {
+        let Some(expected) = batch.entries.get(path) else {
+            return Ok(false);
+        };
+
+        Ok(self.tree.compare_and_swap(queue_key(path)?, Some(expected), None::<&[u8]>)?.is_ok())
+    }
+
+    /// Removes the moves of `batch` once they were synced.
+    ///
+    /// Moves are never retried, a move that failed to be recorded is settled by the events
+    /// queued alongside it.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the queue cannot be written.
+    pub fn complete_moves(&self, batch: &QueuedBatch) -> CratisResult<()> {
+        for key in &batch.move_keys {
+            self.moves.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if no events or moves are queued.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty() && self.moves.is_empty()
+    }
+
+    /// Returns the number of queued paths and moves.
+    pub fn len(&self) -> usize {
+        self.tree.len() + self.moves.len()
+    }
+
+    /// Writes all queued events to disk.
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the flush fails.
+    pub fn flush(&self) -> CratisResult<()> {
+        self.tree.flush()?;
+        self.moves.flush()?;
+        Ok(())
+    }
+}
+
+fn queue_key(path: &Path) -> CratisResult<&str> {
+    path.to_str().ok_or_else(|| CratisError::InvalidPath(path.to_string_lossy().into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// Opens the queue of the index at `path`. sled may hold its file lock for a moment after
+    /// the last handle was dropped, so a failed attempt is retried.
+    fn open(path: &Path) -> WorkQueue {
+        for _ in 0..100 {
+            if let Ok(index) = VersionIndex::open(path) {
+                return WorkQueue::open(&index).unwrap();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        WorkQueue::open(&VersionIndex::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn queued_events_survive_reopening() {
+        let dir = TempDir::new().unwrap();
+        let (a, b, c) = (Path::new("/data/a.txt"), Path::new("/data/b.txt"), Path::new("/data/c.txt"));
+
+        {
+            let queue = open(dir.path());
+            queue.push(a, EventAction::Create).unwrap();
+            queue.push(a, EventAction::Modify).unwrap();
+            queue.push(a, EventAction::Modify).unwrap();
+            queue.push(b, EventAction::Delete).unwrap();
+            queue.push_move(b, c).unwrap();
+            queue.flush().unwrap();
+        }
+
+        let queue = open(dir.path());
+        assert_eq!(queue.len(), 3);
+
+        let batch = queue.batch().unwrap();
+        let expected: HashSet<_> = [(a.to_path_buf(), EventAction::Create), (a.to_path_buf(), EventAction::Modify), (b.to_path_buf(), EventAction::Delete)].into();
+        assert_eq!(batch.events, expected);
+        assert_eq!(batch.moves, vec![(b.to_path_buf(), c.to_path_buf())]);
+    }
+
+    #[test]
+    fn completed_paths_are_removed_unless_they_changed() {
+        let dir = TempDir::new().unwrap();
+        let (a, b) = (Path::new("/data/a.txt"), Path::new("/data/b.txt"));
+
+        {
+            let queue = open(dir.path());
+            queue.push(a, EventAction::Modify).unwrap();
+            queue.push(b, EventAction::Modify).unwrap();
+            queue.push_move(a, b).unwrap();
+
+            let batch = queue.batch().unwrap();
+            queue.push(b, EventAction::Delete).unwrap();
+
+            assert!(queue.complete(&batch, a).unwrap());
+            assert!(!queue.complete(&batch, b).unwrap());
+            assert!(!queue.complete(&batch, Path::new("/data/unqueued.txt")).unwrap());
+            queue.complete_moves(&batch).unwrap();
+            queue.flush().unwrap();
+        }
+
+        let queue = open(dir.path());
+        let batch = queue.batch().unwrap();
+        let expected: HashSet<_> = [(b.to_path_buf(), EventAction::Modify), (b.to_path_buf(), EventAction::Delete)].into();
+        assert_eq!(batch.events, expected);
+        assert!(batch.moves.is_empty());
+
+        assert!(queue.complete(&batch, b).unwrap());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn every_backup_set_has_its_own_queue() {
+        let dir = TempDir::new().unwrap();
+        let index = VersionIndex::open(dir.path()).unwrap();
+        let default = WorkQueue::open(&index).unwrap();
+        let photos = WorkQueue::open(&index.namespace("photos").unwrap()).unwrap();
+
+        photos.push(Path::new("/data/a.jpg"), EventAction::Create).unwrap();
+        assert!(default.is_empty());
+        assert_eq!(photos.len(), 1);
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use crate::error::{CratisError, CratisResult};
+use crate::repository::Repository;
+use crate::snapshot::walk_tree;
+use crate::utils::{modified_secs, EventAction};
+
+/// Compares the watched directories against the version index and returns the differences.
+///
+/// Used on startup to catch changes made while the watcher was not running. For every file
+/// below `roots` that passes `include`:
+/// * No live revision recorded → `Create`
+/// * Size or modification time differ from the latest revision → `Modify`, unless only the
+///   modification time differs and the content hash still matches
+///
+/// Every directory below `roots` that passes `include` and has no live directory revision is
+/// reported as `CreateDir`, like the watcher records directories created while it runs. Every
+/// path below `roots` with a live revision in the index that no longer exists on disk is
+/// reported as `Delete`, or `DeleteDir` if it was recorded as a directory.
+///
+/// The result has the same shape as a debounced watcher batch and is meant to be passed to
+/// `SyncEngine::sync_batch`.
+///
+/// # Arguments
+/// * `repo` - The repository holding the version index
+/// * `roots` - The watched directories, usually `BackupConfig::watch_directories`
+/// * `include` - Filter deciding which paths are backed up
+///
+/// # Errors
+/// Returns an error if a root cannot be read or the index cannot be queried.
+///
+/// # Examples
+/// ```ignore
+/// let batch = reconcile(engine.repository(), &config.backup.watch_directories, |_| true)?;
+/// engine.sync_batch(&batch);
+/// ```
+pub fn reconcile<F>(repo: &Repository, roots: &[String], include: F) -> CratisResult<HashSet<(PathBuf, EventAction)>>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut batch = HashSet::new();
+    let mut on_disk = HashSet::new();
+
+    for root in roots {
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        walk_tree(Path::new(root), &include, &mut files, &mut dirs)?;
+
+        for dir in dirs {
+            on_disk.insert(dir.clone());
+            if !repo.index.latest(&dir)?.is_some_and(|v| v.action == EventAction::CreateDir) {
+                batch.insert((dir, EventAction::CreateDir));
+            }
+        }
+
+        for (path, metadata) in files {
+            on_disk.insert(path.clone());
+
+            let latest = repo.index.latest(&path)?.filter(|v| !v.is_deleted());
+            let action = match latest {
+                None => Some(EventAction::Create),
+                Some(v) if v.size != metadata.len() => Some(EventAction::Modify),
+                Some(v) if v.mtime != modified_secs(&metadata) => match repo.store.hash_file(&path) {
+                    Ok(hash) if v.hash.as_deref() == Some(hash.as_str()) => None,
+                    Ok(_) => Some(EventAction::Modify),
+                    Err(CratisError::IoError(e)) if e.kind() == ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                },
+                Some(_) => None,
+            };
+
+            if let Some(action) = action {
+                batch.insert((path, action));
+            }
+        }
+    }
+
+    for path in repo.index.paths()? {
+        if on_disk.contains(&path) || !roots.iter().any(|root| path.starts_with(root)) || !include(&path) {
+            continue;
+        }
+
+        if path.exists() {
+            continue;
+        }
+
+        if let Some(latest) = repo.index.latest(&path)?.filter(|v| !v.is_deleted()) {
+            let action = if latest.is_directory() { EventAction::DeleteDir } else { EventAction::Delete };
+            batch.insert((path, action));
+        }
+    }
+
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::sync::SyncEngine;
+
+    /// A repository in `<dir>/repo` and a watched directory `<dir>/data`.
+    fn setup() -> (TempDir, SyncEngine, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+
+        let engine = SyncEngine::new(Repository::open(dir.path().join("repo")).unwrap());
+        (dir, engine, data)
+    }
+
+    fn run(engine: &SyncEngine, data: &Path) -> HashSet<(PathBuf, EventAction)> {
+        reconcile(engine.repository(), &[data.to_string_lossy().into_owned()], |_| true).unwrap()
+    }
+
+    #[test]
+    fn new_files_and_directories_are_reported() {
+        let (_dir, engine, data) = setup();
+        fs::create_dir_all(data.join("empty")).unwrap();
+        fs::create_dir_all(data.join("sub").join("deeper")).unwrap();
+        fs::write(data.join("sub").join("a.txt"), "a").unwrap();
+
+        let batch = run(&engine, &data);
+        let expected = HashSet::from([
+            (data.join("empty"), EventAction::CreateDir),
+            (data.join("sub"), EventAction::CreateDir),
+            (data.join("sub").join("deeper"), EventAction::CreateDir),
+            (data.join("sub").join("a.txt"), EventAction::Create),
+        ]);
+        assert_eq!(batch, expected);
+
+        assert!(engine.sync_batch(&batch).iter().all(|result| result.outcome.is_ok()));
+        assert!(engine.repository().index.latest(&data.join("empty")).unwrap().unwrap().is_directory());
+        assert!(run(&engine, &data).is_empty());
+    }
+
+    #[test]
+    fn removed_files_and_directories_are_reported() {
+        let (_dir, engine, data) = setup();
+        fs::create_dir_all(data.join("empty")).unwrap();
+        fs::create_dir_all(data.join("sub")).unwrap();
+        fs::write(data.join("sub").join("a.txt"), "a").unwrap();
+        fs::write(data.join("kept.txt"), "kept").unwrap();
+        engine.sync_batch(&run(&engine, &data));
+
+        fs::remove_dir(data.join("empty")).unwrap();
+        fs::remove_dir_all(data.join("sub")).unwrap();
+
+        let expected = HashSet::from([
+            (data.join("empty"), EventAction::DeleteDir),
+            (data.join("sub"), EventAction::DeleteDir),
+            (data.join("sub").join("a.txt"), EventAction::Delete),
+        ]);
+        assert_eq!(run(&engine, &data), expected);
+    }
+
+    #[test]
+    fn excluded_directories_are_not_reported() {
+        let (_dir, engine, data) = setup();
+        fs::create_dir_all(data.join("target").join("debug")).unwrap();
+        fs::write(data.join("target").join("debug").join("app"), "binary").unwrap();
+
+        let root = data.to_string_lossy().into_owned();
+        let batch = reconcile(engine.repository(), &[root], |path| !path.ends_with("target")).unwrap();
+        assert!(batch.is_empty());
+    }
+}
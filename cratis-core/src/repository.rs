@@ -0,0 +1,200 @@
+use std::path::{Path, PathBuf};
+use crate::compress::Compression;
+use crate::config::CratisConfig;
+use crate::crypto::KeyFile;
+use crate::error::{CratisError, CratisResult};
+use crate::index::VersionIndex;
+use crate::store::ObjectStore;
+
+const INDEX_DIR: &str = "index";
+const KEY_FILE: &str = "keyfile";
+
+/// The on-disk backup repository: an object store and the version index describing it.
+///
+/// ```text
+/// <root>/objects/   content-addressed objects (see `ObjectStore`)
+/// <root>/index/     sled database (see `VersionIndex`)
+/// <root>/keyfile     passphrase-protected repository key, only for encrypted repositories
+/// ```
+///
+/// An encrypted repository has to be unlocked with its passphrase before objects can be read
+/// or written. Encryption covers the objects only, i.e. file chunks, file recipes and snapshot
+/// manifests. The version index is stored in plaintext, so the names, sizes and modification
+/// times of backed up files are readable by anyone with access to `<root>/index/`.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    root: PathBuf,
+    pub store: ObjectStore,
+    pub index: VersionIndex,
+}
+
+impl Repository {
+    /// Opens the repository at the given directory, creating it if it does not exist yet.
+    ///
+    /// # Arguments
+    /// * `root` - The repository directory
+    ///
+    /// # Errors
+    /// * `CratisError::IoError` if the object store layout cannot be created
+    /// * `CratisError::DatabaseError` if the version index cannot be opened
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let repo = Repository::open(config.storage_path())?;
+    /// let versions = repo.index.versions(Path::new("/home/user/notes.txt"))?;
+    /// ```
+    pub fn open<P: AsRef<Path>>(root: P) -> CratisResult<Self> {
+        let root = root.as_ref().to_path_buf();
+        let store = ObjectStore::open(&root)?;
+        let index = VersionIndex::open(root.join(INDEX_DIR))?;
+
+        Ok(Self { root, store, index })
+    }
+
+    /// Opens the repository described by the configuration.
+    ///
+    /// Applies the configured compression and, for encrypted repositories, unlocks the
+    /// repository with the configured passphrase (see `CratisConfig::passphrase`).
+    ///
+    /// # Errors
+    /// * `CratisError::AuthFailure` if the repository is encrypted and no passphrase is configured
+    ///   or the passphrase is wrong
+    /// * Any error returned by `open`
+    pub fn open_configured(config: &CratisConfig) -> CratisResult<Self> {
+        let repo = Self::open(config.storage_path())?.with_compression(config.compression());
+
+        if !repo.is_encrypted() {
+            return Ok(repo);
+        }
+
+        match config.passphrase()? {
+            Some(passphrase) => repo.unlock(&passphrase),
+            None => Err(CratisError::AuthFailure("Repository is encrypted but no passphrase is configured")),
+        }
+    }
+
+    /// Returns the repository scoped to the given backup set.
+    ///
+    /// The object store is shared by all backup sets, so content backed up by several sets is
+    /// stored once, while the index is switched to the namespace of the set (see
+    /// `VersionIndex::namespace`).
+    ///
+    /// # Errors
+    /// Returns `CratisError::DatabaseError` if the namespace cannot be opened.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let photos = Repository::open_configured(&config)?.backup_set("photos")?;
+    /// let engine = SyncEngine::new(photos);
+    /// ```
+    pub fn backup_set(&self, name: &str) -> CratisResult<Self> {
+        Ok(Self { root: self.root.clone(), store: self.store.clone(), index: self.index.namespace(name)? })
+    }
+
+    /// Sets the compression applied to objects written into the repository.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.store = self.store.with_compression(compression);
+        self
+    }
+
+    /// Returns the repository directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns `true` if the repository has a key file, i.e. its objects are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.key_file_path().is_file()
+    }
+
+    /// Turns a new, empty repository into an encrypted one protected by the given passphrase.
+    ///
+    /// Creates the key file and unlocks the repository with the newly generated key.
+    ///
+    /// # Errors
+    /// * `CratisError::ConfigError` if the repository is already encrypted or already contains
+    ///   objects or file recipes
+    /// * `CratisError::IoError` if the key file cannot be written
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let repo = Repository::open("/var/lib/cratis")?.init_encryption("correct horse battery staple")?;
+    /// ```
+    pub fn init_encryption(self, passphrase: &str) -> CratisResult<Self> {
+        if self.is_encrypted() {
+            return Err(CratisError::ConfigError(format!("Repository {} is already encrypted", self.root.display())));
+        }
+
+        if !self.store.object_hashes()?.is_empty() || !self.store.file_hashes()?.is_empty() {
+            return Err(CratisError::ConfigError(format!(
+                "Repository {} already contains unencrypted objects",
+                self.root.display()
+            )));
+        }
+
+        let (key_file, key) = KeyFile::create(passphrase)?;
+        key_file.save(&self.key_file_path())?;
+
+        let mut repo = self;
+        repo.store = repo.store.with_key(key);
+        Ok(repo)
+    }
+
+    /// Unlocks an encrypted repository with its passphrase.
+    ///
+    /// # Errors
+    /// * `CratisError::IoError` if the repository has no key file
+    /// * `CratisError::AuthFailure` if the passphrase is wrong
+    pub fn unlock(self, passphrase: &str) -> CratisResult<Self> {
+        let key = KeyFile::load(&self.key_file_path())?.unlock(passphrase)?;
+
+        let mut repo = self;
+        repo.store = repo.store.with_key(key);
+        Ok(repo)
+    }
+
+    /// Changes the passphrase of an encrypted repository.
+    ///
+    /// Only the key file is rewritten, stored objects are not re-encrypted.
+    ///
+    /// # Errors
+    /// * `CratisError::IoError` if the key file cannot be read or written
+    /// * `CratisError::AuthFailure` if `old_passphrase` is wrong
+    pub fn change_passphrase(&self, old_passphrase: &str, new_passphrase: &str) -> CratisResult<()> {
+        let path = self.key_file_path();
+        KeyFile::load(&path)?.change_passphrase(old_passphrase, new_passphrase)?.save(&path)
+    }
+
+    fn key_file_path(&self) -> PathBuf {
+        self.root.join(KEY_FILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn encryption_is_only_enabled_on_empty_repositories() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::open(dir.path()).unwrap();
+        repo.store.put_bytes(b"chunk").unwrap();
+        assert!(matches!(repo.init_encryption("passphrase"), Err(CratisError::ConfigError(_))));
+
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::open(dir.path()).unwrap();
+        let stored = repo.store.put_reader(&b"contents"[..]).unwrap();
+        for chunk in repo.store.file_recipe(&stored.hash).unwrap().chunks {
+            repo.store.remove_object(&chunk.hash).unwrap();
+        }
+        assert!(repo.store.object_hashes().unwrap().is_empty());
+        assert!(matches!(repo.clone().init_encryption("passphrase"), Err(CratisError::ConfigError(_))));
+        assert!(!repo.is_encrypted());
+
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::open(dir.path()).unwrap().init_encryption("passphrase").unwrap();
+        assert!(repo.is_encrypted());
+        assert!(matches!(repo.init_encryption("passphrase"), Err(CratisError::ConfigError(_))));
+    }
+}
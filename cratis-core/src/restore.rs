@@ -0,0 +1,333 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use crate::error::{CratisError, CratisResult};
+use crate::index::FileVersion;
+use crate::repository::Repository;
+use crate::utils::HashingWriter;
+
+/// What to do when a restored file would replace an existing file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file and skip the restore.
+    SkipExisting,
+    /// Restore next to the existing file, appending the given suffix to the file name. If that
+    /// name is taken as well, a counter is appended (`notes.txt.restored.1`, ...).
+    Alongside(String),
+}
+
+/// Options controlling a restore.
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    /// Directory to restore into. `None` restores every file to its original location.
+    pub target: Option<PathBuf>,
+    pub conflict: ConflictPolicy,
+}
+
+/// The result of a restore.
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Restored files as `(original path, restored path)`.
+    pub restored: Vec<(PathBuf, PathBuf)>,
+    /// Files that were not restored because the target already existed.
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, CratisError)>,
+}
+
+/// Materializes a file or a directory tree as it was at the given timestamp.
+///
+/// If `path` is a recorded file it is restored as a single file, otherwise every recorded file
+/// and directory below `path` is restored, including empty directories. Files that did not
+/// exist or were deleted at `timestamp` are left out.
+///
+/// With a target directory, a file is restored as `<target>/<file name>` and a directory as
+/// `<target>/<directory name>/...`. Without one, files are restored to their original location.
+///
+/// Every file is reassembled into a temporary file next to its destination, verified against its
+/// recorded hash and only then renamed into place, so a failed restore never leaves a partially
+/// written or corrupt file behind. The recorded modification time is applied to restored files.
+///
+/// # Arguments
+/// * `repo` - The repository to restore from
+/// * `path` - The original path of the file or directory
+/// * `timestamp` - The point in time to restore
+/// * `options` - Where to restore to and how to handle existing files
+///
+/// # Errors
+/// * `CratisError::InvalidPath` if nothing was recorded for `path` at `timestamp`
+/// * `CratisError::DatabaseError` if the index cannot be read
+///
+/// Failures of individual files are reported in `RestoreReport::failed`.
+///
+/// # Examples
+/// ```ignore
+/// let options = RestoreOptions { target: Some("/tmp/restore".into()), conflict: ConflictPolicy::SkipExisting };
+/// let report = restore(&repo, Path::new("/home/user/project"), yesterday_14_00, &options)?;
+/// ```
+pub fn restore(repo: &Repository, path: &Path, timestamp: u64, options: &RestoreOptions) -> CratisResult<RestoreReport> {
+    let versions = versions_at(repo, path, timestamp)?;
+    if versions.is_empty() {
+        return Err(CratisError::InvalidPath(format!("Nothing recorded for {} at that time", path.display())));
+    }
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut report = RestoreReport::default();
+
+    for (original, version) in versions {
+        let destination = match &options.target {
+            Some(target) => target.join(original.strip_prefix(base).unwrap_or(&original)),
+            None => original.clone(),
+        };
+
+        if version.is_directory() {
+            if let Err(e) = fs::create_dir_all(&destination) {
+                report.failed.push((original, e.into()));
+            }
+            continue;
+        }
+
+        let destination = match (&options.conflict, destination.exists()) {
+            (_, false) | (ConflictPolicy::Overwrite, true) => destination,
+            (ConflictPolicy::SkipExisting, true) => {
+                report.skipped.push(destination);
+                continue;
+            }
+            (ConflictPolicy::Alongside(suffix), true) => alongside(&destination, suffix),
+        };
+
+        match restore_file(repo, &version, &destination) {
+            Ok(()) => report.restored.push((original, destination)),
+            Err(e) => report.failed.push((original, e)),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Returns the live revision at `timestamp` of `path`, or of every recorded file and directory
+/// below `path`.
+///
+/// Whether `path` is restored as a file or as a tree depends on what it was at `timestamp`, not
+/// on what it is now.
+fn versions_at(repo: &Repository, path: &Path, timestamp: u64) -> CratisResult<Vec<(PathBuf, FileVersion)>> {
+    let paths = match repo.index.at(path, timestamp)? {
+        Some(version) if !version.is_deleted() && !version.is_directory() => vec![path.to_path_buf()],
+        _ => {
+            let mut paths = vec![path.to_path_buf()];
+            paths.extend(repo.index.paths_under(path)?);
+            paths
+        }
+    };
+
+    let mut versions = Vec::new();
+    for p in paths {
+        if let Some(version) = repo.index.at(&p, timestamp)?
+            && !version.is_deleted()
+            && (version.hash.is_some() || version.is_directory())
+        {
+            versions.push((p, version));
+        }
+    }
+
+    Ok(versions)
+}
+
+/// Returns the first unused name of the form `<destination><suffix>`, `<destination><suffix>.1`, ...
+fn alongside(destination: &Path, suffix: &str) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+
+    let mut candidate = destination.with_file_name(&name);
+    let mut counter = 1;
+    while candidate.exists() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{counter}"));
+        candidate = destination.with_file_name(numbered);
+        counter += 1;
+    }
+
+    candidate
+}
+
+/// Restores a single file revision to `destination`, verifying it against its recorded hash.
+fn restore_file(repo: &Repository, version: &FileVersion, destination: &Path) -> CratisResult<()> {
+    let hash = version
+        .hash
+        .as_deref()
+        .ok_or(CratisError::Internal("Cannot restore a revision without content"))?;
+
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_name = destination.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".cratis-restore.tmp");
+    let tmp_path = destination.with_file_name(tmp_name);
+
+    let result = (|| {
+        let mut writer = HashingWriter::new(BufWriter::new(File::create(&tmp_path)?), repo.store.hasher());
+        repo.store.read_file(hash, &mut writer)?;
+
+        let (inner, digest) = writer.finish();
+        if digest != hash {
+            return Err(CratisError::BackupFailure("Restored file does not match its recorded hash"));
+        }
+
+        let file = inner.into_inner().map_err(|e| e.into_error())?;
+        if version.mtime > 0 {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(version.mtime))?;
+        }
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, destination)?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintenance::verify;
+    use crate::sync::{SyncEngine, SyncOutcome};
+    use crate::utils::EventAction;
+    use tempfile::TempDir;
+
+    /// A repository in `<dir>/repo` and the directory `<dir>/data` its files come from.
+    fn setup() -> (TempDir, SyncEngine, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+
+        let engine = SyncEngine::new(Repository::open(dir.path().join("repo")).unwrap());
+        (dir, engine, data)
+    }
+
+    /// Writes a file and records it at `timestamp`.
+    fn backup(engine: &SyncEngine, path: &Path, contents: &str, timestamp: u64) -> String {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+
+        match engine.sync_path(path, EventAction::Modify, timestamp).unwrap() {
+            SyncOutcome::Stored { hash, .. } | SyncOutcome::Unchanged { hash } => hash,
+            outcome => panic!("{} was not stored: {:?}", path.display(), outcome),
+        }
+    }
+
+    fn options(target: Option<&Path>, conflict: ConflictPolicy) -> RestoreOptions {
+        RestoreOptions { target: target.map(Path::to_path_buf), conflict }
+    }
+
+    #[test]
+    fn restores_a_file_as_it_was() {
+        let (dir, engine, data) = setup();
+        let file = data.join("notes.txt");
+        backup(&engine, &file, "first", 10);
+        backup(&engine, &file, "second", 20);
+
+        let target = dir.path().join("restore");
+        let report = restore(engine.repository(), &file, 15, &options(Some(&target), ConflictPolicy::Overwrite)).unwrap();
+
+        assert_eq!(report.restored, vec![(file.clone(), target.join("notes.txt"))]);
+        assert_eq!(fs::read_to_string(target.join("notes.txt")).unwrap(), "first");
+        assert!(matches!(
+            restore(engine.repository(), &file, 5, &options(Some(&target), ConflictPolicy::Overwrite)),
+            Err(CratisError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn restores_a_tree_as_it_was() {
+        let (dir, engine, data) = setup();
+        backup(&engine, &data.join("a.txt"), "a", 10);
+        backup(&engine, &data.join("docs/b.txt"), "b", 10);
+        engine.sync_path(&data.join("docs"), EventAction::CreateDir, 10).unwrap();
+        fs::create_dir(data.join("empty")).unwrap();
+        engine.sync_path(&data.join("empty"), EventAction::CreateDir, 10).unwrap();
+
+        backup(&engine, &data.join("a.txt"), "a changed", 20);
+        backup(&engine, &data.join("c.txt"), "created later", 20);
+        fs::remove_file(data.join("docs/b.txt")).unwrap();
+        engine.sync_path(&data.join("docs/b.txt"), EventAction::Delete, 20).unwrap();
+
+        let target = dir.path().join("restore");
+        let report = restore(engine.repository(), &data, 15, &options(Some(&target), ConflictPolicy::Overwrite)).unwrap();
+        assert!(report.failed.is_empty());
+
+        let restored = target.join("data");
+        assert_eq!(fs::read_to_string(restored.join("a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(restored.join("docs/b.txt")).unwrap(), "b");
+        assert!(restored.join("empty").is_dir());
+        assert!(!restored.join("c.txt").exists());
+    }
+
+    #[test]
+    fn restores_what_the_path_was_at_the_time() {
+        let (dir, engine, data) = setup();
+        let path = data.join("project");
+        backup(&engine, &path, "a file", 10);
+        fs::remove_file(&path).unwrap();
+        engine.sync_path(&path, EventAction::Delete, 20).unwrap();
+        backup(&engine, &path.join("main.rs"), "fn main() {}", 30);
+
+        let target = dir.path().join("restore");
+        restore(engine.repository(), &path, 15, &options(Some(&target.join("then")), ConflictPolicy::Overwrite)).unwrap();
+        restore(engine.repository(), &path, 35, &options(Some(&target.join("now")), ConflictPolicy::Overwrite)).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("then/project")).unwrap(), "a file");
+        assert_eq!(fs::read_to_string(target.join("now/project/main.rs")).unwrap(), "fn main() {}");
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let (_dir, engine, data) = setup();
+        let file = data.join("notes.txt");
+        backup(&engine, &file, "backed up", 10);
+        fs::write(&file, "local changes").unwrap();
+        let repo = engine.repository();
+
+        let report = restore(repo, &file, 10, &options(None, ConflictPolicy::SkipExisting)).unwrap();
+        assert_eq!(report.skipped, vec![file.clone()]);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "local changes");
+
+        let alongside = ConflictPolicy::Alongside(".restored".to_string());
+        restore(repo, &file, 10, &options(None, alongside.clone())).unwrap();
+        let report = restore(repo, &file, 10, &options(None, alongside)).unwrap();
+        assert_eq!(report.restored, vec![(file.clone(), data.join("notes.txt.restored.1"))]);
+        assert_eq!(fs::read_to_string(data.join("notes.txt.restored")).unwrap(), "backed up");
+        assert_eq!(fs::read_to_string(data.join("notes.txt.restored.1")).unwrap(), "backed up");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "local changes");
+
+        restore(repo, &file, 10, &options(None, ConflictPolicy::Overwrite)).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "backed up");
+    }
+
+    #[test]
+    fn corrupt_chunks_are_detected() {
+        let (dir, engine, data) = setup();
+        let file = data.join("notes.txt");
+        let hash = backup(&engine, &file, "backed up", 10);
+        let repo = engine.repository();
+
+        let chunk = &repo.store.file_recipe(&hash).unwrap().chunks[0].hash;
+        fs::write(repo.store.object_path(chunk).unwrap(), "garbage").unwrap();
+
+        let target = dir.path().join("restore");
+        let report = restore(repo, &file, 10, &options(Some(&target), ConflictPolicy::Overwrite)).unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.restored.is_empty());
+        assert!(fs::read_dir(&target).map_or(true, |mut entries| entries.next().is_none()));
+
+        let verified = verify(repo).unwrap();
+        assert_eq!(verified.errors.len(), 1);
+        assert_eq!(verified.errors[0].0, hash);
+    }
+}
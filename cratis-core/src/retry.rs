@@ -0,0 +1,150 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::Duration;
+use crate::error::CratisResult;
+
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How often and how patiently to retry operations that fail with a transient error.
+///
+/// The delay before retry `n` (starting at 0) is `base_delay * 2^n`, capped at `max_delay`,
+/// of which the upper half is randomized. The jitter keeps several clients that lost their
+/// connection at the same time from retrying in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt. `0` disables retrying.
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Creates a policy with the given number of retries and initial delay.
+    pub fn new(attempts: u32, base_delay: Duration) -> Self {
+        Self { attempts, base_delay, max_delay: MAX_RETRY_DELAY.max(base_delay) }
+    }
+
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    /// Returns the delay to wait before the given retry, starting at 0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .checked_mul(1 << retry.min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let half = backoff / 2;
+        half + jitter(backoff - half)
+    }
+
+    /// Runs an operation, retrying it as long as it fails with a transient error.
+    ///
+    /// Permanent errors (see `CratisError::is_transient`) are returned immediately. Once all
+    /// retries are used up, the last error is returned. Blocks the calling thread while waiting.
+    ///
+    /// # Arguments
+    /// * `operation` - The operation to run, called once per attempt
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let policy = config.retry_policy();
+    /// let hash = policy.retry(|| repo.store.hash_file(&path))?;
+    /// ```
+    pub fn retry<T, F>(&self, mut operation: F) -> CratisResult<T>
+    where
+        F: FnMut() -> CratisResult<T>,
+    {
+        let mut retry = 0;
+
+        loop {
+            match operation() {
+                Err(e) if e.is_transient() && retry < self.attempts => {
+                    thread::sleep(self.delay(retry));
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETRY_ATTEMPTS, DEFAULT_RETRY_DELAY)
+    }
+}
+
+/// Returns a random duration in `[0, max]`.
+fn jitter(max: Duration) -> Duration {
+    let nanos = max.as_nanos().min(u64::MAX as u128) as u64;
+    if nanos == 0 {
+        return Duration::ZERO;
+    }
+
+    // Every `RandomState` is seeded differently, which is all the randomness jitter needs.
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % (nanos + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io;
+    use crate::error::CratisError;
+
+    /// Runs `policy` on an operation failing with `error` every time and returns the number of
+    /// calls made.
+    fn count_calls(policy: RetryPolicy, error: fn() -> CratisError) -> u32 {
+        let calls = Cell::new(0);
+        let result: CratisResult<()> = policy.retry(|| {
+            calls.set(calls.get() + 1);
+            Err(error())
+        });
+        assert!(result.is_err());
+        calls.get()
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let policy = RetryPolicy { attempts: 10, base_delay: Duration::from_secs(1), max_delay: Duration::from_secs(10) };
+
+        for (retry, backoff) in [(0, 1), (1, 2), (2, 4), (3, 8), (4, 10), (40, 10)] {
+            let backoff = Duration::from_secs(backoff);
+            let delay = policy.delay(retry);
+            assert!(delay >= backoff / 2 && delay <= backoff, "retry {retry} waited {delay:?}");
+        }
+
+        assert_eq!(RetryPolicy::none().delay(5), Duration::ZERO);
+        assert_eq!(RetryPolicy::new(1, Duration::from_secs(120)).max_delay, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn transient_errors_are_retried_until_the_attempts_are_used_up() {
+        assert_eq!(count_calls(RetryPolicy::new(2, Duration::ZERO), || CratisError::Timeout), 3);
+        assert_eq!(count_calls(RetryPolicy::none(), || CratisError::Timeout), 1);
+
+        let calls = Cell::new(0);
+        let result = RetryPolicy::new(5, Duration::ZERO).retry(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 { Err(CratisError::ConnectionIssue("Connection refused")) } else { Ok(calls.get()) }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn permanent_errors_are_returned_at_once() {
+        let policy = RetryPolicy::new(2, Duration::ZERO);
+        assert_eq!(count_calls(policy, || CratisError::AuthFailure("Wrong token")), 1);
+        assert_eq!(count_calls(policy, || CratisError::InvalidInput("Bad digest")), 1);
+        assert_eq!(count_calls(policy, || io::Error::from(io::ErrorKind::NotFound).into()), 1);
+        assert_eq!(count_calls(policy, || io::Error::from(io::ErrorKind::ResourceBusy).into()), 3);
+    }
+}
@@ -0,0 +1,448 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config::BackupMode;
+use crate::error::{CratisError, CratisResult};
+use crate::index::SnapshotRecord;
+use crate::repository::Repository;
+use crate::sync::{SyncEngine, SyncOutcome};
+use crate::utils::{modified_secs, EventAction};
+
+/// The longest snapshot chain `take_snapshot` builds unless configured otherwise, full snapshot
+/// included.
+pub const DEFAULT_MAX_CHAIN_LENGTH: usize = 10;
+
+/// The state of a single file at the time a snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+/// An immutable manifest of the tree state of all watched directories at a point in time.
+///
+/// Manifests are stored as objects in the object store (and therefore compressed and encrypted
+/// like any other object). The id of a snapshot is the digest of its manifest object.
+///
+/// * A `Full` snapshot lists every file below `roots` and has no parent.
+/// * An `Incremental` snapshot only lists files that are new or changed since its `parent`, plus
+///   the paths that were `removed`. Following the parents back to the nearest full snapshot and
+///   applying the changes in order yields the complete tree (see `resolve_tree`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub timestamp: u64,
+    pub mode: BackupMode,
+    pub parent: Option<String>,
+    pub roots: Vec<String>,
+    /// Files captured by this snapshot, sorted by path.
+    pub entries: Vec<SnapshotEntry>,
+    /// Paths present in the parent tree but missing from this one, sorted.
+    pub removed: Vec<String>,
+}
+
+/// The result of taking a snapshot.
+#[derive(Debug)]
+pub struct SnapshotReport {
+    pub record: SnapshotRecord,
+    pub snapshot: Snapshot,
+    /// Files that could not be captured and are therefore missing from the snapshot.
+    pub failed: Vec<(PathBuf, CratisError)>,
+}
+
+/// Captures the current state of the given directories as a snapshot.
+///
+/// Walks every root recursively, stores the content of every file that passes `include` through
+/// the sync engine (so per-file history stays complete and unchanged content is not stored
+/// twice), then writes the manifest as an object and records it in the index. Files whose size
+/// and modification time match their latest revision are not read again.
+///
+/// In `Incremental` mode the manifest only records the differences to the most recent snapshot.
+/// A full snapshot is taken instead if there is no previous snapshot of the same roots, or if
+/// the chain of the previous snapshot already holds `max_chain_length` snapshots. Starting a new
+/// chain now and then keeps resolving a snapshot cheap and lets `maintenance::prune` remove the
+/// old chains.
+///
+/// Files that vanish during the walk are silently left out. Files that cannot be read are left
+/// out and reported in `SnapshotReport::failed`, so a single unreadable file does not prevent
+/// the snapshot.
+///
+/// # Arguments
+/// * `engine` - The sync engine writing into the repository
+/// * `roots` - The directories to capture, usually `BackupConfig::watch_directories`
+/// * `timestamp` - The timestamp the snapshot (and any new file revision) is recorded at
+/// * `mode` - Whether to record the complete tree or only the changes since the previous snapshot
+/// * `max_chain_length` - The most snapshots a chain may hold in `Incremental` mode, usually
+///   `BackupConfig::max_chain_length` or `DEFAULT_MAX_CHAIN_LENGTH`
+/// * `include` - Filter deciding which paths are part of the snapshot
+///
+/// # Errors
+/// Returns an error if a root cannot be read or the manifest cannot be stored.
+///
+/// # Examples
+/// ```ignore
+/// let report = take_snapshot(&engine, &backup.watch_directories, timestamp_now()?, backup.mode, DEFAULT_MAX_CHAIN_LENGTH, |_| true)?;
+/// println!("Snapshot {} with {} files", report.record.id, report.record.files);
+/// ```
+pub fn take_snapshot<F>(
+    engine: &SyncEngine,
+    roots: &[String],
+    timestamp: u64,
+    mode: BackupMode,
+    max_chain_length: usize,
+    include: F,
+) -> CratisResult<SnapshotReport>
+where
+    F: Fn(&Path) -> bool,
+{
+    let repo = engine.repository();
+    let mut entries = Vec::new();
+    let mut failed = Vec::new();
+
+    for root in roots {
+        let mut files = Vec::new();
+        walk_tree(Path::new(root), &include, &mut files, &mut Vec::new())?;
+
+        for (path, metadata) in files {
+            match capture_file(engine, &path, &metadata, timestamp) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {}
+                Err(CratisError::IoError(e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => failed.push((path, e)),
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries.dedup_by(|a, b| a.path == b.path);
+
+    let files = entries.len() as u64;
+    let size = entries.iter().map(|entry| entry.size).sum();
+
+    let parent = match mode {
+        BackupMode::Incremental => repo
+            .index
+            .latest_snapshot()?
+            .map(|record| load_chain(repo, &record.id).map(|chain| (record.id, chain)))
+            .transpose()?
+            .filter(|(_, chain)| chain[0].roots == roots && chain.len() < max_chain_length),
+        BackupMode::Full => None,
+    };
+
+    let snapshot = match parent {
+        Some((parent_id, chain)) => {
+            let parent_tree = apply_chain(chain);
+            let (changed, removed) = diff_trees(&parent_tree, entries);
+
+            Snapshot {
+                timestamp,
+                mode: BackupMode::Incremental,
+                parent: Some(parent_id),
+                roots: roots.to_vec(),
+                entries: changed,
+                removed,
+            }
+        }
+        None => Snapshot {
+            timestamp,
+            mode: BackupMode::Full,
+            parent: None,
+            roots: roots.to_vec(),
+            entries,
+            removed: Vec::new(),
+        },
+    };
+
+    let record = store_snapshot(repo, &snapshot, files, size)?;
+
+    Ok(SnapshotReport { record, snapshot, failed })
+}
+
+/// Resolves a snapshot to the complete tree of files it describes.
+///
+/// Full snapshots are returned as is. For incremental snapshots the chain of parents is followed
+/// back to the nearest full snapshot and the changes are applied oldest first.
+///
+/// # Returns
+/// Every file of the snapshot, sorted by path.
+///
+/// # Errors
+/// * `CratisError::BackupFailure` if the chain contains a cycle or does not end in a full snapshot
+/// * Any error returned by `load_snapshot`
+pub fn resolve_tree(repo: &Repository, id: &str) -> CratisResult<Vec<SnapshotEntry>> {
+    Ok(apply_chain(load_chain(repo, id)?))
+}
+
+/// Loads a snapshot and its parents up to the nearest full snapshot, newest first.
+fn load_chain(repo: &Repository, id: &str) -> CratisResult<Vec<Snapshot>> {
+    let mut chain = Vec::new();
+    let mut visited = HashSet::new();
+    let mut next = Some(id.to_string());
+
+    while let Some(id) = next {
+        if !visited.insert(id.clone()) {
+            return Err(CratisError::BackupFailure("Snapshot chain contains a cycle"));
+        }
+
+        let snapshot = load_snapshot(repo, &id)?;
+        next = match snapshot.mode {
+            BackupMode::Full => None,
+            BackupMode::Incremental => Some(
+                snapshot
+                    .parent
+                    .clone()
+                    .ok_or(CratisError::BackupFailure("Incremental snapshot has no parent"))?,
+            ),
+        };
+        chain.push(snapshot);
+    }
+
+    Ok(chain)
+}
+
+/// Applies a chain loaded by `load_chain` oldest first, returning the files sorted by path.
+fn apply_chain(chain: Vec<Snapshot>) -> Vec<SnapshotEntry> {
+    let mut tree: BTreeMap<String, SnapshotEntry> = BTreeMap::new();
+    for snapshot in chain.into_iter().rev() {
+        for path in &snapshot.removed {
+            tree.remove(path);
+        }
+        for entry in snapshot.entries {
+            tree.insert(entry.path.clone(), entry);
+        }
+    }
+
+    tree.into_values().collect()
+}
+
+/// Splits the current tree into the entries that differ from `parent` and the paths that were removed.
+fn diff_trees(parent: &[SnapshotEntry], current: Vec<SnapshotEntry>) -> (Vec<SnapshotEntry>, Vec<String>) {
+    let parent_by_path: BTreeMap<&str, &SnapshotEntry> = parent.iter().map(|e| (e.path.as_str(), e)).collect();
+    let current_paths: HashSet<String> = current.iter().map(|e| e.path.clone()).collect();
+
+    let removed = parent
+        .iter()
+        .filter(|entry| !current_paths.contains(&entry.path))
+        .map(|entry| entry.path.clone())
+        .collect();
+    let changed = current
+        .into_iter()
+        .filter(|entry| parent_by_path.get(entry.path.as_str()) != Some(&entry))
+        .collect();
+
+    (changed, removed)
+}
+
+/// Writes a snapshot manifest into the object store and records it in the index.
+///
+/// `files` and `size` describe the complete tree of the snapshot.
+///
+/// # Errors
+/// Returns an error if the manifest cannot be stored or recorded.
+pub fn store_snapshot(repo: &Repository, snapshot: &Snapshot, files: u64, size: u64) -> CratisResult<SnapshotRecord> {
+    let id = repo.store.put_bytes(&serde_json::to_vec(snapshot)?)?;
+    let record = SnapshotRecord {
+        id,
+        timestamp: snapshot.timestamp,
+        mode: snapshot.mode,
+        files,
+        size,
+    };
+
+    repo.index.record_snapshot(&record)?;
+
+    Ok(record)
+}
+
+/// Loads a snapshot manifest from the object store.
+///
+/// # Errors
+/// Returns an error if the manifest object is missing, cannot be decrypted or is malformed.
+pub fn load_snapshot(repo: &Repository, id: &str) -> CratisResult<Snapshot> {
+    Ok(serde_json::from_slice(&repo.store.get(id)?)?)
+}
+
+/// Captures a single file, storing its content through the sync engine if it changed.
+///
+/// Like `reconcile`, a file whose size and modification time match its latest revision is
+/// taken as unchanged and not read again, as long as that revision is still stored.
+fn capture_file(engine: &SyncEngine, path: &Path, metadata: &Metadata, timestamp: u64) -> CratisResult<Option<SnapshotEntry>> {
+    let repo = engine.repository();
+    let latest = repo.index.latest(path)?.filter(|version| !version.is_deleted());
+
+    let unchanged = latest.as_ref().and_then(|version| {
+        version.hash.clone().filter(|hash| {
+            version.size == metadata.len()
+                && version.mtime != 0
+                && version.mtime == modified_secs(metadata)
+                && repo.store.contains_file(hash)
+        })
+    });
+
+    let hash = match unchanged {
+        Some(hash) => hash,
+        None => {
+            let action = if latest.is_some() { EventAction::Modify } else { EventAction::Create };
+            match engine.sync_path(path, action, timestamp)? {
+                SyncOutcome::Stored { hash, .. } | SyncOutcome::Unchanged { hash } | SyncOutcome::Moved { hash, .. } => hash,
+                SyncOutcome::Tombstoned | SyncOutcome::Directory | SyncOutcome::Skipped(_) | SyncOutcome::TooLarge { .. } => return Ok(None),
+            }
+        }
+    };
+
+    Ok(Some(SnapshotEntry {
+        path: path.to_string_lossy().into_owned(),
+        hash,
+        size: metadata.len(),
+        mode: file_mode(metadata),
+        mtime: modified_secs(metadata),
+    }))
+}
+
+/// Recursively collects all regular files and directories below `dir` that pass `include`.
+///
+/// Symlinks are not followed. Subdirectories that disappear during the walk are skipped.
+pub(crate) fn walk_tree<F>(dir: &Path, include: &F, files: &mut Vec<(PathBuf, Metadata)>, dirs: &mut Vec<PathBuf>) -> CratisResult<()>
+where
+    F: Fn(&Path) -> bool,
+{
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !include(&path) {
+            continue;
+        }
+
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        if metadata.is_dir() {
+            match walk_tree(&path, include, files, dirs) {
+                Err(CratisError::IoError(e)) if e.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+            dirs.push(path);
+        } else if metadata.is_file() {
+            files.push((path, metadata));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    /// A repository in `<dir>/repo` and a watched directory `<dir>/data`.
+    fn setup() -> (TempDir, SyncEngine, Vec<String>) {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+
+        let engine = SyncEngine::new(Repository::open(dir.path().join("repo")).unwrap());
+        (dir, engine, vec![data.to_string_lossy().into_owned()])
+    }
+
+    fn write(path: &Path, contents: &str, mtime: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(mtime)).unwrap();
+    }
+
+    #[test]
+    fn unchanged_size_and_mtime_are_not_read_again() {
+        let (_dir, engine, roots) = setup();
+        let file = Path::new(&roots[0]).join("photo.raw");
+
+        write(&file, "original", 1_000);
+        let first = take_snapshot(&engine, &roots, 10, BackupMode::Full, DEFAULT_MAX_CHAIN_LENGTH, |_| true).unwrap();
+
+        // Same size and modification time: the snapshot trusts the recorded revision.
+        write(&file, "modified", 1_000);
+        let second = take_snapshot(&engine, &roots, 20, BackupMode::Full, DEFAULT_MAX_CHAIN_LENGTH, |_| true).unwrap();
+        assert_eq!(second.snapshot.entries[0].hash, first.snapshot.entries[0].hash);
+
+        write(&file, "modified", 1_001);
+        let third = take_snapshot(&engine, &roots, 30, BackupMode::Full, DEFAULT_MAX_CHAIN_LENGTH, |_| true).unwrap();
+        assert_ne!(third.snapshot.entries[0].hash, first.snapshot.entries[0].hash);
+        assert_eq!(engine.repository().index.versions(&file).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn full_and_incremental_snapshots_resolve_to_the_same_tree() {
+        let (_dir, engine, roots) = setup();
+        let (_other_dir, other_engine, _) = setup();
+        let data = Path::new(&roots[0]);
+
+        let changes: [&dyn Fn(); 4] = [
+            &|| {
+                write(&data.join("a.txt"), "a1", 100);
+                write(&data.join("docs/b.txt"), "b1", 100);
+                write(&data.join("docs/c.txt"), "c1", 100);
+            },
+            &|| {
+                write(&data.join("a.txt"), "a2", 200);
+                write(&data.join("d.txt"), "d1", 200);
+            },
+            &|| {
+                fs::remove_file(data.join("docs/b.txt")).unwrap();
+                write(&data.join("docs/c.txt"), "c2 longer", 300);
+            },
+            &|| fs::remove_dir_all(data.join("docs")).unwrap(),
+        ];
+
+        for (i, change) in changes.iter().enumerate() {
+            change();
+            let timestamp = (i as u64 + 1) * 1000;
+            let full = take_snapshot(&other_engine, &roots, timestamp, BackupMode::Full, DEFAULT_MAX_CHAIN_LENGTH, |_| true).unwrap();
+            let incremental = take_snapshot(&engine, &roots, timestamp, BackupMode::Incremental, DEFAULT_MAX_CHAIN_LENGTH, |_| true).unwrap();
+
+            let expected_mode = if i == 0 { BackupMode::Full } else { BackupMode::Incremental };
+            assert_eq!(incremental.record.mode, expected_mode);
+            assert_eq!(
+                resolve_tree(engine.repository(), &incremental.record.id).unwrap(),
+                resolve_tree(other_engine.repository(), &full.record.id).unwrap(),
+                "trees differ after change {i}"
+            );
+            assert_eq!((incremental.record.files, incremental.record.size), (full.record.files, full.record.size));
+        }
+    }
+
+    #[test]
+    fn incremental_chains_are_limited() {
+        let (_dir, engine, roots) = setup();
+        let file = Path::new(&roots[0]).join("notes.txt");
+
+        let mut modes = Vec::new();
+        for i in 0..7 {
+            write(&file, &format!("revision {i}"), 100 + i);
+            let report = take_snapshot(&engine, &roots, 1000 + i, BackupMode::Incremental, 3, |_| true).unwrap();
+            modes.push(report.record.mode);
+        }
+
+        use BackupMode::{Full, Incremental};
+        assert_eq!(modes, [Full, Incremental, Incremental, Full, Incremental, Incremental, Full]);
+    }
+}
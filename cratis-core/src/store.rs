@@ -0,0 +1,708 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use blake3::Hasher;
+use serde::{Deserialize, Serialize};
+use crate::compress::{self, has_compressed_signature, Compression};
+use crate::crypto::RepoKey;
+use crate::error::{CratisError, CratisResult};
+use crate::utils::chunk_reader;
+
+const OBJECTS_DIR: &str = "objects";
+const FILES_DIR: &str = "files";
+const TMP_DIR: &str = "tmp";
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A reference to a single chunk of a stored file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub length: u64,
+}
+
+/// The list of chunks a stored file is made of, in file order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRecipe {
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// Summary of a `put_file` call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    /// BLAKE3 hex digest of the whole file, as produced by `utils::hash_file`.
+    pub hash: String,
+    pub size: u64,
+    pub chunks: usize,
+    /// Number of chunks that were not stored before.
+    pub new_chunks: usize,
+}
+
+/// A content-addressed object store on the local filesystem.
+///
+/// Files are split into content-defined chunks (see `utils::chunk_reader`). Every chunk is stored
+/// once under the BLAKE3 hex digest of its contents, and every file version is stored as a recipe
+/// listing its chunks, keyed by the digest of the whole file. Both use the first two characters
+/// of the digest as a fan-out subdirectory:
+///
+/// ```text
+/// <root>/objects/ab/cdef0123...  (chunks and other raw objects)
+/// <root>/files/ab/cdef0123...    (file recipes)
+/// <root>/tmp/                    (staging area for in-flight writes)
+/// ```
+///
+/// Writes are staged in `tmp/` and atomically renamed into place, so a reader never observes a
+/// partially written object. Identical content is only ever stored once, across versions as
+/// well as across files. Objects are addressed by the digest of their uncompressed contents and
+/// transparently zstd-compressed on disk (see `Compression`).
+///
+/// If the store is given a `RepoKey`, every object is encrypted after compression and all digests
+/// are keyed BLAKE3 digests, so neither the contents nor the equality of plaintexts are visible
+/// to anyone without the key. This only applies to the objects, the `VersionIndex` kept next to
+/// the store is not encrypted.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    root: PathBuf,
+    compression: Compression,
+    key: Option<RepoKey>,
+}
+
+impl ObjectStore {
+    /// Opens an object store rooted at the given directory, creating the directory layout if needed.
+    ///
+    /// # Arguments
+    /// * `root` - The directory that holds the `objects/`, `files/` and `tmp/` subdirectories
+    ///
+    /// # Returns
+    /// * `Ok(ObjectStore)` if the directory layout exists or was created
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the directories cannot be created.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let store = ObjectStore::open("/var/lib/cratis")?;
+    /// let stored = store.put_file(Path::new("/home/user/notes.txt"))?;
+    /// assert!(store.contains_file(&stored.hash));
+    /// ```
+    pub fn open<P: AsRef<Path>>(root: P) -> CratisResult<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(OBJECTS_DIR))?;
+        fs::create_dir_all(root.join(FILES_DIR))?;
+        fs::create_dir_all(root.join(TMP_DIR))?;
+
+        Ok(Self { root, compression: Compression::default(), key: None })
+    }
+
+    /// Sets the compression applied to objects written from now on.
+    ///
+    /// Objects that are already stored keep their encoding and stay readable.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let store = ObjectStore::open("/var/lib/cratis")?.with_compression(Compression::new(9));
+    /// ```
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the key used to encrypt objects and to key their digests.
+    ///
+    /// A store must always be used with the same key (or always without one), since digests
+    /// computed with and without a key differ.
+    pub fn with_key(mut self, key: RepoKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Returns `true` if objects are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Returns a hasher producing the digests used to address objects in this store.
+    pub fn hasher(&self) -> Hasher {
+        self.key.as_ref().map(RepoKey::hasher).unwrap_or_default()
+    }
+
+    /// Computes the digest under which a buffer would be stored.
+    pub fn hash_bytes(&self, data: &[u8]) -> String {
+        match &self.key {
+            Some(key) => key.keyed_hash(data),
+            None => blake3::hash(data).to_hex().to_string(),
+        }
+    }
+
+    /// Computes the digest under which a file would be stored.
+    ///
+    /// Without a key this is identical to `utils::hash_file`.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the file cannot be read.
+    pub fn hash_file(&self, path: &Path) -> CratisResult<String> {
+        let mut file = File::open(path)?;
+        let mut hasher = self.hasher();
+        let mut buffer = [0u8; 64 * 1024];
+
+        loop {
+            let bytes_read = file.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+
+    /// Returns the root directory of the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the path at which the object with the given hash is (or would be) stored.
+    ///
+    /// # Errors
+    /// Returns `CratisError::InvalidInput` if `hash` is not a 64 character hex digest.
+    pub fn object_path(&self, hash: &str) -> CratisResult<PathBuf> {
+        fan_out(&self.root.join(OBJECTS_DIR), hash)
+    }
+
+    /// Returns the path at which the recipe of the file with the given hash is (or would be) stored.
+    ///
+    /// # Errors
+    /// Returns `CratisError::InvalidInput` if `hash` is not a 64 character hex digest.
+    pub fn file_path(&self, hash: &str) -> CratisResult<PathBuf> {
+        fan_out(&self.root.join(FILES_DIR), hash)
+    }
+
+    /// Checks whether an object with the given hash is already stored.
+    ///
+    /// Invalid hashes are reported as not present.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.object_path(hash).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    /// Checks whether a file with the given whole-file hash is already stored.
+    ///
+    /// Invalid hashes are reported as not present.
+    pub fn contains_file(&self, hash: &str) -> bool {
+        self.file_path(hash).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    /// Stores an in-memory buffer as a single object and returns its digest.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the object cannot be written.
+    pub fn put_bytes(&self, data: &[u8]) -> CratisResult<String> {
+        let hash = self.hash_bytes(data);
+        let target = self.object_path(&hash)?;
+        if !target.is_file() {
+            self.write_atomic(&target, &self.encode(data, true)?)?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Reads an object back from the store.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest
+    /// * `CratisError::IoError` if the object does not exist or cannot be read
+    /// * `CratisError::EncryptionError` if the object cannot be decrypted with the store key
+    pub fn get(&self, hash: &str) -> CratisResult<Vec<u8>> {
+        self.decode(&fs::read(self.object_path(hash)?)?)
+    }
+
+    /// Stores the contents of a file as content-defined chunks and returns a summary.
+    ///
+    /// The whole file is hashed while it is chunked, so the returned hash always describes
+    /// exactly the bytes that were stored, even if the file changes concurrently. The digest is
+    /// identical to the one produced by `hash_file`.
+    ///
+    /// # Arguments
+    /// * `path` - The file to store
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the file cannot be read or an object cannot be written.
+    pub fn put_file(&self, path: &Path) -> CratisResult<StoredFile> {
+        let file = File::open(path)?;
+        self.put_reader(file)
+    }
+
+    /// Streams the contents of a reader into the store as content-defined chunks.
+    ///
+    /// If the first chunk starts with the signature of an already compressed format (jpeg, zip,
+    /// mp4, ...), no chunk of the file is compressed. Otherwise every chunk is checked separately.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if reading fails or an object cannot be written.
+    pub fn put_reader<R: Read>(&self, reader: R) -> CratisResult<StoredFile> {
+        let mut hasher = self.hasher();
+        let mut recipe = FileRecipe { size: 0, chunks: Vec::new() };
+        let mut new_chunks = 0;
+        let mut precompressed = None;
+
+        for chunk in chunk_reader(reader) {
+            let chunk = chunk?;
+            hasher.update(&chunk.data);
+
+            let precompressed = *precompressed.get_or_insert_with(|| has_compressed_signature(&chunk.data));
+            let chunk_hash = match &self.key {
+                Some(key) => key.keyed_hash(&chunk.data),
+                None => chunk.hash,
+            };
+
+            let target = self.object_path(&chunk_hash)?;
+            if !target.is_file() {
+                self.write_atomic(&target, &self.encode(&chunk.data, !precompressed)?)?;
+                new_chunks += 1;
+            }
+
+            recipe.size += chunk.data.len() as u64;
+            recipe.chunks.push(ChunkRef { hash: chunk_hash, length: chunk.data.len() as u64 });
+        }
+
+        let hash = hasher.finalize().to_hex().to_string();
+        let target = self.file_path(&hash)?;
+        if !target.is_file() {
+            self.write_atomic(&target, &self.encode(&serde_json::to_vec(&recipe)?, true)?)?;
+        }
+
+        Ok(StoredFile { hash, size: recipe.size, chunks: recipe.chunks.len(), new_chunks })
+    }
+
+    /// Reads the recipe of a stored file.
+    ///
+    /// # Errors
+    /// * `CratisError::IoError` if the file is not stored
+    /// * `CratisError::BackupFailure` or `CratisError::SerializationError` if the recipe is corrupt
+    /// * `CratisError::EncryptionError` if the recipe cannot be decrypted with the store key
+    pub fn file_recipe(&self, hash: &str) -> CratisResult<FileRecipe> {
+        let data = self.decode(&fs::read(self.file_path(hash)?)?)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Reassembles a stored file from its chunks and writes it to the given writer.
+    ///
+    /// Every chunk is verified against its hash before it is written.
+    ///
+    /// # Returns
+    /// * `Ok(u64)` - The number of bytes written
+    ///
+    /// # Errors
+    /// * `CratisError::IoError` if a chunk is missing or writing fails
+    /// * `CratisError::BackupFailure` if a chunk does not match its hash
+    pub fn read_file<W: Write>(&self, hash: &str, writer: &mut W) -> CratisResult<u64> {
+        let recipe = self.file_recipe(hash)?;
+        let mut written = 0;
+
+        for chunk in &recipe.chunks {
+            let data = self.get(&chunk.hash)?;
+            if self.hash_bytes(&data) != chunk.hash {
+                return Err(CratisError::BackupFailure("Stored chunk does not match its hash"));
+            }
+
+            writer.write_all(&data)?;
+            written += data.len() as u64;
+        }
+
+        Ok(written)
+    }
+
+    /// Reads an object exactly as it is stored on disk, i.e. compressed and encrypted.
+    ///
+    /// Together with `put_encoded` this copies objects between stores without decoding them,
+    /// so a server can hold objects of an encrypted repository without knowing its key.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest
+    /// * `CratisError::IoError` if the object does not exist or cannot be read
+    pub fn get_encoded(&self, hash: &str) -> CratisResult<Vec<u8>> {
+        Ok(fs::read(self.object_path(hash)?)?)
+    }
+
+    /// Stores an object that was encoded by another store (see `get_encoded`).
+    ///
+    /// The contents cannot be checked against `hash` without the key of the originating store,
+    /// so corrupt uploads are only detected when the object is read back and decoded. Use
+    /// `put_verified` wherever the store can decode the object.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the object was stored, `Ok(false)` if it already existed
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest
+    /// * `CratisError::IoError` if the object cannot be written
+    pub fn put_encoded(&self, hash: &str, data: &[u8]) -> CratisResult<bool> {
+        let target = self.object_path(hash)?;
+        if target.is_file() {
+            return Ok(false);
+        }
+
+        self.write_atomic(&target, data)?;
+        Ok(true)
+    }
+
+    /// Stores an object encoded by another store with the same key (or none), after checking
+    /// that it decodes to contents matching `hash`.
+    ///
+    /// Since an existing object is never replaced, this keeps anyone from planting other
+    /// contents under a digest before the real object is uploaded.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the object was stored, `Ok(false)` if it already existed
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest or does not match the object
+    /// * `CratisError::IoError` if the object cannot be written
+    pub fn put_verified(&self, hash: &str, data: &[u8]) -> CratisResult<bool> {
+        let decoded = self.decode(data).map_err(|_| CratisError::InvalidInput("Object cannot be decoded"))?;
+        if self.hash_bytes(&decoded) != hash {
+            return Err(CratisError::InvalidInput("Object does not match its hash"));
+        }
+
+        self.put_encoded(hash, data)
+    }
+
+    /// Stores a file recipe encoded by another store with the same key (or none), after checking
+    /// that the chunks it lists are stored and together match `hash`.
+    ///
+    /// The chunks have to be stored first. Each of them is read back, so this costs as much as
+    /// reading the whole file.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the recipe was stored, `Ok(false)` if it already existed
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest, the recipe is malformed,
+    ///   refers to a missing chunk or does not match the file
+    /// * `CratisError::IoError` if a chunk cannot be read or the recipe cannot be written
+    pub fn put_verified_file(&self, hash: &str, data: &[u8]) -> CratisResult<bool> {
+        if self.file_path(hash)?.is_file() {
+            return Ok(false);
+        }
+
+        let recipe: FileRecipe = self
+            .decode(data)
+            .and_then(|decoded| Ok(serde_json::from_slice(&decoded)?))
+            .map_err(|_| CratisError::InvalidInput("File recipe cannot be decoded"))?;
+
+        let mut hasher = self.hasher();
+        let mut size = 0;
+        for chunk in &recipe.chunks {
+            let contents = match self.get(&chunk.hash) {
+                Ok(contents) => contents,
+                Err(CratisError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+                    return Err(CratisError::InvalidInput("File recipe refers to a missing chunk"));
+                }
+                Err(e) => return Err(e),
+            };
+
+            if contents.len() as u64 != chunk.length {
+                return Err(CratisError::InvalidInput("File recipe does not match its chunks"));
+            }
+            hasher.update(&contents);
+            size += chunk.length;
+        }
+
+        if size != recipe.size || hasher.finalize().to_hex().as_str() != hash {
+            return Err(CratisError::InvalidInput("File recipe does not match its hash"));
+        }
+
+        self.put_encoded_file(hash, data)
+    }
+
+    /// Reads the recipe of a stored file exactly as it is stored on disk.
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest
+    /// * `CratisError::IoError` if the file is not stored or cannot be read
+    pub fn get_encoded_file(&self, hash: &str) -> CratisResult<Vec<u8>> {
+        Ok(fs::read(self.file_path(hash)?)?)
+    }
+
+    /// Stores the recipe of a file that was encoded by another store (see `get_encoded_file`).
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the recipe was stored, `Ok(false)` if it already existed
+    ///
+    /// # Errors
+    /// * `CratisError::InvalidInput` if `hash` is not a valid digest
+    /// * `CratisError::IoError` if the recipe cannot be written
+    pub fn put_encoded_file(&self, hash: &str, data: &[u8]) -> CratisResult<bool> {
+        let target = self.file_path(hash)?;
+        if target.is_file() {
+            return Ok(false);
+        }
+
+        self.write_atomic(&target, data)?;
+        Ok(true)
+    }
+
+    /// Returns the digests of all stored objects.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the object directory cannot be read.
+    pub fn object_hashes(&self) -> CratisResult<Vec<String>> {
+        list_fan_out(&self.root.join(OBJECTS_DIR))
+    }
+
+    /// Returns the whole-file digests of all stored files.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the file directory cannot be read.
+    pub fn file_hashes(&self) -> CratisResult<Vec<String>> {
+        list_fan_out(&self.root.join(FILES_DIR))
+    }
+
+    /// Deletes an object and returns the number of bytes freed.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the object cannot be removed.
+    pub fn remove_object(&self, hash: &str) -> CratisResult<u64> {
+        remove_counted(&self.object_path(hash)?)
+    }
+
+    /// Deletes the recipe of a stored file and returns the number of bytes freed.
+    ///
+    /// The chunks of the file are left in place.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the recipe cannot be removed.
+    pub fn remove_file(&self, hash: &str) -> CratisResult<u64> {
+        remove_counted(&self.file_path(hash)?)
+    }
+
+    /// Returns the size on disk of an object.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the object does not exist.
+    pub fn object_size(&self, hash: &str) -> CratisResult<u64> {
+        Ok(fs::metadata(self.object_path(hash)?)?.len())
+    }
+
+    /// Returns the size on disk of a file recipe.
+    ///
+    /// # Errors
+    /// Returns `CratisError::IoError` if the recipe does not exist.
+    pub fn file_size(&self, hash: &str) -> CratisResult<u64> {
+        Ok(fs::metadata(self.file_path(hash)?)?.len())
+    }
+
+    /// Compresses (if `compressible`) and then encrypts (if the store has a key) a buffer.
+    fn encode(&self, data: &[u8], compressible: bool) -> CratisResult<Vec<u8>> {
+        let encoded = if compressible {
+            self.compression.encode(data)?
+        } else {
+            self.compression.encode_raw(data)
+        };
+
+        match &self.key {
+            Some(key) => key.encrypt(&encoded),
+            None => Ok(encoded),
+        }
+    }
+
+    /// Reverses `encode`.
+    fn decode(&self, data: &[u8]) -> CratisResult<Vec<u8>> {
+        match &self.key {
+            Some(key) => compress::decode(&key.decrypt(data)?),
+            None => compress::decode(data),
+        }
+    }
+
+    /// Writes `data` to `target` via the staging area, unless `target` already exists.
+    ///
+    /// An existing target is left untouched, which makes concurrent writers of the same
+    /// content harmless.
+    fn write_atomic(&self, target: &Path, data: &[u8]) -> CratisResult<()> {
+        if target.is_file() {
+            return Ok(());
+        }
+
+        let tmp_path = self.tmp_path();
+        let result = (|| {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(data)?;
+            tmp.sync_all()?;
+
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&tmp_path, target)?;
+
+            Ok(())
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.root.join(TMP_DIR).join(format!("{}-{}.tmp", std::process::id(), n))
+    }
+}
+
+fn list_fan_out(dir: &Path) -> CratisResult<Vec<String>> {
+    let mut hashes = Vec::new();
+
+    for prefix in fs::read_dir(dir)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+
+        for object in fs::read_dir(prefix.path())? {
+            let hash = format!("{}{}", prefix.file_name().to_string_lossy(), object?.file_name().to_string_lossy());
+            if validate_hash(&hash).is_ok() {
+                hashes.push(hash);
+            }
+        }
+    }
+
+    hashes.sort();
+    Ok(hashes)
+}
+
+fn remove_counted(path: &Path) -> CratisResult<u64> {
+    let size = fs::metadata(path)?.len();
+    fs::remove_file(path)?;
+
+    Ok(size)
+}
+
+fn fan_out(dir: &Path, hash: &str) -> CratisResult<PathBuf> {
+    validate_hash(hash)?;
+    let (prefix, rest) = hash.split_at(2);
+
+    Ok(dir.join(prefix).join(rest))
+}
+
+/// Checks that a string is a lowercase 64 character hex digest as produced by BLAKE3.
+///
+/// # Errors
+/// Returns `CratisError::InvalidInput` if the string has the wrong length or contains non-hex characters.
+pub fn validate_hash(hash: &str) -> CratisResult<()> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return Err(CratisError::InvalidInput("Object hash must be a 64 character hex digest"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn store() -> (TempDir, ObjectStore) {
+        let dir = TempDir::new().unwrap();
+        let store = ObjectStore::open(dir.path()).unwrap();
+        (dir, store)
+    }
+
+    /// Pseudo-random, poorly compressible content large enough to span several chunks.
+    fn content(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn put_bytes_round_trip() {
+        let (_dir, store) = store();
+        let hash = store.put_bytes(b"hello cratis").unwrap();
+
+        assert_eq!(hash, blake3::hash(b"hello cratis").to_hex().to_string());
+        assert!(store.contains(&hash));
+        assert_eq!(store.get(&hash).unwrap(), b"hello cratis");
+    }
+
+    #[test]
+    fn put_file_round_trip() {
+        let (dir, store) = store();
+        let data = content(3 * 1024 * 1024, 1);
+        let path = dir.path().join("input.bin");
+        fs::write(&path, &data).unwrap();
+
+        let stored = store.put_file(&path).unwrap();
+        assert_eq!(stored.hash, crate::utils::hash_file(path.to_str().unwrap()).unwrap());
+        assert_eq!(stored.size, data.len() as u64);
+        assert!(stored.chunks > 1);
+        assert_eq!(stored.new_chunks, stored.chunks);
+
+        let mut restored = Vec::new();
+        assert_eq!(store.read_file(&stored.hash, &mut restored).unwrap(), data.len() as u64);
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let (_dir, store) = store();
+        let data = content(2 * 1024 * 1024, 2);
+
+        let first = store.put_reader(data.as_slice()).unwrap();
+        let second = store.put_reader(data.as_slice()).unwrap();
+
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(first.new_chunks, first.chunks);
+        assert_eq!(second.new_chunks, 0);
+        assert_eq!(store.object_hashes().unwrap().len(), first.chunks);
+        assert_eq!(store.file_hashes().unwrap(), vec![first.hash]);
+    }
+
+    #[test]
+    fn validate_hash_rejects_malformed_digests() {
+        let valid = "a".repeat(64);
+        assert!(validate_hash(&valid).is_ok());
+
+        for invalid in ["", "abc", &"a".repeat(63), &"a".repeat(65), &"A".repeat(64), &"g".repeat(64), &format!("../{}", "a".repeat(61))] {
+            assert!(matches!(validate_hash(invalid), Err(CratisError::InvalidInput(_))), "{invalid:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn invalid_hashes_are_not_looked_up() {
+        let (_dir, store) = store();
+
+        assert!(!store.contains("../../etc/passwd"));
+        assert!(matches!(store.get("../../etc/passwd"), Err(CratisError::InvalidInput(_))));
+        assert!(matches!(store.object_path("xyz"), Err(CratisError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn verified_uploads_must_match_their_digest() {
+        let (_source_dir, source) = store();
+        let (_dir, store) = store();
+        let data = content(2 * 1024 * 1024, 3);
+        let stored = source.put_reader(data.as_slice()).unwrap();
+        let other = source.put_bytes(b"other").unwrap();
+        let recipe = source.get_encoded_file(&stored.hash).unwrap();
+
+        let forged = source.get_encoded(&other).unwrap();
+        assert!(matches!(store.put_verified(&stored.hash, &forged), Err(CratisError::InvalidInput(_))));
+        assert!(matches!(store.put_verified_file(&stored.hash, &recipe), Err(CratisError::InvalidInput(_))));
+
+        for chunk in source.file_recipe(&stored.hash).unwrap().chunks {
+            assert!(store.put_verified(&chunk.hash, &source.get_encoded(&chunk.hash).unwrap()).unwrap());
+        }
+        assert!(matches!(store.put_verified_file(&other, &recipe), Err(CratisError::InvalidInput(_))));
+        assert!(store.put_verified_file(&stored.hash, &recipe).unwrap());
+
+        let mut restored = Vec::new();
+        store.read_file(&stored.hash, &mut restored).unwrap();
+        assert_eq!(restored, data);
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{CratisError, CratisResult};
+use crate::config::SizePolicy;
+use crate::index::{FileVersion, SkippedFile};
+use crate::limits::SizeLimit;
+use crate::repository::Repository;
+use crate::retry::RetryPolicy;
+use crate::utils::{modified_secs, timestamp_now, EventAction};
+
+/// What the sync engine did with a single path of a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// A new revision was recorded. `new_object` is `false` if the content was already stored.
+    /// `new_chunks` counts the chunks that were not stored before.
+    Stored { hash: String, size: u64, new_object: bool, new_chunks: usize },
+    /// The content matches the latest recorded revision, nothing was recorded.
+    Unchanged { hash: String },
+    /// The file was moved here from `from` and its history was carried over, see
+    /// `VersionIndex::record_move`. Nothing was read or stored.
+    Moved { from: PathBuf, hash: String },
+    /// The path no longer exists and a deletion was recorded.
+    Tombstoned,
+    /// The path is a directory that was not recorded yet and was recorded now.
+    Directory,
+    /// The path was ignored, e.g. because it is not a regular file or is already recorded as deleted.
+    Skipped(&'static str),
+    /// The file exceeds `max_file_size_mb` and was not backed up. `warned` is `true` if the skip
+    /// was recorded in the index (`SizePolicy::Warn`).
+    TooLarge { size: u64, warned: bool },
+}
+
+/// The result of syncing a single path.
+#[derive(Debug)]
+pub struct SyncResult {
+    pub path: PathBuf,
+    pub action: EventAction,
+    pub outcome: CratisResult<SyncOutcome>,
+}
+
+/// Turns batches of filesystem events into stored objects and recorded revisions.
+#[derive(Debug, Clone)]
+pub struct SyncEngine {
+    repo: Repository,
+    retry: RetryPolicy,
+    size_limit: SizeLimit,
+}
+
+impl SyncEngine {
+    /// Creates a sync engine writing into the given repository.
+    pub fn new(repo: Repository) -> Self {
+        Self { repo, retry: RetryPolicy::default(), size_limit: SizeLimit::unlimited() }
+    }
+
+    /// Sets the policy for retrying file reads that fail because the file is busy or locked.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the size limit files are checked against before they are read.
+    pub fn with_size_limit(mut self, size_limit: SizeLimit) -> Self {
+        self.size_limit = size_limit;
+        self
+    }
+
+    /// Returns the size limit files are checked against.
+    pub fn size_limit(&self) -> &SizeLimit {
+        &self.size_limit
+    }
+
+    /// Returns the repository the engine writes into.
+    pub fn repository(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// Syncs a debounced batch of filesystem events into the repository.
+    ///
+    /// Events are collapsed per path, and the action recorded for a path is derived from its
+    /// current state on disk rather than from the raw events, since a debounced batch can
+    /// contain e.g. both a create and a delete of the same path:
+    /// * Existing files are hashed and their content is stored if it differs from the latest revision
+    /// * Missing paths are recorded as deletions (tombstones)
+    ///
+    /// A created file with the same content as a file deleted in the same batch is recorded as
+    /// a move of that file (see `VersionIndex::record_move`). This catches renames the watcher
+    /// could not pair up itself, e.g. on platforms without rename tracking or across restarts.
+    ///
+    /// All revisions of a batch share the same timestamp. A failure on one path does not stop
+    /// the remaining paths from being synced.
+    ///
+    /// # Arguments
+    /// * `batch` - The set of changed paths and the action reported for each
+    ///
+    /// # Returns
+    /// One `SyncResult` per distinct path, in path order.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let engine = SyncEngine::new(Repository::open("/var/lib/cratis")?);
+    /// for result in engine.sync_batch(&pending_events) {
+    ///     println!("{:?}: {:?}", result.path, result.outcome);
+    /// }
+    /// ```
+    pub fn sync_batch(&self, batch: &HashSet<(PathBuf, EventAction)>) -> Vec<SyncResult> {
+        let mut by_path: BTreeMap<&Path, Vec<EventAction>> = BTreeMap::new();
+        for (path, action) in batch {
+            by_path.entry(path.as_path()).or_default().push(action.clone());
+        }
+
+        let collapsed: Vec<(&Path, EventAction)> = by_path
+            .into_iter()
+            .map(|(path, actions)| (path, collapse_actions(path, &actions)))
+            .collect();
+
+        let moves = self.detect_moves(&collapsed);
+        let timestamp = timestamp_now();
+
+        collapsed
+            .into_iter()
+            .map(|(path, action)| {
+                if let Some((from, hash)) = moves.get(path) {
+                    let outcome = match &timestamp {
+                        Ok(timestamp) => self
+                            .repo
+                            .index
+                            .record_move(from, path, *timestamp)
+                            .map(|_| SyncOutcome::Moved { from: from.to_path_buf(), hash: hash.clone() }),
+                        Err(_) => Err(CratisError::Internal("Failed to get system time.")),
+                    };
+                    let action = EventAction::Rename { from: from.to_path_buf(), to: path.to_path_buf() };
+
+                    return SyncResult { path: path.to_path_buf(), action, outcome };
+                }
+
+                let outcome = match &timestamp {
+                    Ok(_) if moves.values().any(|(from, _)| *from == path) => Ok(SyncOutcome::Skipped("Moved to another path")),
+                    Ok(timestamp) => self.sync_path(path, action.clone(), *timestamp),
+                    Err(_) => Err(CratisError::Internal("Failed to get system time.")),
+                };
+
+                SyncResult { path: path.to_path_buf(), action, outcome }
+            })
+            .collect()
+    }
+
+    /// Records moves reported by the filesystem watcher.
+    ///
+    /// The watcher pairs both halves of a rename, so the content at the new path is known to be
+    /// the content last recorded at the old path and is not read again. Moving a directory
+    /// moves its own record and every recorded file and directory below it. Moves are applied
+    /// in order, so a file renamed twice before the batch is synced ends up at its final path.
+    ///
+    /// The new path should still be synced as part of the following batch, in case the file
+    /// was changed after it was moved.
+    ///
+    /// # Arguments
+    /// * `moves` - The reported moves as `(from, to)`, oldest first
+    ///
+    /// # Returns
+    /// One `EventAction::Rename` result per moved file at its new path, or a
+    /// `SyncOutcome::Skipped` result at the new path if no file was recorded at the old one.
+    pub fn sync_moves(&self, moves: &[(PathBuf, PathBuf)]) -> Vec<SyncResult> {
+        let timestamp = timestamp_now();
+        let mut results = Vec::new();
+
+        for (from, to) in moves {
+            let moved = match &timestamp {
+                Ok(timestamp) => self.move_tree(from, to, *timestamp),
+                Err(_) => Err(CratisError::Internal("Failed to get system time.")),
+            };
+
+            let action = EventAction::Rename { from: from.clone(), to: to.clone() };
+            match moved {
+                Ok(moved) if moved.is_empty() => results.push(SyncResult {
+                    path: to.clone(),
+                    action,
+                    outcome: Ok(SyncOutcome::Skipped("Nothing recorded at the old path")),
+                }),
+                Ok(moved) => results.extend(moved.into_iter().map(|(from, to, hash)| SyncResult {
+                    path: to.clone(),
+                    action: EventAction::Rename { from: from.clone(), to },
+                    outcome: Ok(SyncOutcome::Moved { from, hash }),
+                })),
+                Err(e) => results.push(SyncResult { path: to.clone(), action, outcome: Err(e) }),
+            }
+        }
+
+        results
+    }
+
+    /// Syncs a single path with an already collapsed action.
+    ///
+    /// Reading the file is retried according to the engine's `RetryPolicy` if it fails with a
+    /// transient error, e.g. because another process holds a lock on it.
+    ///
+    /// Files exceeding the engine's `SizeLimit` are not read at all. Depending on the matching
+    /// `SizePolicy` the skip is recorded in the index, and the record is cleared again once
+    /// the file is backed up or deleted.
+    ///
+    /// For `EventAction::MetadataChange` the file is not read either, as long as its size and
+    /// modification time still match the latest revision. Directories are recorded once, without
+    /// content.
+    ///
+    /// # Errors
+    /// Returns any error raised while hashing, storing or recording the path.
+    pub fn sync_path(&self, path: &Path, action: EventAction, timestamp: u64) -> CratisResult<SyncOutcome> {
+        let latest = self.repo.index.latest(path)?;
+
+        if matches!(action, EventAction::Delete | EventAction::DeleteDir) {
+            self.repo.index.remove_skipped(path)?;
+            let Some(latest) = latest.filter(|v| !v.is_deleted()) else {
+                return Ok(SyncOutcome::Skipped("No live revision to delete"));
+            };
+
+            let action = if latest.is_directory() { EventAction::DeleteDir } else { EventAction::Delete };
+            self.repo.index.record(path, &FileVersion { timestamp, hash: None, size: 0, mtime: 0, action, moved_to: None })?;
+            return Ok(SyncOutcome::Tombstoned);
+        }
+
+        let metadata = fs::metadata(path)?;
+        if metadata.is_dir() {
+            if latest.is_some_and(|v| v.action == EventAction::CreateDir) {
+                return Ok(SyncOutcome::Skipped("Directory already recorded"));
+            }
+
+            let mtime = modified_secs(&metadata);
+            self.repo.index.record(
+                path,
+                &FileVersion { timestamp, hash: None, size: 0, mtime, action: EventAction::CreateDir, moved_to: None },
+            )?;
+            return Ok(SyncOutcome::Directory);
+        }
+        if !metadata.is_file() {
+            return Ok(SyncOutcome::Skipped("Not a regular file"));
+        }
+
+        if action == EventAction::MetadataChange
+            && let Some(latest) = &latest
+            && let Some(hash) = &latest.hash
+            && latest.size == metadata.len()
+            && latest.mtime == modified_secs(&metadata)
+        {
+            return Ok(SyncOutcome::Unchanged { hash: hash.clone() });
+        }
+
+        let mut size = metadata.len();
+        match self.size_limit.decide(path, size) {
+            SizePolicy::Allow => self.repo.index.remove_skipped(path)?,
+            SizePolicy::Skip => {
+                self.repo.index.remove_skipped(path)?;
+                return Ok(SyncOutcome::TooLarge { size, warned: false });
+            }
+            SizePolicy::Warn => {
+                let limit = self.size_limit.max_bytes().unwrap_or_default();
+                self.repo.index.record_skipped(path, &SkippedFile { timestamp, size, limit })?;
+                return Ok(SyncOutcome::TooLarge { size, warned: true });
+            }
+        }
+
+        let mut hash = self.retry.retry(|| self.repo.store.hash_file(path))?;
+
+        if let Some(latest) = &latest
+            && !latest.is_deleted()
+            && latest.hash.as_deref() == Some(hash.as_str())
+        {
+            return Ok(SyncOutcome::Unchanged { hash });
+        }
+
+        let mtime = modified_secs(&metadata);
+        let mut new_chunks = 0;
+        let new_object = !self.repo.store.contains_file(&hash);
+        if new_object {
+            // Re-hashed while chunking, so the recorded hash always matches the stored bytes.
+            let stored = self.retry.retry(|| self.repo.store.put_file(path))?;
+            hash = stored.hash;
+            size = stored.size;
+            new_chunks = stored.new_chunks;
+        }
+
+        // Anything but a creation changed the content, whatever the watcher reported.
+        let action = if action == EventAction::Create { action } else { EventAction::Modify };
+        self.repo.index.record(path, &FileVersion { timestamp, hash: Some(hash.clone()), size, mtime, action, moved_to: None })?;
+
+        Ok(SyncOutcome::Stored { hash, size, new_object, new_chunks })
+    }
+
+    /// Moves the recorded file at `from`, or the recorded directory and everything below it,
+    /// to `to`.
+    ///
+    /// # Returns
+    /// Every moved file as `(from, to, hash)`.
+    fn move_tree(&self, from: &Path, to: &Path, timestamp: u64) -> CratisResult<Vec<(PathBuf, PathBuf, String)>> {
+        // Also moves the record of the directory itself, if there is one.
+        if let Some(version) = self.repo.index.record_move(from, to, timestamp)?
+            && !version.is_directory()
+        {
+            return Ok(vec![(from.to_path_buf(), to.to_path_buf(), version.hash.unwrap_or_default())]);
+        }
+
+        let mut moved = Vec::new();
+
+        for path in self.repo.index.paths_under(from)? {
+            let Ok(relative) = path.strip_prefix(from) else {
+                continue;
+            };
+
+            let target = to.join(relative);
+            if let Some(FileVersion { hash: Some(hash), .. }) = self.repo.index.record_move(&path, &target, timestamp)? {
+                moved.push((path, target, hash));
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Pairs files created in a batch with files deleted in it that had the same content.
+    ///
+    /// Only sizes are compared until a candidate turns up, so files are hashed here only if a
+    /// deleted file of the same size exists. Errors just rule a path out as a move target.
+    ///
+    /// # Returns
+    /// The move source and content hash for every path that was moved, keyed by its new path.
+    fn detect_moves<'a>(&self, collapsed: &[(&'a Path, EventAction)]) -> HashMap<&'a Path, (&'a Path, String)> {
+        let mut vanished: Vec<(&Path, FileVersion)> = collapsed
+            .iter()
+            .filter(|(_, action)| *action == EventAction::Delete)
+            .filter_map(|(path, _)| {
+                let latest = self.repo.index.latest(path).ok().flatten()?;
+                (!latest.is_deleted() && latest.hash.is_some()).then_some((*path, latest))
+            })
+            .collect();
+
+        let mut moves = HashMap::new();
+
+        for (path, action) in collapsed {
+            if vanished.is_empty() {
+                break;
+            }
+            // Only new files can be move targets, not ones with a live revision of their own.
+            let live = self.repo.index.latest(path).ok().flatten().is_some_and(|v| !v.is_deleted());
+            if *action != EventAction::Create || live {
+                continue;
+            }
+
+            let Ok(metadata) = fs::metadata(path) else {
+                continue;
+            };
+            if !metadata.is_file() || !vanished.iter().any(|(_, version)| version.size == metadata.len()) {
+                continue;
+            }
+
+            let Ok(hash) = self.retry.retry(|| self.repo.store.hash_file(path)) else {
+                continue;
+            };
+            if let Some(i) = vanished.iter().position(|(_, version)| version.hash.as_deref() == Some(hash.as_str())) {
+                let (from, _) = vanished.swap_remove(i);
+                moves.insert(*path, (from, hash));
+            }
+        }
+
+        moves
+    }
+}
+
+/// Derives the action to record for a path from its raw events and its current state on disk.
+fn collapse_actions(path: &Path, actions: &[EventAction]) -> EventAction {
+    match fs::metadata(path) {
+        Err(_) if actions.contains(&EventAction::DeleteDir) => EventAction::DeleteDir,
+        Err(_) => EventAction::Delete,
+        Ok(metadata) if metadata.is_dir() => EventAction::CreateDir,
+        Ok(_) if actions.contains(&EventAction::Create) => EventAction::Create,
+        Ok(_) if actions.iter().all(|action| *action == EventAction::MetadataChange) => EventAction::MetadataChange,
+        Ok(_) => EventAction::Modify,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn moving_a_directory_moves_everything_below_it() {
+        let dir = TempDir::new().unwrap();
+        let engine = SyncEngine::new(Repository::open(dir.path().join("repo")).unwrap());
+        let (from, to) = (dir.path().join("photos"), dir.path().join("archive"));
+        fs::create_dir_all(from.join("2024")).unwrap();
+        fs::write(from.join("2024/a.jpg"), b"pixels").unwrap();
+
+        let batch: HashSet<_> = [&from, &from.join("2024"), &from.join("2024/a.jpg")]
+            .into_iter()
+            .map(|path| (path.clone(), EventAction::Create))
+            .collect();
+        assert!(engine.sync_batch(&batch).iter().all(|result| result.outcome.is_ok()));
+        let hash = engine.repo.index.latest(&from.join("2024/a.jpg")).unwrap().unwrap().hash;
+
+        fs::rename(&from, &to).unwrap();
+        let results = engine.sync_moves(&[(from.clone(), to.clone())]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, to.join("2024/a.jpg"));
+        assert!(matches!(&results[0].outcome, Ok(SyncOutcome::Moved { from: moved, .. }) if *moved == from.join("2024/a.jpg")));
+
+        let index = &engine.repo.index;
+        assert_eq!(index.latest(&to.join("2024/a.jpg")).unwrap().unwrap().hash, hash);
+        assert!(index.latest(&to).unwrap().unwrap().is_directory());
+        assert!(index.latest(&to.join("2024")).unwrap().unwrap().is_directory());
+        for path in [&from, &from.join("2024"), &from.join("2024/a.jpg")] {
+            assert!(index.latest(path).unwrap().unwrap().is_deleted(), "{path:?} is still live");
+        }
+    }
+
+    #[test]
+    fn moves_are_applied_in_order() {
+        let dir = TempDir::new().unwrap();
+        let engine = SyncEngine::new(Repository::open(dir.path().join("repo")).unwrap());
+        let (first, second, third) = (dir.path().join("a.txt"), dir.path().join("b.txt"), dir.path().join("c.txt"));
+        fs::write(&first, b"contents").unwrap();
+        engine.sync_path(&first, EventAction::Create, 100).unwrap();
+
+        let results = engine.sync_moves(&[(first.clone(), second.clone()), (second.clone(), third.clone())]);
+        assert_eq!(results.iter().map(|result| result.path.clone()).collect::<Vec<_>>(), vec![second.clone(), third.clone()]);
+        assert!(engine.repo.index.latest(&third).unwrap().is_some_and(|version| !version.is_deleted()));
+        assert!(engine.repo.index.latest(&second).unwrap().unwrap().is_deleted());
+
+        let results = engine.sync_moves(&[(dir.path().join("unrecorded.txt"), first)]);
+        assert!(matches!(results[0].outcome, Ok(SyncOutcome::Skipped(_))));
+    }
+}
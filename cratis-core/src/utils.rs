@@ -1,10 +1,13 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use crate::error::{CratisError, CratisResult};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::fs::{File, Metadata};
+use std::io::{BufReader, Read, Write};
 use blake3::Hasher;
-use notify::event::{EventKind};
+use fastcdc::v2020::StreamCDC;
+use notify::event::{CreateKind, EventKind, MetadataKind, ModifyKind, RemoveKind, RenameMode};
+use notify::Event;
+use serde::{Deserialize, Serialize};
 
 /// Verifies that a given path exists and is a directory in the filesystem.
 ///
@@ -100,6 +103,23 @@ pub fn timestamp_now() -> CratisResult<u64> {
         .map(|duration| duration.as_secs())
 }
 
+/// Returns the modification time of a file in seconds since the Unix epoch.
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+///
+/// # Returns
+/// * `u64` - The modification time, or `0` if the platform does not report it or it lies before the epoch.
+pub fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 /// Sanitizes a filename by removing or replacing invalid characters.
 /// 
 /// This function removes control characters and replaces common invalid characters
@@ -141,7 +161,7 @@ pub fn sanitize_filename(filename: &str) -> String {
 /// # Returns
 /// 
 /// * `CratisResult<String>` - THe hexadecimal string representation of the file's hash on success,
-/// or an error if the file cannot be read.
+///   or an error if the file cannot be read.
 /// 
 /// # Errors
 /// 
@@ -149,14 +169,14 @@ pub fn sanitize_filename(filename: &str) -> String {
 /// * The file cannot be opened
 /// * An error occurs while reading the file
 pub fn hash_file(path: &str) -> CratisResult<String> {
-    let file = File::open(path).map_err(|e| CratisError::IoError(e.into()))?;
+    let file = File::open(path).map_err(CratisError::IoError)?;
     let mut reader = BufReader::new(file);
 
     let mut hasher = Hasher::new();
     let mut buffer = [0u8; 1024];
 
     loop {
-        let bytes_read = reader.read(&mut buffer).map_err(|e| CratisError::IoError(e.into()))?;
+        let bytes_read = reader.read(&mut buffer).map_err(CratisError::IoError)?;
         if bytes_read == 0 {
             break;
         }
@@ -166,44 +186,162 @@ pub fn hash_file(path: &str) -> CratisResult<String> {
     Ok(hasher.finalize().to_hex().to_string())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Minimum size of a content-defined chunk in bytes.
+pub const CHUNK_MIN_SIZE: u32 = 16 * 1024;
+/// Target average size of a content-defined chunk in bytes.
+pub const CHUNK_AVG_SIZE: u32 = 64 * 1024;
+/// Maximum size of a content-defined chunk in bytes.
+pub const CHUNK_MAX_SIZE: u32 = 256 * 1024;
+
+/// A content-defined chunk of a file, addressed by the BLAKE3 hex digest of its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChunk {
+    pub hash: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Splits the contents of a reader into content-defined chunks using FastCDC.
+///
+/// Chunk boundaries depend only on the surrounding bytes, so inserting or removing data in one
+/// part of a file only changes the chunks around the edit. All other chunks keep their hash and
+/// are deduplicated against earlier versions and other files.
+///
+/// # Arguments
+///
+/// * `reader` - The source to split into chunks
+///
+/// # Returns
+///
+/// An iterator yielding the chunks in order. The chunks cover the whole input without gaps, so
+/// feeding their data into a hasher yields the same digest as `hash_file`.
+///
+/// # Errors
+///
+/// Each item is `CratisError::IoError` if reading from the source fails.
+///
+/// # Examples
+///
+/// ```ignore
+/// let file = File::open("disk.img")?;
+/// for chunk in chunk_reader(file) {
+///     let chunk = chunk?;
+///     println!("{} @ {} ({} bytes)", chunk.hash, chunk.offset, chunk.data.len());
+/// }
+/// ```
+pub fn chunk_reader<R: Read>(reader: R) -> impl Iterator<Item = CratisResult<FileChunk>> {
+    StreamCDC::new(reader, CHUNK_MIN_SIZE, CHUNK_AVG_SIZE, CHUNK_MAX_SIZE).map(|chunk| {
+        let chunk = chunk.map_err(|e| CratisError::IoError(e.into()))?;
+
+        Ok(FileChunk {
+            hash: blake3::hash(&chunk.data).to_hex().to_string(),
+            offset: chunk.offset,
+            data: chunk.data,
+        })
+    })
+}
+
+/// Opens a file and splits its contents into content-defined chunks.
+///
+/// See `chunk_reader` for details on the chunking.
+///
+/// # Errors
+///
+/// Returns `CratisError::IoError` if the file cannot be opened.
+pub fn chunk_file(path: &str) -> CratisResult<impl Iterator<Item = CratisResult<FileChunk>>> {
+    let file = File::open(path).map_err(CratisError::IoError)?;
+    Ok(chunk_reader(file))
+}
+
+/// A writer that hashes everything written through it.
+pub(crate) struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W, hasher: Hasher) -> Self {
+        Self { inner, hasher }
+    }
+
+    /// Returns the inner writer and the hex digest of everything written.
+    pub(crate) fn finish(self) -> (W, String) {
+        (self.inner, self.hasher.finalize().to_hex().to_string())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// What happened to a path, as reported by the file watcher and recorded in the version index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EventAction {
     Create,
     Modify,
     Delete,
+    /// The file was moved from `from` to `to`, its content is unchanged.
+    Rename { from: PathBuf, to: PathBuf },
+    /// Only permissions, ownership or extended attributes changed, not the content.
+    MetadataChange,
+    CreateDir,
+    DeleteDir,
     Other
 }
 
-/// Maps a file system event kind to a simplified `EventAction` enum.
+/// Maps a file system event to a simplified `EventAction` enum.
 ///
-/// This function converts complex file system event kinds into a simplified
+/// This function converts detailed file system event kinds into a simplified
 /// representation using the `EventAction` enum, making it easier to handle
 /// common file system operations.
 ///
 /// # Arguments
 ///
-/// * `kind` - A reference to an `EventKind` that represents the type of file system event
+/// * `event` - A reference to the `Event` reported by the notify backend
 ///
 /// # Returns
 ///
-/// Returns an `EventAction` enum variant corresponding to the input event kind:
-/// * `EventAction::Create` for creation events
-/// * `EventAction::Modify` for modification events
-/// * `EventAction::Delete` for removal events
-/// * `EventAction::Other` for any other event types
+/// Returns an `EventAction` enum variant corresponding to the event kind:
+/// * `EventAction::Create` / `EventAction::CreateDir` for creation events
+/// * `EventAction::Modify` for content modification events
+/// * `EventAction::MetadataChange` for permission, ownership and extended attribute changes
+/// * `EventAction::Rename` for renames reported with both paths
+/// * `EventAction::Delete` / `EventAction::DeleteDir` for removal events
+/// * `EventAction::Other` for any other event types, including access time changes
+///
+/// The halves of a rename reported in two events map to `EventAction::Delete` (the old path)
+/// and `EventAction::Create` (the new path), since pairing them up needs state across events.
 ///
 /// # Example
 ///
 /// ```ignore
-/// let event_kind = EventKind::Create(CreateKind::Any);
-/// let action = map_event_kinds(&event_kind);
-/// assert_eq!(action, EventAction::Create);
+/// let event = Event::new(EventKind::Create(CreateKind::Folder)).add_path(path);
+/// let action = map_event_kinds(&event);
+/// assert_eq!(action, EventAction::CreateDir);
 /// ```
-pub fn map_event_kinds(kind: &EventKind) -> EventAction {
-    match kind {
+pub fn map_event_kinds(event: &Event) -> EventAction {
+    match event.kind {
+        EventKind::Create(CreateKind::Folder) => EventAction::CreateDir,
         EventKind::Create(_) => EventAction::Create,
-        EventKind::Modify(_) => EventAction::Modify,
+        EventKind::Remove(RemoveKind::Folder) => EventAction::DeleteDir,
         EventKind::Remove(_) => EventAction::Delete,
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)) => EventAction::Other,
+        EventKind::Modify(ModifyKind::Metadata(_)) => EventAction::MetadataChange,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => match event.paths.as_slice() {
+            [from, to] => EventAction::Rename { from: from.clone(), to: to.clone() },
+            _ => EventAction::Other,
+        },
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => EventAction::Delete,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => EventAction::Create,
+        EventKind::Modify(_) => EventAction::Modify,
         _ => EventAction::Other
     }
-}
\ No newline at end of file
+}
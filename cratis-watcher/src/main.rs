@@ -1,30 +1,61 @@
-#[allow(unused_must_use)]
+mod reload;
+mod rename;
+mod scheduler;
 
-use notify::{RecommendedWatcher, Event, RecursiveMode, Result, Watcher};
-use std::collections::HashSet;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, Event, EventKind, RecursiveMode, Result, Watcher};
+use std::collections::BTreeSet;
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
-use cratis_core::error::{display_error, CratisError};
-use cratis_core::config::{get_config, load_config, CratisConfig}; // Remove load_config() once config loading is properly implemented
-use cratis_core::utils::{EventAction, map_event_kinds};
-use glob::Pattern;
+use cratis_core::client::{UploadClient, UploadReport};
+use cratis_core::error::{display_error, CratisError, CratisResult};
+use cratis_core::exclude::ExcludeRules;
+use cratis_core::config::{config_flag, find_config, load_config, BackupConfig, CratisConfig, SizePolicy};
+use cratis_core::limits::SizeLimit;
+use cratis_core::queue::{QueuedBatch, WorkQueue};
+use cratis_core::reconcile::reconcile;
+use cratis_core::repository::Repository;
+use cratis_core::snapshot::{take_snapshot, DEFAULT_MAX_CHAIN_LENGTH};
+use cratis_core::sync::{SyncEngine, SyncOutcome};
+use cratis_core::utils::{EventAction, map_event_kinds, timestamp_now, to_human_readable_size};
+use reload::{restart_required, BackupChanges, ReloadTrigger};
+use rename::RenameTracker;
+use scheduler::Scheduler;
 
 /// Entry point for the Cratis file watcher application.
 ///
 /// This function initializes and runs the file watching system with the following steps:
-/// 1. Loads configuration from a specified YAML file
+/// 1. Loads configuration from the YAML file given with `--config`, `CRATIS_CONFIG` or found in
+///    the standard locations (see `find_config`)
 /// 2. Sets up file system watching for configured directories
-/// 3. Implements event debouncing with a 500ms window
-/// 4. Processes file system events while filtering out temporary files and excluded paths
+/// 3. Reconciles the watch directories with the version index to catch changes made while the
+///    watcher was not running
+/// 4. Implements event debouncing with a 500ms window
+/// 5. Processes file system events while filtering out temporary files, excluded paths and
+///    files exceeding `max_file_size_mb`, pairing up renames so moved files keep their history
+/// 6. Persists every event in a durable work queue and syncs the queue into the local backup
+///    repository once the debounce window has passed, replaying leftover events on startup
+/// 7. Takes a full scan-and-snapshot every `interval_seconds`, if configured, on a worker
+///    thread so events keep being queued while it runs
+/// 8. Pushes new revisions and snapshots to the cratis-api server, if `server.enabled` is set
+/// 9. Reloads the configuration when the file changes or on `SIGHUP`, adding and removing
+///    watched directories and recompiling exclusion patterns without restarting
 ///
 /// # Configuration
 ///
 /// The application expects a configuration file that specifies:
 /// * Watch directories to monitor
 /// * Directories to exclude from monitoring
+/// * Optionally an interval for scheduled snapshots, and whether real-time watching is enabled
+///
+/// Every backup set in `backup_sets` is watched, queued and snapshotted on its own, with its
+/// history in a separate namespace of the index.
 ///
 /// # Error Handling
 ///
@@ -38,207 +69,756 @@ use glob::Pattern;
 /// Uses a channel-based approach for event handling with:
 /// * Debouncing mechanism to prevent event flooding
 /// * Event filtering for temporary files
-/// * Pattern-based exclusion system
+/// * Gitignore-style exclusion rules, including per-directory `.cratisignore` files
 fn main() {
-    let _ = load_config("/home/raphael/Development/Cratis/cratis.yml");
+    let config_path = match find_config(config_flag(env::args().skip(1)).as_deref()) {
+        Ok(path) => path,
+        Err(e) => display_error(&e, false),
+    };
 
-    let config: &CratisConfig = get_config();
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => display_error(&e, false),
+    };
 
-    let (tx, rx) = channel();
+    let repo = match Repository::open_configured(&config) {
+        Ok(repo) => repo,
+        Err(e) => display_error(&e, false),
+    };
 
-    let watch_dirs: &Vec<String> = &config.backup.watch_directories;
-    let exclude_dirs: &Vec<String> = &config.backup.exclude.clone().unwrap_or_default();
+    let mut settings = match Settings::new(config, repo) {
+        Ok(settings) => settings,
+        Err(e) => display_error(&e, false),
+    };
 
-    let mut exclude_patterns: Vec<Pattern> = Vec::new();
-    
-    if !exclude_dirs.is_empty() {
-        for pattern in exclude_dirs.iter() {
-            match Pattern::new(pattern) {
-                Ok(p) => exclude_patterns.push(p),
-                Err(e) => display_error(&CratisError::ConfigError(format!("Invalid exclusion pattern '{}': {}", pattern, e)), false)
-            }
+    let queued: usize = settings.sets.iter().map(|set| set.queue.len()).sum();
+    if queued > 0 {
+        println!("Replaying {} queued paths from the previous run", queued);
+    }
+
+    let (tx, rx) = channel();
+    let watch_dirs = settings.watch_directories();
+    let mut watcher = if watch_dirs.is_empty() {
+        None
+    } else {
+        match start_watching(&watch_dirs, tx.clone()) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => display_error(&CratisError::WatcherError(e.to_string()), false),
         }
+    };
+    let mut reload = ReloadTrigger::new(&config_path);
+
+    for set in &settings.sets {
+        enqueue_differences(set, &set.backup.watch_directories);
+        process_queue(&set.engine, &set.queue, settings.uploader.as_ref());
     }
-    
-    let _watcher = start_watching(&watch_dirs, tx).unwrap();
 
     let debounce_duration: Duration = Duration::from_millis(500);
+    let rename_timeout: Duration = Duration::from_millis(500);
     let mut last_event_time: Instant = Instant::now();
-    let mut pending_events: HashSet<(std::path::PathBuf, EventAction)> = HashSet::new();
+    let mut has_new_events = false;
+    let mut renames = RenameTracker::new();
 
     loop {
+        if reload.poll() {
+            reload_config(&config_path, &mut settings, &mut watcher, &tx);
+
+            if settings.sets.iter().any(|set| !set.queue.is_empty()) {
+                has_new_events = true;
+                last_event_time = Instant::now();
+            }
+        }
+
+        let sets = &settings.sets;
+
         match rx.recv_timeout(Duration::from_millis(100)) {
             Ok(event) => {
-                for path in event.paths {
-                    if is_temp_file(&path) || is_excluded(&path, &exclude_patterns) { continue; }
-                    
-                    let event_action = map_event_kinds(&event.kind);
-                    
-                    match event_action {
-                        EventAction::Delete => {
-                            pending_events.insert((path, event_action));
-                        },
-                        _ => {
-                            if let Ok(metadata) = fs::metadata(&path) {
-                                if metadata.is_file() {
-                                    pending_events.insert((path, event_action));
-                                }
+                let event_action = map_event_kinds(&event);
+
+                if let EventAction::Rename { from, to } = &event_action {
+                    if renames.both(event.attrs.tracker()) {
+                        for set in sets {
+                            enqueue_move(set, from, to);
+                        }
+                    }
+                } else if let EventKind::Modify(ModifyKind::Name(mode)) = event.kind {
+                    handle_rename(sets, &mut renames, mode, event.attrs.tracker(), event.paths);
+                } else {
+                    for path in event.paths {
+                        for set in sets.iter().filter(|set| set.watches(&path)) {
+                            match event_action {
+                                // Access events, among them the ones caused by backing the file up.
+                                EventAction::Other => {}
+                                EventAction::Delete => enqueue(&set.queue, &path, EventAction::Delete),
+                                EventAction::DeleteDir => enqueue_removal(set, &path),
+                                EventAction::CreateDir => enqueue_arrival(set, &path),
+                                _ => enqueue_file(set, &path, event_action.clone()),
                             }
                         }
                     }
                 }
+
+                // Events are acknowledged only once they are on disk.
+                flush_queues(sets);
+                has_new_events = true;
                 last_event_time = Instant::now();
             }
             Err(RecvTimeoutError::Timeout) => {
-                if !pending_events.is_empty() && last_event_time.elapsed() >= debounce_duration {
-                    println!("Batch of changed paths:");
-                    for p in &pending_events {
-                        println!(" - {:?}", p);
+                if has_new_events && last_event_time.elapsed() >= debounce_duration {
+                    for set in sets {
+                        process_queue(&set.engine, &set.queue, settings.uploader.as_ref());
                     }
-                    // TODO: Call sync function from here
+                    has_new_events = false;
+                }
+            }
+            Err(e) => display_error(&CratisError::ChannelError(format!("{}", e)), false),
+        }
 
-                    pending_events.clear();
+        // Renames whose second half never arrived moved files out of the watched directories.
+        let vanished = renames.expire(rename_timeout);
+        if !vanished.is_empty() {
+            for path in vanished {
+                for set in sets.iter().filter(|set| set.watches(&path)) {
+                    enqueue_removal(set, &path);
                 }
             }
-            Err(e) => {
-                display_error(&CratisError::ChannelError(format!("{}", e)), false);
-                break;
+
+            flush_queues(sets);
+            has_new_events = true;
+            last_event_time = Instant::now();
+        }
+
+        for set in &mut settings.sets {
+            if set.scheduler.is_due() && !set.is_snapshotting() {
+                match timestamp_now() {
+                    Ok(timestamp) => {
+                        set.snapshot = Some(spawn_scheduled_snapshot(set, timestamp, settings.uploader.clone()));
+                        set.scheduler.mark_run(timestamp);
+                    }
+                    Err(e) => eprintln!("{e}"),
+                }
             }
         }
     }
 }
 
-/// Initializes and starts a file system watcher for the specified paths.
-///
-/// Sets up a `RecommendedWatcher` instance that monitors the given paths for file system events
-/// and forwards them through a channel.
+/// The parts of the watcher built from the configuration, replaced as a whole on reload.
+struct Settings {
+    config: CratisConfig,
+    repo: Repository,
+    sets: Vec<BackupSet>,
+    uploader: Option<UploadClient>,
+}
+
+impl Settings {
+    /// Sets up every backup set of `config` and the uploader around `repo`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of `BackupSet::new`.
+    fn new(config: CratisConfig, repo: Repository) -> CratisResult<Self> {
+        let sets = config
+            .backup_sets()
+            .into_iter()
+            .map(|(name, backup)| BackupSet::new(name, backup, &config, &repo))
+            .collect::<CratisResult<Vec<_>>>()?;
+        let uploader = config.server.is_enabled().then(|| UploadClient::new(&config.client, &config.server).with_retry(config.retry_policy()));
+
+        Ok(Self { config, repo, sets, uploader })
+    }
+
+    /// Returns the watch directories of every backup set with real-time watching enabled.
+    fn watch_directories(&self) -> Vec<String> {
+        let dirs: BTreeSet<&String> = self.sets.iter().filter(|set| set.realtime()).flat_map(|set| &set.backup.watch_directories).collect();
+        dirs.into_iter().cloned().collect()
+    }
+}
+
+/// A backup set as run by the watcher, with its own sync engine, work queue and schedule.
+struct BackupSet {
+    name: String,
+    backup: BackupConfig,
+    engine: SyncEngine,
+    queue: WorkQueue,
+    exclude: Arc<ExcludeRules>,
+    scheduler: Scheduler,
+    /// The worker thread of the running or last scheduled snapshot.
+    snapshot: Option<JoinHandle<()>>,
+}
+
+impl BackupSet {
+    /// Sets up a backup set in its namespace of `repo`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the set
+    /// * `backup` - The settings of the set
+    /// * `config` - The loaded configuration, for the settings shared by all sets
+    /// * `repo` - The backup repository
+    ///
+    /// # Errors
+    ///
+    /// * `CratisError::ConfigError` if an exclusion or large file pattern is invalid, or the set
+    ///   has real-time watching disabled and no interval
+    /// * `CratisError::DatabaseError` if the namespace of the set cannot be opened
+    fn new(name: &str, backup: &BackupConfig, config: &CratisConfig, repo: &Repository) -> CratisResult<Self> {
+        let exclude = Arc::new(ExcludeRules::from_config(backup)?);
+
+        let repo = repo.backup_set(name)?;
+        let queue = WorkQueue::open(&repo.index)?;
+        let last_snapshot = repo.index.latest_snapshot()?.map(|s| s.timestamp);
+        let scheduler = Scheduler::new(backup.interval_seconds, last_snapshot);
+
+        if !backup.realtime.unwrap_or(true) && !scheduler.is_enabled() {
+            return Err(CratisError::ConfigError(format!(
+                "Real-time watching is disabled for backup set '{}' but no interval_seconds is configured",
+                name
+            )));
+        }
+
+        let engine = SyncEngine::new(repo).with_retry(config.retry_policy()).with_size_limit(SizeLimit::from_config(config, backup)?);
+
+        Ok(Self { name: name.to_string(), backup: backup.clone(), engine, queue, exclude, scheduler, snapshot: None })
+    }
+
+    /// Returns `true` while a scheduled snapshot of this set is running.
+    fn is_snapshotting(&self) -> bool {
+        self.snapshot.as_ref().is_some_and(|snapshot| !snapshot.is_finished())
+    }
+
+    /// Returns `true` if real-time watching is enabled.
+    fn realtime(&self) -> bool {
+        self.backup.realtime.unwrap_or(true)
+    }
+
+    /// Returns `true` for temporary and excluded paths, which are never backed up.
+    fn is_ignored(&self, path: &Path) -> bool {
+        self.exclude.is_excluded(path)
+    }
+
+    /// Returns `true` if real-time events for `path` are backed up by this set.
+    fn watches(&self, path: &Path) -> bool {
+        self.realtime() && self.backup.watch_directories.iter().any(|dir| path.starts_with(dir)) && !self.is_ignored(path)
+    }
+}
+
+/// Reloads the configuration file and applies the changes to the running watcher.
 ///
 /// # Arguments
 ///
-/// * `paths` - A vector of strings representing the file system paths to watch
-/// * `tx` - A channel sender for forwarding file system events
-///
-/// # Returns
+/// * `path` - The configuration file the watcher was started with
+/// * `settings` - The current settings, replaced if the new configuration is valid
+/// * `watcher` - The notify watcher, `None` if no backup set uses real-time watching
+/// * `tx` - The channel sender handed to a newly started notify watcher
 ///
-/// Returns a `Result` containing the initialized `RecommendedWatcher` if successful.
+/// # Implementation Details
 ///
-/// # Errors
+/// An invalid configuration is reported and the watcher keeps running with the old one.
+/// Pending events are left alone: they live in the durable work queue of their backup set and
+/// are synced with the new settings, a removed set syncs its queue one last time. New backup
+/// sets and added watch directories are reconciled with the index, and so are all directories
+/// of a set whose exclusion patterns changed, to pick up files that were excluded before.
+/// Changes to the storage settings are reported but only take effect after a restart.
+fn reload_config(path: &Path, settings: &mut Settings, watcher: &mut Option<RecommendedWatcher>, tx: &Sender<Event>) {
+    let config = match load_config(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Keeping the current configuration, reloading {:?} failed: {}", path, e);
+            return;
+        }
+    };
+
+    for setting in restart_required(&settings.config, &config) {
+        eprintln!("Changing {setting} takes effect after restarting the watcher");
+    }
+
+    let repo = settings.repo.clone().with_compression(config.compression());
+    let mut previous = match Settings::new(config, repo) {
+        Ok(updated) => std::mem::replace(settings, updated),
+        Err(e) => {
+            eprintln!("Keeping the current configuration, reloading {:?} failed: {}", path, e);
+            return;
+        }
+    };
+
+    println!("Reloaded configuration from {:?}", path);
+
+    for set in previous.sets.iter().filter(|set| !settings.sets.iter().any(|s| s.name == set.name)) {
+        process_queue(&set.engine, &set.queue, previous.uploader.as_ref());
+        println!("Removed backup set {}", set.name);
+    }
+
+    update_watches(watcher, &previous.watch_directories(), &settings.watch_directories(), tx);
+
+    // A snapshot still running keeps the set from starting another one with the new settings.
+    for set in &mut settings.sets {
+        if let Some(previous) = previous.sets.iter_mut().find(|previous| previous.name == set.name) {
+            set.snapshot = previous.snapshot.take();
+        }
+    }
+
+    for set in &settings.sets {
+        let rescan = match previous.sets.iter().find(|previous| previous.name == set.name) {
+            Some(previous) => {
+                let changes = BackupChanges::between(&previous.backup, &set.backup);
+                if changes.exclude || changes.realtime { set.backup.watch_directories.clone() } else { changes.added }
+            }
+            None => {
+                println!("Added backup set {}", set.name);
+                set.backup.watch_directories.clone()
+            }
+        };
+
+        enqueue_differences(set, &rescan);
+    }
+}
+
+/// Adds and removes notify watches to go from watching `old` to watching `new`.
+///
+/// The notify watcher is started once the first directory is watched in real time and
+/// dropped once none is left.
+fn update_watches(watcher: &mut Option<RecommendedWatcher>, old: &[String], new: &[String], tx: &Sender<Event>) {
+    if new.is_empty() {
+        if watcher.take().is_some() {
+            println!("Stopped real-time watching");
+        }
+        return;
+    }
+
+    let Some(active) = watcher else {
+        println!("Started real-time watching");
+        *watcher = start_watching(new, tx.clone()).map_err(|e| eprintln!("Failed to start watching: {e}")).ok();
+        return;
+    };
+
+    for dir in old.iter().filter(|dir| !new.contains(dir)) {
+        match active.unwatch(Path::new(dir)) {
+            Ok(()) => println!("Stopped watching {}", dir),
+            Err(e) => eprintln!("Failed to stop watching {}: {}", dir, e),
+        }
+    }
+    for dir in new.iter().filter(|dir| !old.contains(dir)) {
+        match active.watch(Path::new(dir), RecursiveMode::Recursive) {
+            Ok(()) => println!("Watching {}", dir),
+            Err(e) => eprintln!("Failed to watch {}: {}", dir, e),
+        }
+    }
+}
+
+/// Queues every difference between the given watch directories of a backup set and its
+/// version index.
+fn enqueue_differences(set: &BackupSet, roots: &[String]) {
+    if roots.is_empty() {
+        return;
+    }
+
+    match reconcile(set.engine.repository(), roots, |path| !set.is_ignored(path)) {
+        Ok(batch) => {
+            for (path, action) in batch {
+                enqueue(&set.queue, &path, action);
+            }
+        }
+        Err(e) => eprintln!("Reconciliation of backup set {} failed: {}", set.name, e),
+    }
+
+    flush_queues(std::slice::from_ref(set));
+}
+
+/// Starts a scan-and-snapshot of all watch directories of a backup set on a worker thread.
 ///
-/// This function can fail if:
-/// * The watcher cannot be initialized
-/// * Any of the specified paths cannot be watched
+/// # Arguments
 ///
-/// # Example
+/// * `set` - The backup set to snapshot
+/// * `timestamp` - The timestamp the snapshot is recorded at
+/// * `uploader` - The client pushing the snapshot to the server, if uploads are enabled
 ///
-/// ```rust
-/// use std::sync::mpsc::channel;
+/// # Implementation Details
 ///
-/// let (tx, rx) = channel();
-/// let paths = vec![String::from("/path/to/watch")];
-/// let watcher = start_watching(&paths, tx)?;
-/// ```
+/// A snapshot of a large tree can take minutes. Running it on its own thread keeps the event
+/// loop receiving and queueing events in the meantime; both sides write through the same
+/// repository, which is safe to share between threads.
+fn spawn_scheduled_snapshot(set: &BackupSet, timestamp: u64, uploader: Option<UploadClient>) -> JoinHandle<()> {
+    let name = set.name.clone();
+    let backup = set.backup.clone();
+    let engine = set.engine.clone();
+    let exclude = set.exclude.clone();
+
+    thread::spawn(move || run_scheduled_snapshot(&name, &backup, &engine, &exclude, timestamp, uploader.as_ref()))
+}
+
+/// Takes a scan-and-snapshot of all watch directories of a backup set in its backup mode.
 ///
 /// # Implementation Details
 ///
-/// * Uses recursive watching mode for all directories
-/// * Automatically handles error cases by displaying them through `CratisError`
-/// * Events are sent through the channel asynchronously
-/// * Failed watch attempts for individual paths are logged but don't stop the overall watching process
-fn start_watching(paths: &Vec<String>, tx: Sender<Event>) -> Result<RecommendedWatcher> {
-    let mut watcher = RecommendedWatcher::new(
-        move |res: Result<Event>| {
-            match res {
-                Ok(event) => tx.send(event).unwrap(),
-                Err(e) => display_error(&CratisError::WatcherError(format!("{:?}", e)), false),
+/// The scan applies the same temp-file and exclusion filters as the real-time watcher, so it
+/// also picks up changes missed by the notify backend (e.g. on network mounts).
+fn run_scheduled_snapshot(name: &str, backup: &BackupConfig, engine: &SyncEngine, exclude: &ExcludeRules, timestamp: u64, uploader: Option<&UploadClient>) {
+    let include = |path: &Path| !exclude.is_excluded(path);
+
+    let max_chain_length = backup.max_chain_length.unwrap_or(DEFAULT_MAX_CHAIN_LENGTH);
+
+    match take_snapshot(engine, &backup.watch_directories, timestamp, backup.mode, max_chain_length, include) {
+        Ok(report) => {
+            println!(
+                "Snapshot {} of {} ({:?}, {} files, {})",
+                report.record.id,
+                name,
+                report.record.mode,
+                report.record.files,
+                to_human_readable_size(report.record.size as f64)
+            );
+            for (path, e) in &report.failed {
+                eprintln!("Failed to back up {:?}: {}", path, e);
             }
-        },
-        notify::Config::default(),
-    )?;
 
-    for path in paths {
-        let _ = watcher.watch(Path::new(path), RecursiveMode::Recursive).map_err(|_| display_error(&CratisError::WatcherError(format!("Failed to watch directory: {}", path)), false));
+            if let Some(uploader) = uploader {
+                match uploader.push_snapshot(engine.repository(), &report.record) {
+                    Ok(upload) => print_upload(&upload),
+                    Err(e) => eprintln!("Failed to upload snapshot {}: {}", report.record.id, e),
+                }
+            }
+        }
+        Err(e) => eprintln!("Scheduled snapshot of {} failed: {}", name, e),
     }
 
-    Ok(watcher)
+    if let Err(e) = engine.repository().index.flush() {
+        eprintln!("{e}");
+    }
+}
+
+/// Adds an event to the durable work queue, reporting paths that cannot be queued.
+fn enqueue(queue: &WorkQueue, path: &Path, action: EventAction) {
+    if let Err(e) = queue.push(path, action) {
+        eprintln!("Failed to queue {:?}: {}", path, e);
+    }
+}
+
+/// Writes the queued events of every backup set to disk.
+fn flush_queues(sets: &[BackupSet]) {
+    for set in sets {
+        if let Err(e) = set.queue.flush() {
+            eprintln!("Failed to persist queued events: {e}");
+        }
+    }
+}
+
+/// Queues a created or modified file, unless it is oversized and would be skipped silently.
+fn enqueue_file(set: &BackupSet, path: &Path, action: EventAction) {
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.is_file()
+        && set.engine.size_limit().decide(path, metadata.len()) != SizePolicy::Skip
+    {
+        enqueue(&set.queue, path, action);
+    }
+}
+
+/// Queues a path that was created or moved into the watched directories of a backup set from
+/// outside.
+///
+/// A directory is recorded itself and reconciled with the index to queue every file and
+/// directory below it, since a moved directory is reported as a single event and files created
+/// in a new directory before it is watched are not reported at all.
+fn enqueue_arrival(set: &BackupSet, path: &Path) {
+    if !path.is_dir() {
+        enqueue_file(set, path, EventAction::Create);
+        return;
+    }
+
+    enqueue(&set.queue, path, EventAction::CreateDir);
+
+    let root = path.to_string_lossy().into_owned();
+    match reconcile(set.engine.repository(), &[root], |path| set.watches(path)) {
+        Ok(batch) => {
+            for (path, action) in batch {
+                enqueue(&set.queue, &path, action);
+            }
+        }
+        Err(e) => eprintln!("Failed to scan {:?}: {}", path, e),
+    }
+}
+
+/// Queues the deletion of a path that was removed or moved away, including every recorded file
+/// and directory below it.
+fn enqueue_removal(set: &BackupSet, path: &Path) {
+    enqueue(&set.queue, path, EventAction::Delete);
+
+    match set.engine.repository().index.paths_under(path) {
+        Ok(paths) => {
+            for path in paths {
+                enqueue(&set.queue, &path, EventAction::Delete);
+            }
+        }
+        Err(e) => eprintln!("Failed to look up files below {:?}: {}", path, e),
+    }
+}
+
+/// Queues a rename whose both halves are known.
+///
+/// A file renamed to or from a path the backup set does not watch, e.g. a temporary or excluded
+/// name or another set's directory, is treated as created or deleted, which covers editors
+/// saving through a temporary file. The deletion of `from` and creation of `to` are queued next
+/// to the move itself, so both paths are settled even if the move cannot be recorded, and a
+/// file changed right after being moved is still backed up.
+fn enqueue_move(set: &BackupSet, from: &Path, to: &Path) {
+    match (set.watches(from), set.watches(to)) {
+        (false, false) => {}
+        (false, true) => enqueue_arrival(set, to),
+        (true, false) => enqueue_removal(set, from),
+        (true, true) => {
+            if let Err(e) = set.queue.push_move(from, to) {
+                eprintln!("Failed to queue the move of {:?}: {}", from, e);
+            }
+            enqueue(&set.queue, from, EventAction::Delete);
+            enqueue_file(set, to, EventAction::Create);
+        }
+    }
 }
 
-/// Determines if a given path represents a temporary file.
+/// Handles one half of a rename reported by the notify backend.
 ///
-/// Checks the file name against common temporary file patterns used by various
-/// text editors and systems.
+/// # Arguments
+///
+/// * `sets` - The backup sets the rename is queued for
+/// * `renames` - Pending halves of renames reported in two events
+/// * `mode` - Which half of the rename the event reports
+/// * `tracker` - The cookie tying both halves together, if the backend provides one
+/// * `paths` - The paths of the event
+///
+/// # Implementation Details
+///
+/// Renames the backend cannot describe (`RenameMode::Any`, e.g. on macOS) are queued as a
+/// creation or deletion depending on whether the path still exists. The sync engine then
+/// pairs them up by content.
+fn handle_rename(sets: &[BackupSet], renames: &mut RenameTracker, mode: RenameMode, tracker: Option<usize>, paths: Vec<PathBuf>) {
+    match mode {
+        RenameMode::From => {
+            for path in paths {
+                if let Some(vanished) = renames.from(tracker, path) {
+                    for set in sets.iter().filter(|set| set.watches(&vanished)) {
+                        enqueue_removal(set, &vanished);
+                    }
+                }
+            }
+        }
+        RenameMode::To => {
+            for path in paths {
+                match renames.to(tracker) {
+                    Some(from) => {
+                        for set in sets {
+                            enqueue_move(set, &from, &path);
+                        }
+                    }
+                    None => {
+                        for set in sets.iter().filter(|set| set.watches(&path)) {
+                            enqueue_arrival(set, &path);
+                        }
+                    }
+                }
+            }
+        }
+        _ => {
+            for path in &paths {
+                for set in sets.iter().filter(|set| set.watches(path)) {
+                    if path.exists() {
+                        enqueue_arrival(set, path);
+                    } else {
+                        enqueue_removal(set, path);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Syncs everything in the work queue and removes the paths that are settled.
 ///
 /// # Arguments
 ///
-/// * `path` - A reference to a `Path` to check
+/// * `engine` - The sync engine writing into the backup repository
+/// * `queue` - The durable queue of pending events
+/// * `uploader` - The client pushing new revisions to the server, if uploads are enabled
 ///
-/// # Returns
+/// # Implementation Details
+///
+/// A path leaves the queue once it was backed up or failed with a permanent error. Paths that
+/// failed with a transient error stay queued and are retried with the next batch or on the
+/// next start. Events queued for a path while the batch was syncing keep the path queued.
+fn process_queue(engine: &SyncEngine, queue: &WorkQueue, uploader: Option<&UploadClient>) {
+    let batch = match queue.batch() {
+        Ok(batch) if !batch.is_empty() => batch,
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!("Failed to read the work queue: {e}");
+            return;
+        }
+    };
+
+    for path in sync_pending(engine, &batch, uploader) {
+        if let Err(e) = queue.complete(&batch, &path) {
+            eprintln!("Failed to dequeue {:?}: {}", path, e);
+        }
+    }
+    if let Err(e) = queue.complete_moves(&batch) {
+        eprintln!("Failed to dequeue moves: {e}");
+    }
+
+    if let Err(e) = queue.flush() {
+        eprintln!("{e}");
+    }
+}
+
+/// Syncs a debounced batch of moves and events and reports the outcome for every path.
 ///
-/// Returns `true` if the file matches any of these patterns:
-/// * Files starting with '.'
-/// * Files ending with '.tmp', '.temp', '.swp', or '.bak'
-/// * Files starting with '~' or '.#'
-/// * Vim temporary files (specifically '4913')
+/// # Arguments
 ///
-/// Returns `false` if:
-/// * The path has no filename
-/// * The filename cannot be converted to a string
-/// * The filename doesn't match any temporary file patterns
+/// * `engine` - The sync engine writing into the backup repository
+/// * `batch` - The debounced batch of moves and changed paths
+/// * `uploader` - The client pushing new revisions to the server, if uploads are enabled
 ///
-/// # Example
+/// # Returns
 ///
-/// ```ignore
-/// use std::path::Path;
+/// The paths that are settled, i.e. backed up or failed with a permanent error.
 ///
-/// let temp_file = Path::new(".temporary.swp");
-/// assert!(is_temp_file(&temp_file));
+/// # Implementation Details
 ///
-/// let normal_file = Path::new("document.txt");
-/// assert!(!is_temp_file(&normal_file));
-/// ```
-fn is_temp_file(path: &Path) -> bool {
-    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-        name.starts_with('.')
-            || name.ends_with(".tmp")
-            || name.ends_with(".temp")
-            || name.ends_with(".swp")
-            || name.ends_with(".bak")
-            || name.starts_with("~")
-            || name.starts_with(".#")
-            || name == "4913" // vim temp file
-    } else {
-        false
+/// Moves are recorded before the events, which would otherwise record the old paths as plain
+/// deletions. Failures are reported per path and never abort the watcher, so a single
+/// unreadable file does not stop the remaining files of the batch from being backed up. A
+/// failed upload only affects the server copy; the revisions are reconciled with the server by
+/// the next snapshot.
+fn sync_pending(engine: &SyncEngine, batch: &QueuedBatch, uploader: Option<&UploadClient>) -> Vec<PathBuf> {
+    let mut recorded = Vec::new();
+    let mut settled = Vec::new();
+
+    for result in engine.sync_moves(&batch.moves) {
+        match &result.outcome {
+            Ok(SyncOutcome::Moved { from, .. }) => {
+                println!("Moved {:?} to {:?}", from, result.path);
+                recorded.push(from.clone());
+                recorded.push(result.path);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to record the move to {:?}: {}", result.path, e),
+        }
+    }
+
+    for result in engine.sync_batch(&batch.events) {
+        match &result.outcome {
+            Ok(SyncOutcome::Moved { from, .. }) => {
+                println!("Moved {:?} to {:?}", from, result.path);
+                recorded.push(from.clone());
+                recorded.push(result.path.clone());
+            }
+            Ok(SyncOutcome::Stored { hash, size, .. }) => {
+                println!("Stored {:?} ({:?}, {}, {})", result.path, result.action, to_human_readable_size(*size as f64), hash);
+                recorded.push(result.path.clone());
+            }
+            Ok(SyncOutcome::Tombstoned) => {
+                println!("Recorded deletion of {:?}", result.path);
+                recorded.push(result.path.clone());
+            }
+            Ok(SyncOutcome::Directory) => {
+                println!("Recorded directory {:?}", result.path);
+                recorded.push(result.path.clone());
+            }
+            Ok(SyncOutcome::TooLarge { size, warned: true }) => {
+                eprintln!("Skipped {:?}: {} exceeds max_file_size_mb", result.path, to_human_readable_size(*size as f64));
+            }
+            Ok(SyncOutcome::Unchanged { .. }) | Ok(SyncOutcome::Skipped(_)) | Ok(SyncOutcome::TooLarge { .. }) => {}
+            Err(e) if e.is_transient() => {
+                eprintln!("Failed to back up {:?}, will retry: {}", result.path, e);
+                continue;
+            }
+            Err(e) => eprintln!("Failed to back up {:?}: {}", result.path, e),
+        }
+
+        settled.push(result.path);
+    }
+
+    if let Err(e) = engine.repository().index.flush() {
+        eprintln!("{e}");
     }
+
+    if let Some(uploader) = uploader
+        && !recorded.is_empty()
+    {
+        let repo = engine.repository();
+        let versions: Vec<_> = recorded
+            .into_iter()
+            .filter_map(|path| repo.index.latest(&path).ok().flatten().map(|version| (path, version)))
+            .collect();
+
+        match uploader.push_versions(repo, &versions) {
+            Ok(upload) => print_upload(&upload),
+            Err(e) => eprintln!("Failed to upload to the server: {e}"),
+        }
+    }
+
+    settled
+}
+
+/// Reports what an upload transferred to the server.
+fn print_upload(upload: &UploadReport) {
+    println!(
+        "Uploaded {} files and {} chunks ({}), {} new revisions",
+        upload.files_uploaded,
+        upload.objects_uploaded,
+        to_human_readable_size(upload.bytes_uploaded as f64),
+        upload.versions_recorded
+    );
 }
 
-/// Checks if a path matches any of the provided exclusion patterns.
+/// Initializes and starts a file system watcher for the specified paths.
+///
+/// Sets up a `RecommendedWatcher` instance that monitors the given paths for file system events
+/// and forwards them through a channel.
 ///
 /// # Arguments
 ///
-/// * `path` - A reference to a `Path` to check
-/// * `exclude_patterns` - A slice of `Pattern`s to match against
+/// * `paths` - A slice of strings representing the file system paths to watch
+/// * `tx` - A channel sender for forwarding file system events
 ///
 /// # Returns
 ///
-/// Returns `true` if the path matches any of the exclusion patterns,
-/// `false` otherwise.
+/// Returns a `Result` containing the initialized `RecommendedWatcher` if successful.
+///
+/// # Errors
+///
+/// This function only fails if the watcher cannot be initialized. Paths that cannot be watched
+/// are reported and skipped, so a configuration reload never stops the daemon.
 ///
 /// # Example
 ///
 /// ```rust
-/// use std::path::Path;
-///
-/// let patterns = vec![Pattern::new("*.log"), Pattern::new("target/*")];
-/// let path = Path::new("app.log");
-/// assert!(is_excluded(&path, &patterns));
+/// use std::sync::mpsc::channel;
 ///
-/// let source_file = Path::new("src/main.rs");
-/// assert!(!is_excluded(&source_file, &patterns));
+/// let (tx, rx) = channel();
+/// let paths = vec![String::from("/path/to/watch")];
+/// let watcher = start_watching(&paths, tx)?;
 /// ```
 ///
 /// # Implementation Details
 ///
-/// Uses the `Iterator::any()` method to check if any pattern matches the given path,
-/// providing short-circuit evaluation for efficiency.
-fn is_excluded(path: &Path, exclude_patterns: &[Pattern]) -> bool {
-    exclude_patterns.iter().any(|pattern| pattern.matches_path(path))
-}
\ No newline at end of file
+/// * Uses recursive watching mode for all directories
+/// * Watcher errors are reported on stderr and never terminate the process
+/// * Events are sent through the channel asynchronously
+/// * Failed watch attempts for individual paths are logged but don't stop the overall watching process
+fn start_watching(paths: &[String], tx: Sender<Event>) -> Result<RecommendedWatcher> {
+    let mut watcher = RecommendedWatcher::new(
+        move |res: Result<Event>| {
+            match res {
+                Ok(event) => tx.send(event).unwrap(),
+                Err(e) => eprintln!("{}", CratisError::WatcherError(format!("{:?}", e))),
+            }
+        },
+        notify::Config::default(),
+    )?;
+
+    for path in paths {
+        if let Err(e) = watcher.watch(Path::new(path), RecursiveMode::Recursive) {
+            eprintln!("Failed to watch {}: {}", path, e);
+        }
+    }
+
+    Ok(watcher)
+}
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+use cratis_core::config::{BackupConfig, CratisConfig};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Result, Watcher};
+
+/// How long the configuration file has to stay unchanged before it is reloaded.
+///
+/// Editors often save in several steps (truncate, write, rename), reloading on the first one
+/// would read a half-written file.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Tells the watcher when to reload its configuration.
+///
+/// A reload is requested when the configuration file is written, replaced or removed, or when
+/// the process receives `SIGHUP`. The file is watched through its parent directory, since
+/// editors commonly save by renaming a new file over the old one, which ends a watch on the
+/// file itself.
+pub struct ReloadTrigger {
+    requests: Receiver<()>,
+    hangup: Arc<AtomicBool>,
+    changed_at: Option<Instant>,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl ReloadTrigger {
+    /// Starts watching the configuration file and listening for `SIGHUP`.
+    ///
+    /// Failing to set up either source is reported but not fatal, the watcher then simply
+    /// cannot be reloaded that way.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_path` - The configuration file the watcher was started with
+    pub fn new(config_path: &Path) -> Self {
+        let (tx, requests) = channel();
+
+        let watcher = match watch_config(config_path, move || {
+            let _ = tx.send(());
+        }) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!("Cannot watch {:?} for changes, send SIGHUP to reload it: {}", config_path, e);
+                None
+            }
+        };
+
+        let hangup = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hangup)) {
+            eprintln!("Cannot listen for SIGHUP: {e}");
+        }
+
+        Self { requests, hangup, changed_at: None, _watcher: watcher }
+    }
+
+    /// Returns `true` if the configuration should be reloaded now.
+    ///
+    /// `SIGHUP` requests a reload right away, changes to the file once it has been quiet for
+    /// `RELOAD_DEBOUNCE`.
+    pub fn poll(&mut self) -> bool {
+        while self.requests.try_recv().is_ok() {
+            self.changed_at = Some(Instant::now());
+        }
+
+        let debounced = self.changed_at.is_some_and(|at| at.elapsed() >= RELOAD_DEBOUNCE);
+        let hangup = self.hangup.swap(false, Ordering::Relaxed);
+
+        if debounced || hangup {
+            self.changed_at = None;
+            return true;
+        }
+        false
+    }
+}
+
+/// Watches the directory containing `config_path` and calls `on_change` for events on the file.
+fn watch_config<F>(config_path: &Path, on_change: F) -> Result<RecommendedWatcher>
+where
+    F: Fn() + Send + 'static,
+{
+    let config_path = std::path::absolute(config_path)?;
+    let dir = config_path.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("/"));
+
+    let mut watcher = RecommendedWatcher::new(
+        move |res: Result<Event>| {
+            if let Ok(event) = res
+                && !event.kind.is_access()
+                && event.paths.contains(&config_path)
+            {
+                on_change();
+            }
+        },
+        notify::Config::default(),
+    )?;
+
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+/// What changed in a backup set between two configurations, as far as it needs a rescan.
+#[derive(Debug, Default)]
+pub struct BackupChanges {
+    /// Watch directories that were added.
+    pub added: Vec<String>,
+    /// Whether the exclusion patterns or temporary file rules changed, or `.gitignore` files
+    /// were switched on or off.
+    pub exclude: bool,
+    /// Whether real-time watching was switched on or off.
+    pub realtime: bool,
+}
+
+impl BackupChanges {
+    /// Compares the old and new settings of a backup set.
+    pub fn between(old: &BackupConfig, new: &BackupConfig) -> Self {
+        Self {
+            added: new.watch_directories.iter().filter(|dir| !old.watch_directories.contains(dir)).cloned().collect(),
+            exclude: old.exclude != new.exclude || old.gitignore != new.gitignore || old.temp_files != new.temp_files,
+            realtime: old.realtime.unwrap_or(true) != new.realtime.unwrap_or(true),
+        }
+    }
+}
+
+/// Returns the settings that changed between the configurations but only take effect after a
+/// restart of the watcher.
+///
+/// The repository stays open while the watcher runs, so its location and passphrase cannot be
+/// changed by a reload.
+pub fn restart_required(old: &CratisConfig, new: &CratisConfig) -> Vec<&'static str> {
+    let passphrase_file = |config: &CratisConfig| config.storage.as_ref().and_then(|storage| storage.passphrase_file.clone());
+
+    let mut settings = Vec::new();
+    if old.storage_path() != new.storage_path() {
+        settings.push("storage.path");
+    }
+    if passphrase_file(old) != passphrase_file(new) {
+        settings.push("storage.passphrase_file");
+    }
+    settings
+}
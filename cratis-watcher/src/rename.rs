@@ -0,0 +1,131 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How many recently paired tracker cookies are remembered to drop duplicate reports.
+const PAIRED_HISTORY: usize = 256;
+
+/// Pairs up both halves of rename events reported by the notify backend.
+///
+/// Backends report a rename either as one event carrying both paths, or as a "from" event
+/// followed by a "to" event. inotify tags both halves with the same tracker cookie and then also
+/// reports the pair as one event; other backends (e.g. Windows) report the halves back to back
+/// without a cookie. The tracker handles all of these:
+///
+/// * A "from" half is held until its "to" half arrives, or until it expires because the file
+///   was moved out of the watched directories
+/// * A "to" half without a matching "from" half means the file was moved in from outside
+/// * Pairs that were already reported half by half are dropped when reported again as a whole
+#[derive(Default)]
+pub struct RenameTracker {
+    pending: HashMap<Option<usize>, (PathBuf, Instant)>,
+    paired: VecDeque<usize>,
+}
+
+impl RenameTracker {
+    /// Creates a tracker without pending renames.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the "from" half of a rename.
+    ///
+    /// # Returns
+    ///
+    /// A previously pending "from" half without cookie that was never matched, i.e. a path
+    /// that was moved away.
+    pub fn from(&mut self, tracker: Option<usize>, path: PathBuf) -> Option<PathBuf> {
+        self.pending.insert(tracker, (path, Instant::now())).map(|(path, _)| path)
+    }
+
+    /// Records the "to" half of a rename.
+    ///
+    /// # Returns
+    ///
+    /// The path the file was moved from, or `None` if it was moved in from outside.
+    pub fn to(&mut self, tracker: Option<usize>) -> Option<PathBuf> {
+        let (from, _) = self.pending.remove(&tracker)?;
+
+        if let Some(tracker) = tracker {
+            if self.paired.len() == PAIRED_HISTORY {
+                self.paired.pop_front();
+            }
+            self.paired.push_back(tracker);
+        }
+
+        Some(from)
+    }
+
+    /// Records a rename reported as one event carrying both paths.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the rename was already reported half by half and should be ignored.
+    pub fn both(&mut self, tracker: Option<usize>) -> bool {
+        match tracker {
+            Some(tracker) if self.paired.contains(&tracker) => false,
+            Some(tracker) => {
+                self.pending.remove(&Some(tracker));
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Removes and returns the "from" halves that have been waiting longer than `timeout`.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<PathBuf> {
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, since))| since.elapsed() >= timeout)
+            .map(|(tracker, _)| *tracker)
+            .collect();
+
+        expired.into_iter().filter_map(|tracker| self.pending.remove(&tracker)).map(|(path, _)| path).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn both_halves_of_a_rename_are_paired() {
+        let mut tracker = RenameTracker::new();
+
+        assert_eq!(tracker.from(Some(7), PathBuf::from("/data/old.txt")), None);
+        assert_eq!(tracker.to(Some(7)).as_deref(), Some(Path::new("/data/old.txt")));
+        assert!(!tracker.both(Some(7)), "the pair was already reported half by half");
+        assert!(tracker.expire(Duration::ZERO).is_empty());
+
+        assert!(tracker.both(Some(8)));
+        assert!(tracker.both(None));
+    }
+
+    #[test]
+    fn unmatched_halves_expire() {
+        let mut tracker = RenameTracker::new();
+
+        tracker.from(Some(1), PathBuf::from("/data/moved-away.txt"));
+        assert!(tracker.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(tracker.expire(Duration::ZERO), vec![PathBuf::from("/data/moved-away.txt")]);
+        assert_eq!(tracker.to(Some(1)), None, "an expired half is not paired anymore");
+
+        assert_eq!(tracker.to(Some(2)), None, "moved in from outside");
+    }
+
+    #[test]
+    fn directory_moves_without_cookie_are_paired_back_to_back() {
+        let mut tracker = RenameTracker::new();
+
+        assert_eq!(tracker.from(None, PathBuf::from("/data/photos")), None);
+        assert_eq!(tracker.to(None).as_deref(), Some(Path::new("/data/photos")));
+
+        tracker.from(None, PathBuf::from("/data/music"));
+        let moved_away = tracker.from(None, PathBuf::from("/data/videos"));
+        assert_eq!(moved_away.as_deref(), Some(Path::new("/data/music")));
+        assert_eq!(tracker.to(None).as_deref(), Some(Path::new("/data/videos")));
+        assert!(tracker.expire(Duration::ZERO).is_empty());
+    }
+}
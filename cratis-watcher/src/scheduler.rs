@@ -0,0 +1,46 @@
+use cratis_core::utils::timestamp_now;
+
+/// Decides when the next interval backup is due.
+///
+/// The schedule is based on Unix timestamps rather than `Instant`s, so it can be seeded with
+/// the timestamp of the last recorded snapshot and survives restarts of the watcher: a watcher
+/// that was down for longer than the interval takes a snapshot right after starting.
+pub struct Scheduler {
+    interval_seconds: Option<u64>,
+    last_run: Option<u64>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler running every `interval_seconds`.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval_seconds` - The configured interval, `None` or `0` disables interval backups
+    /// * `last_run` - Timestamp of the most recent snapshot, if any
+    pub fn new(interval_seconds: Option<u64>, last_run: Option<u64>) -> Self {
+        Self { interval_seconds: interval_seconds.filter(|i| *i > 0), last_run }
+    }
+
+    /// Returns `true` if interval backups are enabled at all.
+    pub fn is_enabled(&self) -> bool {
+        self.interval_seconds.is_some()
+    }
+
+    /// Returns `true` if an interval backup should run now.
+    pub fn is_due(&self) -> bool {
+        let Some(interval) = self.interval_seconds else {
+            return false;
+        };
+
+        match (self.last_run, timestamp_now()) {
+            (None, _) => true,
+            (Some(last_run), Ok(now)) => now.saturating_sub(last_run) >= interval,
+            (Some(_), Err(_)) => false,
+        }
+    }
+
+    /// Records that an interval backup ran at the given timestamp.
+    pub fn mark_run(&mut self, timestamp: u64) {
+        self.last_run = Some(timestamp);
+    }
+}